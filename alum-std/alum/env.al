@@ -0,0 +1,6 @@
+$ifndef ALUM_ENV
+$define ALUM_ENV 1
+
+extern getenv(str): str
+
+$endif
\ No newline at end of file
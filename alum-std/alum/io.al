@@ -13,10 +13,12 @@ $define SEEK_CUR 1
 $define SEEK_END 2
 
 extern write(int, str, int): int
+extern write_all(int, str, int): int
 extern read(int, str, int): int
 extern print(str): int
 extern println(str): int
 extern input(str): str
+extern readline(str, int): int
 extern fopen(str, int, int): int
 extern fclose(int): int
 extern fread(int): str
@@ -9,6 +9,10 @@ $import "string.al"
 $import "convert.al"
 $import "math.al"
 $import "array.al"
+$import "env.al"
+$import "mem.al"
+$import "process.al"
+$import "time.al"
 extern syscall(int, int, int, int): int
 extern exit(int): void
 
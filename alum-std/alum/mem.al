@@ -0,0 +1,7 @@
+$ifndef ALUM_MEM
+$define ALUM_MEM 1
+
+extern malloc(int): str
+extern free(str): void
+
+$endif
\ No newline at end of file
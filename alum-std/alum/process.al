@@ -0,0 +1,10 @@
+$ifndef ALUM_PROCESS
+$define ALUM_PROCESS 1
+
+$define WNOHANG 1
+
+extern fork(): int
+extern execve(str, arr<_>, arr<_>): int
+extern waitpid(int, arr<_>, int): int
+
+$endif
\ No newline at end of file
@@ -0,0 +1,6 @@
+$ifndef ALUM_TIME
+$define ALUM_TIME 1
+
+extern sleep_ms(int): int
+
+$endif
\ No newline at end of file
@@ -0,0 +1,26 @@
+use core::ptr::null;
+
+pub(crate) static mut ENVP: *const *const u8 = null();
+
+#[unsafe(no_mangle)]
+pub extern "C" fn getenv(name: *const u8) -> *const u8 {
+    unsafe {
+        let mut env = ENVP;
+        if env.is_null() || name.is_null() {
+            return null();
+        }
+
+        while !(*env).is_null() {
+            let entry = *env;
+            let mut i = 0;
+            while *name.add(i) != 0 && *entry.add(i) == *name.add(i) {
+                i += 1;
+            }
+            if *name.add(i) == 0 && *entry.add(i) == b'=' {
+                return entry.add(i + 1);
+            }
+            env = env.add(1);
+        }
+    }
+    null()
+}
@@ -12,43 +12,70 @@ pub extern "C" fn read(fd: usize, buffer: *mut u8, n: usize) -> isize {
     syscall(0, fd as isize, buffer as isize, n as isize)
 }
 
+const EINTR: isize = 4;
+
+/// Writes all `n` bytes, retrying after partial writes and interrupted
+/// calls. Returns `n`, the bytes written so far if `write` makes no
+/// progress, or the negative error of the first failing `write`.
+#[inline(never)]
+#[unsafe(no_mangle)]
+pub extern "C" fn write_all(fd: usize, buffer: *const u8, n: usize) -> isize {
+    let mut written = 0;
+    while written < n {
+        let result = write(fd, unsafe { buffer.add(written) }, n - written);
+        if result == -EINTR {
+            continue;
+        }
+        if result <= 0 {
+            return if result == 0 {
+                written as isize
+            } else {
+                result
+            };
+        }
+        written += result as usize;
+    }
+    written as isize
+}
+
 #[inline(never)]
 #[unsafe(no_mangle)]
 pub extern "C" fn print(fmt: *const u8) -> isize {
     let len = strlen(fmt);
-    write(1, fmt, len)
+    write_all(1, fmt, len)
 }
 
 #[inline(never)]
 #[unsafe(no_mangle)]
 pub extern "C" fn println(fmt: *const u8) -> isize {
     let len = strlen(fmt);
-    write(1, fmt, len) + write(1, b"\n".as_ptr(), 1)
+    let result = write_all(1, fmt, len);
+    if result < 0 {
+        return result;
+    }
+    let newline = write_all(1, b"\n".as_ptr(), 1);
+    if newline < 0 {
+        return newline;
+    }
+    result + newline
 }
 
-static mut BUFFER: [u8; 1024] = [0; 1024];
-
+/// Reads one line from stdin into `buffer`, which holds `n` bytes including
+/// the terminating NUL. The line ending is not stored.
+///
+/// Returns the number of bytes stored. If the line does not fit, the rest of
+/// it is discarded and the count is returned negated.
 #[inline(never)]
 #[unsafe(no_mangle)]
-pub extern "C" fn input(prompt: *const u8) -> *const u8 {
-    let buffer = &raw mut BUFFER;
-
-    if !prompt.is_null() {
-        let mut prompt_len = 0;
-        unsafe {
-            while *prompt.add(prompt_len) != 0 {
-                prompt_len += 1;
-            }
-        }
-
-        if prompt_len > 0 {
-            write(1, prompt, prompt_len);
-        }
+pub extern "C" fn readline(buffer: *mut u8, n: usize) -> isize {
+    if n == 0 {
+        return 0;
     }
 
     let mut total_read = 0;
+    let mut truncated = false;
 
-    while total_read < unsafe { (*buffer).len() } - 1 {
+    loop {
         let mut ch: u8 = 0;
 
         let result = read(0, &mut ch as *mut u8, 1);
@@ -61,14 +88,51 @@ pub extern "C" fn input(prompt: *const u8) -> *const u8 {
             break;
         }
 
-        unsafe {
-            (*buffer)[total_read] = ch;
+        if total_read < n - 1 {
+            unsafe {
+                *buffer.add(total_read) = ch;
+            }
+            total_read += 1;
+        } else {
+            truncated = true;
         }
-        total_read += 1;
     }
     unsafe {
-        (*buffer)[total_read] = 0;
+        *buffer.add(total_read) = 0;
+    }
+
+    if truncated {
+        -(total_read as isize)
+    } else {
+        total_read as isize
     }
+}
+
+static mut BUFFER: [u8; 1024] = [0; 1024];
+
+/// Prints `prompt` and reads one line from stdin into a static buffer.
+///
+/// Lines longer than 1023 bytes are truncated; use `readline` to detect that
+/// or to read longer lines.
+#[inline(never)]
+#[unsafe(no_mangle)]
+pub extern "C" fn input(prompt: *const u8) -> *const u8 {
+    let buffer = &raw mut BUFFER;
+
+    if !prompt.is_null() {
+        let mut prompt_len = 0;
+        unsafe {
+            while *prompt.add(prompt_len) != 0 {
+                prompt_len += 1;
+            }
+        }
+
+        if prompt_len > 0 {
+            write_all(1, prompt, prompt_len);
+        }
+    }
+
+    readline(buffer as *mut u8, unsafe { (*buffer).len() });
 
     buffer as *const u8
 }
@@ -7,15 +7,19 @@ use core::panic::PanicInfo;
 
 pub mod array;
 pub mod convert;
+pub mod env;
 pub mod io;
 pub mod math;
+pub mod mem;
+pub mod process;
 pub mod string;
+pub mod time;
 
 #[unsafe(no_mangle)]
 pub extern "C" fn rust_eh_personality() {}
 
 unsafe extern "C" {
-    fn main() -> isize;
+    fn main(argc: isize, argv: *const isize) -> isize;
 }
 
 #[panic_handler]
@@ -60,8 +64,27 @@ pub extern "C" fn exit(code: isize) {
     }
 }
 
+/// On entry `[rsp]` holds argc followed by the argv pointers, which is
+/// exactly the layout of an Alum array (length header, then elements),
+/// so `rsp` itself is passed to `main` as `argv`.
+#[unsafe(naked)]
 #[unsafe(no_mangle)]
-extern "C" fn _start() -> () {
-    let ret = unsafe { main() };
+extern "C" fn _start() -> ! {
+    core::arch::naked_asm!(
+        "xor rbp, rbp",
+        "mov rdi, [rsp]",
+        "mov rsi, rsp",
+        "and rsp, -16",
+        "call {start}",
+        "ud2",
+        start = sym start_main,
+    )
+}
+
+extern "C" fn start_main(argc: isize, argv: *const isize) {
+    unsafe {
+        env::ENVP = argv.add(argc as usize + 2) as *const *const u8;
+    }
+    let ret = unsafe { main(argc, argv) };
     exit(ret);
 }
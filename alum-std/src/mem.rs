@@ -0,0 +1,71 @@
+use core::arch::asm;
+use core::ptr::null_mut;
+
+/// Every block starts with a header holding the size of its mapping, so
+/// `free` knows how much to unmap. 16 bytes keeps the payload aligned.
+const HEADER: usize = 16;
+
+fn mmap(len: usize) -> isize {
+    let ret: isize;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") 9isize => ret,
+            in("rdi") 0,
+            in("rsi") len,
+            in("rdx") 3,
+            in("r10") 0x22,
+            in("r8") -1isize,
+            in("r9") 0,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+fn munmap(addr: usize, len: usize) -> isize {
+    let ret: isize;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") 11isize => ret,
+            in("rdi") addr,
+            in("rsi") len,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[inline(never)]
+#[unsafe(no_mangle)]
+pub extern "C" fn malloc(size: usize) -> *mut u8 {
+    let len = match size.checked_add(HEADER) {
+        Some(len) => len,
+        None => return null_mut(),
+    };
+    let base = mmap(len);
+    if base < 0 && base > -4096 {
+        return null_mut();
+    }
+    unsafe {
+        *(base as *mut usize) = len;
+        (base as *mut u8).add(HEADER)
+    }
+}
+
+#[inline(never)]
+#[unsafe(no_mangle)]
+pub extern "C" fn free(ptr: *mut u8) {
+    if ptr.is_null() {
+        return;
+    }
+    unsafe {
+        let base = ptr.sub(HEADER);
+        munmap(base as usize, *(base as *const usize));
+    }
+}
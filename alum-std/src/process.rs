@@ -0,0 +1,57 @@
+use core::arch::asm;
+
+fn syscall4(nr: isize, a1: isize, a2: isize, a3: isize, a4: isize) -> isize {
+    let ret: isize;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") nr => ret,
+            in("rdi") a1,
+            in("rsi") a2,
+            in("rdx") a3,
+            in("r10") a4,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+/// Returns 0 in the child, the child's pid in the parent, and a negative
+/// errno on failure.
+#[inline(never)]
+#[unsafe(no_mangle)]
+pub extern "C" fn fork() -> isize {
+    syscall4(57, 0, 0, 0, 0)
+}
+
+/// `argv` and `envp` are Alum arrays of string addresses whose last element
+/// is 0. Only returns on failure, with a negative errno.
+#[inline(never)]
+#[unsafe(no_mangle)]
+pub extern "C" fn execve(path: *const u8, argv: *const isize, envp: *const isize) -> isize {
+    unsafe {
+        syscall4(
+            59,
+            path as isize,
+            argv.add(1) as isize,
+            envp.add(1) as isize,
+            0,
+        )
+    }
+}
+
+/// Waits for `pid` to change state and returns its pid, or a negative errno.
+/// Unless `status` is 0, the raw wait status is stored into its first
+/// element; the exit code of a child that exited is `(status >> 8) & 255`.
+#[inline(never)]
+#[unsafe(no_mangle)]
+pub extern "C" fn waitpid(pid: isize, status: *mut isize, options: isize) -> isize {
+    let mut raw: i32 = 0;
+    let ret = syscall4(61, pid, &raw mut raw as isize, options, 0);
+    if ret >= 0 && !status.is_null() {
+        unsafe { *status.add(1) = raw as isize };
+    }
+    ret
+}
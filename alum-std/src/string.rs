@@ -1,3 +1,6 @@
+use crate::convert::{ftoa, itoa};
+use crate::mem::{free, malloc};
+
 #[unsafe(no_mangle)]
 pub extern "C" fn strlen(ptr: *const u8) -> usize {
     let mut len = 0;
@@ -104,3 +107,35 @@ pub unsafe extern "C" fn memcmp(s1: *const u8, s2: *const u8, n: usize) -> i32 {
     }
     0
 }
+
+/// Returns a new `malloc`ed string holding `acc` followed by `s`, and frees
+/// `acc`. A null `acc` starts a new string. Interpolated strings are built
+/// from these calls, one per piece.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fmt_str(acc: *mut u8, s: *const u8) -> *mut u8 {
+    let head = if acc.is_null() { 0 } else { strlen(acc) };
+    let tail = strlen(s);
+    let out = malloc(head + tail + 1);
+    if out.is_null() {
+        return out;
+    }
+    unsafe {
+        if !acc.is_null() {
+            memcpy(out, acc, head);
+        }
+        memcpy(out.add(head), s, tail);
+        *out.add(head + tail) = b'\0';
+    }
+    free(acc);
+    out
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fmt_int(acc: *mut u8, n: isize) -> *mut u8 {
+    unsafe { fmt_str(acc, itoa(n)) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fmt_flt(acc: *mut u8, n: f64) -> *mut u8 {
+    unsafe { fmt_str(acc, ftoa(n)) }
+}
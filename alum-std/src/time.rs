@@ -0,0 +1,35 @@
+use core::arch::asm;
+
+fn nanosleep(req: *const [isize; 2], rem: *mut [isize; 2]) -> isize {
+    let ret: isize;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") 35isize => ret,
+            in("rdi") req,
+            in("rsi") rem,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+/// Sleeps for `ms` milliseconds, resuming after signal interruptions until
+/// the full time has elapsed. Returns 0, or a negative errno.
+#[inline(never)]
+#[unsafe(no_mangle)]
+pub extern "C" fn sleep_ms(ms: isize) -> isize {
+    if ms < 0 {
+        return -22;
+    }
+    let mut req = [ms / 1000, ms % 1000 * 1_000_000];
+    let mut rem = [0isize; 2];
+    loop {
+        match nanosleep(&req, &mut rem) {
+            -4 => req = rem,
+            ret => return ret,
+        }
+    }
+}
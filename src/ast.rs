@@ -29,7 +29,11 @@ pub enum Expr {
     Return(Return),
     Label(Label),
     Goto(Goto),
+    Interp(Interp),
+    Break(Option<String>),
+    Continue(Option<String>),
     Extern(Extern),
+    ExternVar(ExternVar),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -78,12 +82,15 @@ pub struct If {
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct While {
+    pub label: Option<String>,
     pub condition: Box<Expr>,
     pub body: Box<Expr>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct For {
+    pub label: Option<String>,
+    pub index: Option<String>,
     pub init: String,
     pub iter: Box<Expr>,
     pub body: Box<Expr>,
@@ -102,7 +109,6 @@ pub struct FuncDecl {
 pub struct FuncCall {
     pub name: String,
     pub args: Vec<Expr>,
-    pub ret_type: VarType,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -115,6 +121,13 @@ pub struct Label {
     pub name: String,
 }
 
+/// An interpolated string: string literals and the values of the
+/// expressions between them, joined in order.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Interp {
+    pub parts: Vec<Expr>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Goto {
     pub label: String,
@@ -139,3 +152,10 @@ pub struct Extern {
     pub params: Vec<VarType>,
     pub ret_type: VarType,
 }
+
+/// A global variable defined outside the program, such as a C global.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExternVar {
+    pub name: String,
+    pub typ: VarType,
+}
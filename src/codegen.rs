@@ -1,7 +1,12 @@
 use ordered_float::OrderedFloat;
 
 use crate::ir::{IRConst, IRFunction, IRProgram, IRType, Instruction, Op, Operand};
-use std::{collections::HashMap, mem::take};
+use std::{
+    collections::{HashMap, HashSet},
+    mem::take,
+    num::NonZeroUsize,
+    thread,
+};
 
 #[derive(Debug, Clone)]
 pub enum CodeGenError {
@@ -12,6 +17,24 @@ pub enum CodeGenError {
 
 impl std::error::Error for CodeGenError {}
 
+impl CodeGenError {
+    /// Names the instruction and function being compiled when the error arose.
+    fn within(self, code: &Instruction, func: &str) -> Self {
+        let context = |message: String| format!("{} (in {:?} of `{}`)", message, code, func);
+        match self {
+            CodeGenError::MissingOperand { message } => CodeGenError::MissingOperand {
+                message: context(message),
+            },
+            CodeGenError::InvalidOperand { message } => CodeGenError::InvalidOperand {
+                message: context(message),
+            },
+            CodeGenError::UnsupportedOperation { message } => CodeGenError::UnsupportedOperation {
+                message: context(message),
+            },
+        }
+    }
+}
+
 impl std::fmt::Display for CodeGenError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -30,22 +53,172 @@ macro_rules! assemble {
     };
 }
 
+/// The operands `op` stands for: the elements of an array literal, which is
+/// built from the temporaries holding them, or else `op` itself.
+fn expand<'a>(op: &'a Operand, constants: &'a [IRConst]) -> &'a [Operand] {
+    match op {
+        Operand::ConstIdx(idx) => match &constants[*idx] {
+            IRConst::Array(_, _, elems) => elems,
+            _ => &[],
+        },
+        op => std::slice::from_ref(op),
+    }
+}
+
+/// The condition code suffix of a comparison, as in `setl`/`jl`. Float
+/// comparisons read the unsigned flags `ucomisd` sets.
+fn condition(op: &Op) -> &'static str {
+    match op {
+        Op::Eq | Op::FEq => "e",
+        Op::Ne | Op::FNe => "ne",
+        Op::Gt => "g",
+        Op::Ge => "ge",
+        Op::Lt => "l",
+        Op::Le => "le",
+        Op::FGt => "a",
+        Op::FGe => "ae",
+        Op::FLt => "b",
+        Op::FLe => "be",
+        _ => unreachable!(),
+    }
+}
+
+/// Whether running on from `insts[from]` reaches `label`, or the end of the
+/// function for `None`, passing nothing but labels. A jump there is then
+/// redundant.
+fn falls_into(insts: &[Instruction], from: usize, label: Option<&str>) -> bool {
+    for inst in &insts[from..] {
+        match &inst.op {
+            Op::Label(name) if Some(name.as_str()) == label => return true,
+            Op::Label(_) => {}
+            _ => return false,
+        }
+    }
+    label.is_none()
+}
+
+/// Registers that hold temporaries. They are callee-saved, so a temporary
+/// keeps its value across calls, and the code generator uses none of them
+/// as scratch.
+const TEMP_REGS: [&str; 5] = ["rbx", "r12", "r13", "r14", "r15"];
+
+/// Assigns registers to the integer temporaries of a function by linear
+/// scan; a temporary left without one lives in its stack slot. Only a
+/// temporary whose uses all lie in straight-line code between two labels or
+/// jumps is considered, so its live range is simply the span from its first
+/// to its last use.
+fn assign_registers(insts: &[Instruction], constants: &[IRConst]) -> HashMap<usize, &'static str> {
+    let mut ranges: HashMap<usize, (usize, usize)> = HashMap::new();
+    let mut excluded = HashSet::new();
+    let mut block = 0;
+    let mut blocks: HashMap<usize, usize> = HashMap::new();
+    for (i, inst) in insts.iter().enumerate() {
+        if matches!(inst.op, Op::Label(_)) {
+            block += 1;
+        }
+        for op in [&inst.dst, &inst.src1, &inst.src2].into_iter().flatten() {
+            for elem in expand(op, constants) {
+                let Operand::Temp(id, typ) = elem else {
+                    continue;
+                };
+                let scalar = matches!(
+                    typ,
+                    IRType::Int | IRType::Bool | IRType::String | IRType::Array(..)
+                );
+                if !scalar || *blocks.entry(*id).or_insert(block) != block {
+                    excluded.insert(*id);
+                }
+                ranges.entry(*id).or_insert((i, i)).1 = i;
+            }
+        }
+        if matches!(inst.op, Op::Jump | Op::JumpIfFalse | Op::Return(_)) {
+            block += 1;
+        }
+    }
+
+    let mut order: Vec<(usize, usize, usize)> = ranges
+        .into_iter()
+        .filter(|(id, _)| !excluded.contains(id))
+        .map(|(id, (start, end))| (start, end, id))
+        .collect();
+    order.sort();
+
+    let mut assigned = HashMap::new();
+    let mut active: Vec<(usize, &'static str)> = Vec::new();
+    let mut free: Vec<&'static str> = TEMP_REGS.iter().rev().copied().collect();
+    for (start, end, id) in order {
+        active.retain(|(last, reg)| {
+            if *last < start {
+                free.push(reg);
+                false
+            } else {
+                true
+            }
+        });
+        if let Some(reg) = free.pop() {
+            assigned.insert(id, reg);
+            active.push((end, reg));
+        }
+    }
+    assigned
+}
+
+/// The operand last loaded into or stored from each register. A register
+/// loaded with an integer constant also remembers that constant until it is
+/// written again, even while its operand changes to the variables the
+/// constant is stored into.
+#[derive(Default)]
+struct RegCache {
+    ops: HashMap<String, Option<Operand>>,
+    ints: HashMap<String, i64>,
+}
+
+impl RegCache {
+    fn get(&self, reg: &str) -> Option<&Option<Operand>> {
+        self.ops.get(reg)
+    }
+
+    fn insert(&mut self, reg: String, op: Option<Operand>) {
+        self.ints.remove(&reg);
+        self.ops.insert(reg, op);
+    }
+
+    fn remove(&mut self, reg: &str) {
+        self.ints.remove(reg);
+        self.ops.remove(reg);
+    }
+
+    fn clear(&mut self) {
+        self.ints.clear();
+        self.ops.clear();
+    }
+
+    /// Records that `reg` also holds `op` without having been written, so a
+    /// constant it was loaded with stays known.
+    fn alias(&mut self, reg: &str, op: Operand) {
+        self.ops.insert(reg.to_string(), Some(op));
+    }
+}
+
 pub struct CodeGen {
     program: IRProgram,
     text: String,
     data: String,
     vars: HashMap<String, usize>,
     lbl_cnt: usize,
+    lbl_scope: String,
     str_cache: HashMap<String, String>,
-    flt_cache: HashMap<OrderedFloat<f64>, String>,
-    stack_ptr: usize,
+    /// Float labels keyed by bits, so -0.0 and 0.0 stay apart.
+    flt_cache: HashMap<u64, String>,
     arg_reg: Vec<String>,
     flt_arg_reg: Vec<String>,
     ret_label: String,
-    regs: HashMap<String, Option<Operand>>,
+    regs: RegCache,
+    temp_regs: HashMap<usize, &'static str>,
     curr_fn: String,
-    loop_label: String,
     curr_flt_reg: usize,
+    runtime_syms: Vec<&'static str>,
+    externs: HashSet<String>,
 }
 
 impl CodeGen {
@@ -56,9 +229,9 @@ impl CodeGen {
             data: String::new(),
             vars: HashMap::new(),
             lbl_cnt: 0,
+            lbl_scope: String::new(),
             str_cache: HashMap::new(),
             flt_cache: HashMap::new(),
-            stack_ptr: 0,
             arg_reg: vec![
                 "rdi".to_string(),
                 "rsi".to_string(),
@@ -86,59 +259,136 @@ impl CodeGen {
                 "xmm15".to_string(),
             ],
             ret_label: String::new(),
-            regs: HashMap::new(),
+            regs: RegCache::default(),
+            temp_regs: HashMap::new(),
             curr_fn: String::new(),
-            loop_label: String::new(),
             curr_flt_reg: 0,
+            runtime_syms: Vec::new(),
+            externs: HashSet::new(),
         }
     }
 
+    /// Functions are compiled in parallel, in contiguous chunks whose output is
+    /// joined in program order. String and float constants get their labels
+    /// up front, so the result does not depend on how the work was split.
     pub fn compile(&mut self) -> Result<String, CodeGenError> {
+        let threads = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        self.compile_with(threads)
+    }
+
+    fn compile_with(&mut self, threads: usize) -> Result<String, CodeGenError> {
         assemble!(self.text, "section .text");
         assemble!(self.data, "section .data");
         assemble!(self.data, "align 16");
         assemble!(self.data, "neg_mask: dq 0x8000000000000000, 0");
-        for func in take(&mut self.program.functions) {
-            self.compile_fn(func)?;
+        for constant in self.program.constants.clone() {
+            match constant {
+                IRConst::Str(s) => {
+                    self.alloc_str(s);
+                }
+                IRConst::Float(f) => {
+                    self.alloc_flt(f);
+                }
+                _ => {}
+            }
         }
-        Ok(take(&mut self.data) + &self.optim(self.text.clone()))
-    }
 
-    fn optim(&mut self, src: String) -> String {
-        let lines: Vec<String> = src.lines().map(|s| s.to_string()).collect();
-        let mut result = Vec::new();
-        let mut i = 0;
+        let functions = take(&mut self.program.functions);
+        self.externs = functions
+            .iter()
+            .filter(|f| f.is_external)
+            .map(|f| f.name.clone())
+            .collect();
+        let chunk_size = functions.len().div_ceil(threads).max(1);
+        let workers: Vec<CodeGen> = functions
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(i, _)| self.worker(i))
+            .collect();
+        let results: Vec<Result<CodeGen, CodeGenError>> = thread::scope(|s| {
+            let handles: Vec<_> = functions
+                .chunks(chunk_size)
+                .zip(workers)
+                .map(|(chunk, mut worker)| {
+                    s.spawn(move || {
+                        for func in chunk {
+                            worker.compile_fn(func.clone())?;
+                        }
+                        Ok(worker)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().expect("codegen worker panicked"))
+                .collect()
+        });
 
-        while i < lines.len() {
-            let current = lines[i].trim();
+        for result in results {
+            let worker = result?;
+            self.text.push_str(&worker.text);
+            self.data.push_str(&worker.data);
+            for sym in worker.runtime_syms {
+                self.use_runtime(sym);
+            }
+        }
+        for sym in take(&mut self.runtime_syms) {
+            assemble!(self.text, "extern {}", sym);
+        }
+        for name in take(&mut self.program.globals) {
+            assemble!(self.text, "extern {}", name);
+        }
+        // Marks the stack non-executable, so linkers accept the object without
+        // warnings when it is linked with C code.
+        assemble!(
+            self.text,
+            "section .note.GNU-stack noalloc noexec nowrite progbits"
+        );
+        Ok(take(&mut self.data) + &self.optim(self.text.clone()))
+    }
 
-            if let Some(push_reg) = current.strip_prefix("push ") {
-                if i + 1 < lines.len() {
-                    let next = lines[i + 1].trim();
+    /// A code generator for one chunk of functions. It shares the constant
+    /// labels allocated so far; any label it allocates itself is scoped to it.
+    fn worker(&self, idx: usize) -> CodeGen {
+        let program = IRProgram {
+            functions: Vec::new(),
+            constants: self.program.constants.clone(),
+            globals: Vec::new(),
+        };
+        let mut worker = CodeGen::new(program);
+        worker.str_cache = self.str_cache.clone();
+        worker.flt_cache = self.flt_cache.clone();
+        worker.externs = self.externs.clone();
+        worker.lbl_scope = format!("{}.", idx);
+        worker
+    }
 
-                    if let Some(pop_reg) = next.strip_prefix("pop ") {
-                        let push_reg = push_reg.trim();
-                        let pop_reg = pop_reg.trim();
+    /// Folds each `push` directly followed by a `pop` into a `mov`, or drops
+    /// the pair when both name the same register. Lines are kept on a stack,
+    /// so removing a pair lets the `push` before it meet the next `pop` in the
+    /// same pass.
+    fn optim(&mut self, src: String) -> String {
+        let mut result: Vec<String> = Vec::new();
 
-                        if push_reg == pop_reg {
-                            i += 2;
-                            continue;
-                        } else {
-                            result.push(format!("mov {}, {}", pop_reg, push_reg));
-                            i += 2;
-                            continue;
-                        }
+        for line in src.lines() {
+            if let Some(pop_reg) = line.trim().strip_prefix("pop ") {
+                let push_reg = result
+                    .last()
+                    .and_then(|last| last.trim().strip_prefix("push "))
+                    .map(|reg| reg.trim().to_string());
+                if let Some(push_reg) = push_reg {
+                    let pop_reg = pop_reg.trim();
+                    result.pop();
+                    if push_reg != pop_reg {
+                        result.push(format!("mov {}, {}", pop_reg, push_reg));
                     }
+                    continue;
                 }
             }
-
-            result.push(lines[i].clone());
-            i += 1;
+            result.push(line.to_string());
         }
 
-        let opt = result.join("\n");
-
-        if opt == src { opt } else { self.optim(opt) }
+        result.join("\n")
     }
 
     fn compile_code(&mut self, code: Instruction) -> Result<(), CodeGenError> {
@@ -160,15 +410,13 @@ impl CodeGen {
                 self.load(src, "rax")?;
 
                 if match src {
-                    Operand::Var(_) | Operand::Temp(_, _) => {
-                        self.get_offset(src)? != self.get_offset(dst)?
-                    }
+                    Operand::Var(_) | Operand::Temp(_, _) => self.slot(src)? != self.slot(dst)?,
                     _ => true,
                 } {
-                    assemble!(self.text, "mov [rbp - {}], rax", self.get_offset(dst)?);
+                    assemble!(self.text, "mov {}, rax", self.slot(dst)?);
                 }
 
-                self.regs.insert("rax".to_string(), Some(dst.clone()));
+                self.regs.alias("rax", dst.clone());
                 Ok(())
             }
             Op::FMove => {
@@ -188,12 +436,10 @@ impl CodeGen {
                 self.load(src, "xmm0")?;
 
                 if match src {
-                    Operand::Var(_) | Operand::Temp(_, _) => {
-                        self.get_offset(src)? != self.get_offset(dst)?
-                    }
+                    Operand::Var(_) | Operand::Temp(_, _) => self.slot(src)? != self.slot(dst)?,
                     _ => true,
                 } {
-                    assemble!(self.text, "movsd [rbp - {}], xmm0", self.get_offset(dst)?);
+                    assemble!(self.text, "movsd {}, xmm0", self.slot(dst)?);
                 }
 
                 self.regs.insert("xmm0".to_string(), Some(dst.clone()));
@@ -213,8 +459,12 @@ impl CodeGen {
                         message: "Load/Store operation requires dst".to_string(),
                     })?;
                 self.load(src, "rax")?;
-                assemble!(self.text, "mov [rbp - {}], rax", self.get_offset(dst)?);
-                self.regs.insert("rax".to_string(), Some(dst.clone()));
+                if let Operand::Global(name, _) = dst {
+                    assemble!(self.text, "mov [rel {}], rax", name);
+                    return Ok(());
+                }
+                assemble!(self.text, "mov {}, rax", self.slot(dst)?);
+                self.regs.alias("rax", dst.clone());
                 Ok(())
             }
 
@@ -232,11 +482,24 @@ impl CodeGen {
                         message: "FLoad/FStore operation requires dst".to_string(),
                     })?;
                 self.load(src, "xmm0")?;
-                assemble!(self.text, "movsd [rbp - {}], xmm0", self.get_offset(dst)?);
+                if let Operand::Global(name, _) = dst {
+                    assemble!(self.text, "movsd [rel {}], xmm0", name);
+                    return Ok(());
+                }
+                assemble!(self.text, "movsd {}, xmm0", self.slot(dst)?);
                 self.regs.insert("xmm0".to_string(), Some(dst.clone()));
                 Ok(())
             }
-            Op::Add | Op::Sub | Op::Mul | Op::Div | Op::LAnd | Op::LOr | Op::Xor | Op::Not => {
+            Op::Add
+            | Op::Sub
+            | Op::Mul
+            | Op::Div
+            | Op::Mod
+            | Op::And
+            | Op::Or
+            | Op::Xor
+            | Op::Shl
+            | Op::Shr => {
                 let dst = code
                     .dst
                     .as_ref()
@@ -256,39 +519,59 @@ impl CodeGen {
                         message: "Binary operation requires src2".to_string(),
                     })?;
                 let asm_op = self.get_asm_op(&code.op).to_string();
-                self.load(src1, "rax");
+                self.load(src1, "rax")?;
 
                 match src2 {
+                    // A variable shift count has to be in `cl`.
+                    _ if matches!(code.op, Op::Shl | Op::Shr) => {
+                        match self.int_const(src2).filter(|v| (0..64).contains(v)) {
+                            Some(v) => assemble!(self.text, "{} rax, {}", asm_op, v),
+                            None => {
+                                self.load(src2, "rcx")?;
+                                assemble!(self.text, "{} rax, cl", asm_op);
+                            }
+                        }
+                    }
                     Operand::ConstIdx(idx) => {
-                        if let IRConst::Int(v) = &self.program.constants[*idx] {
-                            assemble!(self.text, "{} rax, {}", asm_op, v);
+                        if let IRConst::Int(v) = self.program.constants[*idx] {
+                            match (&code.op, v) {
+                                // Scaled addressing multiplies without `imul`;
+                                // powers of two are already shifts.
+                                (Op::Mul, 3 | 5 | 9) => {
+                                    assemble!(self.text, "lea rax, [rax + rax * {}]", v - 1);
+                                }
+                                (Op::Mul, _) => assemble!(self.text, "imul rax, rax, {}", v),
+                                _ => assemble!(self.text, "{} rax, {}", asm_op, v),
+                            }
                         }
                     }
                     Operand::Const(IRConst::Int(v)) => {
                         assemble!(self.text, "{} rax, {}", asm_op, v);
                     }
                     Operand::Var(_) | Operand::Temp(_, _) => {
-                        let off = self.get_offset(src2)?;
-                        if matches!(code.op, Op::Div) {
-                            self.load(src2, "rbx")?;
+                        if matches!(code.op, Op::Div | Op::Mod) {
+                            self.load(src2, "r11")?;
                             assemble!(self.text, "cqo");
-                            assemble!(self.text, "idiv rbx");
+                            assemble!(self.text, "idiv r11");
+                            if matches!(code.op, Op::Mod) {
+                                assemble!(self.text, "mov rax, rdx");
+                            }
                         } else {
-                            assemble!(self.text, "{} rax, qword [rbp - {}]", asm_op, off);
+                            assemble!(self.text, "{} rax, {}", asm_op, self.slot(src2)?);
                         }
                     }
                     _ => {
-                        self.load(src2, "rbx");
-                        assemble!(self.text, "{} rax, rbx", asm_op);
+                        self.load(src2, "r11")?;
+                        assemble!(self.text, "{} rax, r11", asm_op);
                     }
                 }
 
-                assemble!(self.text, "mov [rbp - {}], rax", self.get_offset(dst)?);
+                assemble!(self.text, "mov {}, rax", self.slot(dst)?);
 
                 self.regs.remove("rax");
                 self.regs.remove("rdx");
-                if matches!(code.op, Op::Div) {
-                    self.regs.remove("rbx");
+                if matches!(code.op, Op::Div | Op::Mod) {
+                    self.regs.remove("r11");
                 }
 
                 self.regs.insert("rax".to_string(), Some(dst.clone()));
@@ -316,7 +599,7 @@ impl CodeGen {
 
                 let fasm_op = self.get_fasm_op(&code.op).to_string();
 
-                self.load(src1, "xmm0");
+                self.load(src1, "xmm0")?;
 
                 match src2 {
                     Operand::ConstIdx(idx) => {
@@ -327,8 +610,7 @@ impl CodeGen {
                     }
 
                     Operand::Var(_) | Operand::Temp(_, _) => {
-                        let off = self.get_offset(src2)?;
-                        assemble!(self.text, "{} xmm0, qword [rbp - {}]", fasm_op, off);
+                        assemble!(self.text, "{} xmm0, {}", fasm_op, self.slot(src2)?);
                     }
                     _ => {
                         self.load(src2, "xmm1")?;
@@ -336,7 +618,7 @@ impl CodeGen {
                     }
                 }
 
-                assemble!(self.text, "movsd [rbp - {}], xmm0", self.get_offset(dst)?);
+                assemble!(self.text, "movsd {}, xmm0", self.slot(dst)?);
 
                 self.regs.insert("xmm0".to_string(), Some(dst.clone()));
                 Ok(())
@@ -360,23 +642,48 @@ impl CodeGen {
                     .ok_or_else(|| CodeGenError::MissingOperand {
                         message: "Comparison operation requires src2".to_string(),
                     })?;
+                self.compare(src1, src2)?;
+                assemble!(self.text, "set{} al", condition(&code.op));
+                // `setcc` writes only `al`. Writing `eax` zeroes bits 32..63 of
+                // `rax` as well, so the 64-bit store below holds exactly 0 or 1.
+                assemble!(self.text, "movzx eax, al");
+                assemble!(self.text, "mov {}, rax", self.slot(dst)?);
+                self.regs.clear();
+                self.regs.insert("rax".to_string(), Some(dst.clone()));
+                Ok(())
+            }
+            Op::LAnd | Op::LOr => {
+                let dst = code
+                    .dst
+                    .as_ref()
+                    .ok_or_else(|| CodeGenError::MissingOperand {
+                        message: "Logical operation requires dst".to_string(),
+                    })?;
+                let src1 = code
+                    .src1
+                    .as_ref()
+                    .ok_or_else(|| CodeGenError::MissingOperand {
+                        message: "Logical operation requires src1".to_string(),
+                    })?;
+                let src2 = code
+                    .src2
+                    .as_ref()
+                    .ok_or_else(|| CodeGenError::MissingOperand {
+                        message: "Logical operation requires src2".to_string(),
+                    })?;
                 self.load(src1, "rax")?;
-                self.load(src2, "rbx")?;
-                assemble!(self.text, "cmp rax, rbx");
-                let set_op = match code.op {
-                    Op::Eq => "sete",
-                    Op::Ne => "setne",
-                    Op::Gt => "setg",
-                    Op::Ge => "setge",
-                    Op::Lt => "setl",
-                    Op::Le => "setle",
-                    Op::And => "setne",
-                    Op::Or => "setne",
-                    _ => unreachable!(),
-                };
-                assemble!(self.text, "{} al", set_op);
+                self.load(src2, "r11")?;
+                assemble!(self.text, "test rax, rax");
+                assemble!(self.text, "setne al");
+                assemble!(self.text, "test r11, r11");
+                assemble!(self.text, "setne r11b");
+                if matches!(code.op, Op::LAnd) {
+                    assemble!(self.text, "and al, r11b");
+                } else {
+                    assemble!(self.text, "or al, r11b");
+                }
                 assemble!(self.text, "movzx eax, al");
-                assemble!(self.text, "mov [rbp - {}], rax", self.get_offset(dst)?);
+                assemble!(self.text, "mov {}, rax", self.slot(dst)?);
                 self.regs.clear();
                 self.regs.insert("rax".to_string(), Some(dst.clone()));
                 Ok(())
@@ -400,21 +707,10 @@ impl CodeGen {
                     .ok_or_else(|| CodeGenError::MissingOperand {
                         message: "Float comparison operation requires src2".to_string(),
                     })?;
-                self.load(src1, "xmm0")?;
-                self.load(src2, "xmm1")?;
-                assemble!(self.text, "ucomisd xmm0, xmm1");
-                let set_op = match code.op {
-                    Op::FEq => "sete",
-                    Op::FNe => "setne",
-                    Op::FGt => "setg",
-                    Op::FGe => "setge",
-                    Op::FLt => "setl",
-                    Op::FLe => "setle",
-                    _ => unreachable!(),
-                };
-                assemble!(self.text, "{} al", set_op);
+                self.fcompare(src1, src2)?;
+                assemble!(self.text, "set{} al", condition(&code.op));
                 assemble!(self.text, "movzx eax, al");
-                assemble!(self.text, "mov [rbp - {}], rax", self.get_offset(dst)?);
+                assemble!(self.text, "mov {}, rax", self.slot(dst)?);
                 self.regs.clear();
                 self.regs.insert("rax".to_string(), Some(dst.clone()));
                 Ok(())
@@ -432,9 +728,51 @@ impl CodeGen {
                     .ok_or_else(|| CodeGenError::MissingOperand {
                         message: "Unary operation requires src1".to_string(),
                     })?;
-                self.load(src1, "rax");
+                self.load(src1, "rax")?;
                 assemble!(self.text, "mov rax, [rax]");
-                assemble!(self.text, "mov [rbp - {}], rax", self.get_offset(dst)?);
+                assemble!(self.text, "mov {}, rax", self.slot(dst)?);
+                self.regs.clear();
+                self.regs.insert("rax".to_string(), Some(dst.clone()));
+                Ok(())
+            }
+            Op::Neg => {
+                let dst = code
+                    .dst
+                    .as_ref()
+                    .ok_or_else(|| CodeGenError::MissingOperand {
+                        message: "Neg operation requires dst".to_string(),
+                    })?;
+                let src1 = code
+                    .src1
+                    .as_ref()
+                    .ok_or_else(|| CodeGenError::MissingOperand {
+                        message: "Neg operation requires src1".to_string(),
+                    })?;
+                self.load(src1, "rax")?;
+                assemble!(self.text, "neg rax");
+                assemble!(self.text, "mov {}, rax", self.slot(dst)?);
+                self.regs.clear();
+                self.regs.insert("rax".to_string(), Some(dst.clone()));
+                Ok(())
+            }
+            Op::Not => {
+                let dst = code
+                    .dst
+                    .as_ref()
+                    .ok_or_else(|| CodeGenError::MissingOperand {
+                        message: "Not operation requires dst".to_string(),
+                    })?;
+                let src1 = code
+                    .src1
+                    .as_ref()
+                    .ok_or_else(|| CodeGenError::MissingOperand {
+                        message: "Not operation requires src1".to_string(),
+                    })?;
+                self.load(src1, "rax")?;
+                assemble!(self.text, "test rax, rax");
+                assemble!(self.text, "sete al");
+                assemble!(self.text, "movzx eax, al");
+                assemble!(self.text, "mov {}, rax", self.slot(dst)?);
                 self.regs.clear();
                 self.regs.insert("rax".to_string(), Some(dst.clone()));
                 Ok(())
@@ -452,9 +790,9 @@ impl CodeGen {
                     .ok_or_else(|| CodeGenError::MissingOperand {
                         message: "FNeg operation requires src1".to_string(),
                     })?;
-                self.load(src1, "xmm0");
+                self.load(src1, "xmm0")?;
                 assemble!(self.text, "xorpd xmm0, oword [rel neg_mask]");
-                assemble!(self.text, "movsd [rbp - {}], xmm0", self.get_offset(dst)?);
+                assemble!(self.text, "movsd {}, xmm0", self.slot(dst)?);
                 self.regs.clear();
                 self.regs.insert("xmm0".to_string(), Some(dst.clone()));
                 Ok(())
@@ -478,10 +816,47 @@ impl CodeGen {
                     .ok_or_else(|| CodeGenError::MissingOperand {
                         message: "Range operation requires src2".to_string(),
                     })?;
-                self.load(src1, "rdi");
-                self.load(src2, "rsi");
+                self.load(src1, "rdi")?;
+                self.load(src2, "rsi")?;
                 assemble!(self.text, "call range");
-                assemble!(self.text, "mov [rbp - {}], rax", self.get_offset(dst)?);
+                assemble!(self.text, "mov {}, rax", self.slot(dst)?);
+                self.regs.clear();
+                self.regs.insert("rax".to_string(), Some(dst.clone()));
+                Ok(())
+            }
+            Op::Append(typ) => {
+                let dst = code
+                    .dst
+                    .as_ref()
+                    .ok_or_else(|| CodeGenError::MissingOperand {
+                        message: "Append operation requires dst".to_string(),
+                    })?;
+                let src1 = code
+                    .src1
+                    .as_ref()
+                    .ok_or_else(|| CodeGenError::MissingOperand {
+                        message: "Append operation requires src1".to_string(),
+                    })?;
+                let src2 = code
+                    .src2
+                    .as_ref()
+                    .ok_or_else(|| CodeGenError::MissingOperand {
+                        message: "Append operation requires src2".to_string(),
+                    })?;
+                let func = match typ {
+                    IRType::String => "fmt_str",
+                    IRType::Float => "fmt_flt",
+                    _ => "fmt_int",
+                };
+                self.load(src1, "rdi")?;
+                if typ == IRType::Float {
+                    self.load(src2, "xmm0")?;
+                } else {
+                    self.load(src2, "rsi")?;
+                }
+                self.use_runtime(func);
+                assemble!(self.text, "call {}", func);
+                assemble!(self.text, "mov {}, rax", self.slot(dst)?);
                 self.regs.clear();
                 self.regs.insert("rax".to_string(), Some(dst.clone()));
                 Ok(())
@@ -495,7 +870,7 @@ impl CodeGen {
                     })?;
                 if n < 6 {
                     let reg = self.arg_reg[n].clone();
-                    self.load(op, &reg);
+                    self.load(op, &reg)?;
                 } else {
                     self.load(op, "rax")?;
                     assemble!(self.text, "push rax");
@@ -512,7 +887,7 @@ impl CodeGen {
                 if n < 8 {
                     self.curr_flt_reg = n + 1;
                     let reg = self.flt_arg_reg[n].clone();
-                    self.load(op, &reg);
+                    self.load(op, &reg)?;
                 } else {
                     self.curr_flt_reg = 8;
                     self.load(op, "xmm0")?;
@@ -535,10 +910,14 @@ impl CodeGen {
                         message: "Call operation requires src1".to_string(),
                     })?;
                 if let Operand::Function(name) = src1 {
-                    if self.curr_flt_reg > 0 {
-                        assemble!(self.text, "mov al, {}", self.curr_flt_reg);
-                    } else {
-                        assemble!(self.text, "xor al, al");
+                    // `al` bounds the vector registers used by a variadic
+                    // call. Only an external function can be variadic.
+                    if self.externs.contains(name) {
+                        if self.curr_flt_reg > 0 {
+                            assemble!(self.text, "mov al, {}", self.curr_flt_reg);
+                        } else {
+                            assemble!(self.text, "xor al, al");
+                        }
                     }
                     self.curr_flt_reg = 0;
 
@@ -553,17 +932,20 @@ impl CodeGen {
                         self.regs.remove(&format!("xmm{}", i));
                     }
 
-                    let is_float = match dst {
-                        Operand::Temp(_, IRType::Float) => true,
-                        _ => false,
-                    };
+                    let is_float = matches!(dst, Operand::Temp(_, IRType::Float));
+
+                    // C defines only the low byte of a returned `bool`.
+                    if self.externs.contains(name) && matches!(dst, Operand::Temp(_, IRType::Bool))
+                    {
+                        assemble!(self.text, "movzx eax, al");
+                    }
 
                     if is_float {
-                        assemble!(self.text, "movsd [rbp - {}], xmm0", self.get_offset(dst)?);
+                        assemble!(self.text, "movsd {}, xmm0", self.slot(dst)?);
 
                         self.regs.insert("xmm0".to_string(), Some(dst.clone()));
                     } else {
-                        assemble!(self.text, "mov [rbp - {}], rax", self.get_offset(dst)?);
+                        assemble!(self.text, "mov {}, rax", self.slot(dst)?);
 
                         self.regs.insert("rax".to_string(), Some(dst.clone()));
                     }
@@ -608,12 +990,12 @@ impl CodeGen {
                         });
                     }
                 };
-                self.load(src1, "rax");
+                self.load(src1, "rax")?;
                 assemble!(self.text, "cmp rax, 0");
                 assemble!(self.text, "je {}", lbl);
                 Ok(())
             }
-            Op::ArrayAccess => {
+            Op::ArrayAccess(width) => {
                 let dst = code
                     .dst
                     .as_ref()
@@ -633,15 +1015,18 @@ impl CodeGen {
                         message: "ArrayAccess operation requires src2".to_string(),
                     })?;
                 self.load(src1, "r10")?;
-                self.load(src2, "rcx")?;
-                assemble!(self.text, "lea rax, [r10 + rcx * 8 + 8]");
-                assemble!(self.text, "mov rax, [rax]");
-                assemble!(self.text, "mov [rbp - {}], rax", self.get_offset(dst)?);
+                let elem = self.element(src2, width)?;
+                if width == 1 {
+                    assemble!(self.text, "movzx eax, byte {}", elem);
+                } else {
+                    assemble!(self.text, "mov rax, {}", elem);
+                }
+                assemble!(self.text, "mov {}, rax", self.slot(dst)?);
                 self.regs.clear();
                 self.regs.insert("rax".to_string(), Some(dst.clone()));
                 Ok(())
             }
-            Op::ArrayAssign => {
+            Op::ArrayAssign(width) => {
                 let dst = code
                     .dst
                     .as_ref()
@@ -661,15 +1046,64 @@ impl CodeGen {
                         message: "ArrayAssign operation requires src2".to_string(),
                     })?;
                 self.load(dst, "r10")?;
-                self.load(src1, "rcx")?;
+                let elem = self.element(src1, width)?;
                 self.load(src2, "rax")?;
-                assemble!(self.text, "lea rdx, [r10 + rcx * 8 + 8]");
-                assemble!(self.text, "mov [rdx], rax");
+                if width == 1 {
+                    assemble!(self.text, "mov byte {}, al", elem);
+                } else {
+                    assemble!(self.text, "mov qword {}, rax", elem);
+                }
+                Ok(())
+            }
+            Op::HeapAlloc => {
+                let dst = code
+                    .dst
+                    .as_ref()
+                    .ok_or_else(|| CodeGenError::MissingOperand {
+                        message: "HeapAlloc operation requires dst".to_string(),
+                    })?;
+                let src1 = code
+                    .src1
+                    .as_ref()
+                    .ok_or_else(|| CodeGenError::MissingOperand {
+                        message: "HeapAlloc operation requires src1".to_string(),
+                    })?;
+                let (len, elem, arr) = match src1 {
+                    Operand::ConstIdx(idx) => match &self.program.constants[*idx] {
+                        IRConst::Array(len, elem, arr) => (*len, elem.clone(), arr.clone()),
+                        _ => {
+                            return Err(CodeGenError::InvalidOperand {
+                                message: "HeapAlloc src1 must be an array constant".to_string(),
+                            });
+                        }
+                    },
+                    _ => {
+                        return Err(CodeGenError::InvalidOperand {
+                            message: "HeapAlloc src1 must be an array constant".to_string(),
+                        });
+                    }
+                };
+                self.alloc_arr(len, &elem, arr, "rax", true)?;
+                assemble!(self.text, "mov {}, rax", self.slot(dst)?);
+                self.regs.insert("rax".to_string(), Some(dst.clone()));
+                Ok(())
+            }
+            Op::Free => {
+                let src1 = code
+                    .src1
+                    .as_ref()
+                    .ok_or_else(|| CodeGenError::MissingOperand {
+                        message: "Free operation requires src1".to_string(),
+                    })?;
+                self.use_runtime("free");
+                self.load(src1, "rdi")?;
+                assemble!(self.text, "call free");
+                self.regs.clear();
                 Ok(())
             }
             Op::Return(reg) => {
                 if let Some(ref val) = code.src1 {
-                    self.load(val, reg.as_str());
+                    self.load(val, reg.as_str())?;
                 }
                 assemble!(self.text, "jmp {}", self.ret_label);
                 Ok(())
@@ -688,32 +1122,33 @@ impl CodeGen {
 
         self.vars.clear();
         self.regs.clear();
+        self.temp_regs = assign_registers(&func.instructions, &self.program.constants);
         let mut offset = 0;
 
         for (param, _) in &func.params {
-            if let Operand::Var(name) = param {
-                if !self.vars.contains_key(name) {
-                    offset += 8;
-                    self.vars.insert(name.clone(), offset);
-                }
+            if let Operand::Var(name) = param
+                && !self.vars.contains_key(name)
+            {
+                offset += 8;
+                self.vars.insert(name.clone(), offset);
             }
         }
+        let mut locals = Vec::new();
         for inst in &func.instructions {
             let mut register_op = |op_opt: &Option<Operand>| {
                 if let Some(op) = op_opt {
                     match op {
-                        Operand::Var(name) => {
-                            if !self.vars.contains_key(name) {
-                                offset += 8;
-                                self.vars.insert(name.clone(), offset);
-                            }
+                        Operand::Var(name) if !self.vars.contains_key(name) => {
+                            offset += 8;
+                            self.vars.insert(name.clone(), offset);
+                            locals.push(offset);
                         }
-                        Operand::Temp(id, _) => {
+                        Operand::Temp(id, _) if !self.temp_regs.contains_key(id) => {
                             let temp_key = format!("_tmp_{}", id);
-                            if !self.vars.contains_key(&temp_key) {
+                            self.vars.entry(temp_key).or_insert_with(|| {
                                 offset += 8;
-                                self.vars.insert(temp_key, offset);
-                            }
+                                offset
+                            });
                         }
                         _ => {}
                     }
@@ -723,6 +1158,14 @@ impl CodeGen {
             register_op(&inst.src1);
             register_op(&inst.src2);
         }
+        // The registers holding temporaries belong to the caller.
+        let mut saved: Vec<(&'static str, usize)> = Vec::new();
+        for reg in TEMP_REGS {
+            if self.temp_regs.values().any(|r| *r == reg) {
+                offset += 8;
+                saved.push((reg, offset));
+            }
+        }
 
         let stack_size = (offset + 15) & !15;
         if func.is_pub {
@@ -734,6 +1177,14 @@ impl CodeGen {
         if stack_size > 0 {
             assemble!(self.text, "sub rsp, {}", stack_size);
         }
+        // A jump past a declaration leaves the variable unassigned; reading it
+        // then yields zero rather than whatever the slot held before.
+        for off in locals {
+            assemble!(self.text, "mov qword [rbp - {}], 0", off);
+        }
+        for (reg, off) in &saved {
+            assemble!(self.text, "mov [rbp - {}], {}", off, reg);
+        }
 
         let loop_label = format!(".L_{}_loop", func.name);
         assemble!(self.text, "{}:", loop_label);
@@ -764,35 +1215,100 @@ impl CodeGen {
         }
 
         let insts = &func.instructions;
+        let mut uses: HashMap<usize, usize> = HashMap::new();
+        for inst in insts {
+            for op in [&inst.src1, &inst.src2].into_iter().flatten() {
+                for elem in expand(op, &self.program.constants) {
+                    if let Operand::Temp(id, _) = elem {
+                        *uses.entry(*id).or_default() += 1;
+                    }
+                }
+            }
+        }
+        let mut fused = false;
         for (i, code) in insts.iter().enumerate() {
+            if take(&mut fused) {
+                continue;
+            }
+            // A comparison read only by the branch after it jumps directly.
+            if let (
+                Op::Eq
+                | Op::Ne
+                | Op::Gt
+                | Op::Ge
+                | Op::Lt
+                | Op::Le
+                | Op::FEq
+                | Op::FNe
+                | Op::FGt
+                | Op::FGe
+                | Op::FLt
+                | Op::FLe,
+                Some(Operand::Temp(id, _)),
+                Some(Instruction {
+                    op: Op::JumpIfFalse,
+                    src1: Some(Operand::Temp(cond, _)),
+                    src2: Some(Operand::Label(lbl)),
+                    ..
+                }),
+            ) = (&code.op, &code.dst, insts.get(i + 1))
+                && cond == id
+                && uses.get(id) == Some(&1)
+            {
+                self.branch_unless(code, lbl)
+                    .map_err(|e| e.within(code, &func.name))?;
+                fused = true;
+                continue;
+            }
             match &code.op {
                 Op::Return(reg_name) => {
                     if let Some(ref val) = code.src1 {
                         self.load(val, reg_name)?;
                     }
-                    assemble!(self.text, "jmp {}", self.ret_label);
+                    if !falls_into(insts, i + 1, None) {
+                        assemble!(self.text, "jmp {}", self.ret_label);
+                    }
                 }
+                Op::Jump
+                    if matches!(&code.src1, Some(Operand::Label(lbl))
+                        if falls_into(insts, i + 1, Some(lbl))) => {}
                 Op::Label(name) => {
                     assemble!(self.text, "{}:", name);
                     self.regs.clear();
                 }
                 _ => {
-                    self.compile_code(code.clone())?;
+                    self.compile_code(code.clone())
+                        .map_err(|e| e.within(code, &func.name))?;
                 }
             }
         }
 
         assemble!(self.text, "{}:", self.ret_label);
+        for (reg, off) in &saved {
+            assemble!(self.text, "mov {}, [rbp - {}]", reg, off);
+        }
         assemble!(self.text, "leave");
         assemble!(self.text, "ret");
         Ok(())
     }
 
     fn load(&mut self, op: &Operand, reg: &str) -> Result<(), CodeGenError> {
-        if let Some(Some(cached_op)) = self.regs.get(reg) {
-            if cached_op == op {
-                return Ok(());
-            }
+        if let Some(Some(cached_op)) = self.regs.get(reg)
+            && cached_op == op
+        {
+            return Ok(());
+        }
+        let int = match op {
+            Operand::ConstIdx(idx) => match self.program.constants[*idx] {
+                IRConst::Int(v) => Some(v),
+                _ => None,
+            },
+            Operand::Const(IRConst::Int(v)) => Some(*v),
+            _ => None,
+        };
+        if int.is_some() && self.regs.ints.get(reg).copied() == int {
+            self.regs.alias(reg, op.clone());
+            return Ok(());
         }
 
         match op {
@@ -812,8 +1328,9 @@ impl CodeGen {
                         let lbl = self.alloc_str(s.clone());
                         assemble!(self.text, "lea {}, [rel {}]", reg, lbl);
                     }
-                    IRConst::Array(len, arr) => {
-                        self.alloc_arr(*len, arr.clone(), reg)?;
+                    IRConst::Array(len, elem, arr) => {
+                        let (len, elem, arr) = (*len, elem.clone(), arr.clone());
+                        self.alloc_arr(len, &elem, arr, reg, false)?;
                     }
                     _ => {}
                 }
@@ -833,18 +1350,18 @@ impl CodeGen {
                     let lbl = self.alloc_str(s.clone());
                     assemble!(self.text, "lea {}, [rel {}]", reg, lbl);
                 }
-                IRConst::Array(len, arr) => {
-                    self.alloc_arr(*len, arr.clone(), reg)?;
+                IRConst::Array(len, elem, arr) => {
+                    self.alloc_arr(*len, elem, arr.clone(), reg, false)?;
                 }
                 _ => {}
             },
 
             Operand::Var(_) | Operand::Temp(_, _) => {
-                let off = self.get_offset(op)?;
+                let slot = self.slot(op)?;
                 if reg.starts_with("xmm") {
-                    assemble!(self.text, "movsd {}, qword [rbp - {}]", reg, off);
+                    assemble!(self.text, "movsd {}, {}", reg, slot);
                 } else {
-                    assemble!(self.text, "mov {}, [rbp - {}]", reg, off);
+                    assemble!(self.text, "mov {}, {}", reg, slot);
                 }
             }
 
@@ -852,22 +1369,35 @@ impl CodeGen {
                 assemble!(self.text, "lea {}, [rel {}]", reg, name);
             }
 
+            // Not cached: other code may change an extern variable at any time.
+            Operand::Global(name, _) => {
+                if reg.starts_with("xmm") {
+                    assemble!(self.text, "movsd {}, [rel {}]", reg, name);
+                } else {
+                    assemble!(self.text, "mov {}, [rel {}]", reg, name);
+                }
+                self.regs.remove(reg);
+                return Ok(());
+            }
+
             _ => {}
         }
 
         self.regs.insert(reg.to_string(), Some(op.clone()));
+        if let Some(v) = int {
+            self.regs.ints.insert(reg.to_string(), v);
+        }
         Ok(())
     }
 
     fn alloc_str(&mut self, s: String) -> String {
         if let Some(lbl) = self.str_cache.get(&s) {
-            return lbl.clone();
+            lbl.clone()
         } else {
-            let lbl = format!("L.S.{}", self.lbl_cnt);
+            let lbl = format!("L.S.{}{}", self.lbl_scope, self.lbl_cnt);
             self.str_cache.insert(s.clone(), lbl.clone());
             self.lbl_cnt += 1;
             let bytes = s.as_bytes();
-            let len = bytes.len();
             if s.is_empty() {
                 assemble!(self.data, "{} db 0", lbl,);
                 return lbl;
@@ -887,17 +1417,116 @@ impl CodeGen {
     }
 
     fn alloc_flt(&mut self, f: OrderedFloat<f64>) -> String {
-        if let Some(lbl) = self.flt_cache.get(&f) {
-            return lbl.clone();
+        if let Some(lbl) = self.flt_cache.get(&f.to_bits()) {
+            lbl.clone()
         } else {
-            let lbl = format!("L.F.{}", self.lbl_cnt);
-            self.flt_cache.insert(f, lbl.clone());
+            let lbl = format!("L.F.{}{}", self.lbl_scope, self.lbl_cnt);
+            self.flt_cache.insert(f.to_bits(), lbl.clone());
             self.lbl_cnt += 1;
             assemble!(self.data, "{} dq 0x{:x}", lbl, f.into_inner().to_bits());
             lbl
         }
     }
 
+    /// The address of element `index` of the array in `r10`, past its length
+    /// word. A constant index becomes part of the displacement; any other is
+    /// loaded into `rcx`.
+    fn element(&mut self, index: &Operand, width: usize) -> Result<String, CodeGenError> {
+        let disp = self
+            .int_const(index)
+            .and_then(|k| k.checked_mul(width as i64))
+            .and_then(|n| n.checked_add(8));
+        if let Some(disp) = disp.filter(|n| i32::try_from(*n).is_ok()) {
+            return Ok(format!("[r10 + {}]", disp));
+        }
+        self.load(index, "rcx")?;
+        Ok(format!("[r10 + rcx * {} + 8]", width))
+    }
+
+    /// Compares `src1`, loaded into `rax`, with `src2`.
+    fn compare(&mut self, src1: &Operand, src2: &Operand) -> Result<(), CodeGenError> {
+        self.load(src1, "rax")?;
+        match src2 {
+            Operand::ConstIdx(idx) => {
+                if let IRConst::Int(v) = self.program.constants[*idx] {
+                    assemble!(self.text, "cmp rax, {}", v);
+                }
+            }
+            _ => {
+                self.load(src2, "r11")?;
+                assemble!(self.text, "cmp rax, r11");
+            }
+        }
+        Ok(())
+    }
+
+    /// Compares the floats `src1` and `src2` in `xmm0` and `xmm1`.
+    fn fcompare(&mut self, src1: &Operand, src2: &Operand) -> Result<(), CodeGenError> {
+        self.load(src1, "xmm0")?;
+        self.load(src2, "xmm1")?;
+        assemble!(self.text, "ucomisd xmm0, xmm1");
+        Ok(())
+    }
+
+    /// Jumps to `lbl` unless the comparison `code` holds, in place of
+    /// storing its result and testing that.
+    fn branch_unless(&mut self, code: &Instruction, lbl: &str) -> Result<(), CodeGenError> {
+        let (Some(src1), Some(src2)) = (&code.src1, &code.src2) else {
+            return Err(CodeGenError::MissingOperand {
+                message: "Comparison operation requires src1 and src2".to_string(),
+            });
+        };
+        let negated = match code.op {
+            Op::Eq => Op::Ne,
+            Op::Ne => Op::Eq,
+            Op::Gt => Op::Le,
+            Op::Ge => Op::Lt,
+            Op::Lt => Op::Ge,
+            Op::Le => Op::Gt,
+            Op::FEq => Op::FNe,
+            Op::FNe => Op::FEq,
+            Op::FGt => Op::FLe,
+            Op::FGe => Op::FLt,
+            Op::FLt => Op::FGe,
+            Op::FLe => Op::FGt,
+            _ => unreachable!(),
+        };
+        if matches!(
+            code.op,
+            Op::FEq | Op::FNe | Op::FGt | Op::FGe | Op::FLt | Op::FLe
+        ) {
+            self.fcompare(src1, src2)?;
+        } else {
+            self.compare(src1, src2)?;
+        }
+        // The negated condition holds exactly when `setcc` would store 0.
+        assemble!(self.text, "j{} {}", condition(&negated), lbl);
+        Ok(())
+    }
+
+    /// The value of an integer constant operand.
+    fn int_const(&self, op: &Operand) -> Option<i64> {
+        match op {
+            Operand::ConstIdx(idx) => match self.program.constants[*idx] {
+                IRConst::Int(v) => Some(v),
+                _ => None,
+            },
+            Operand::Const(IRConst::Int(v)) => Some(*v),
+            _ => None,
+        }
+    }
+
+    /// Where `op` lives: the register assigned to a temporary, or else its
+    /// stack slot.
+    fn slot(&self, op: &Operand) -> Result<String, CodeGenError> {
+        if let Operand::Temp(id, _) = op
+            && let Some(reg) = self.temp_regs.get(id)
+        {
+            return Ok(reg.to_string());
+        }
+        Ok(format!("[rbp - {}]", self.get_offset(op)?))
+    }
+
     fn get_offset(&self, op: &Operand) -> Result<usize, CodeGenError> {
         match op {
             Operand::Var(name) => self
@@ -906,7 +1535,7 @@ impl CodeGen {
                 .ok_or_else(|| CodeGenError::MissingOperand {
                     message: format!("variable '{}' not found in stack frame", name),
                 })
-                .map(|v| *v),
+                .copied(),
             Operand::Temp(id, _) => {
                 let key = format!("_tmp_{}", id);
                 self.vars
@@ -914,38 +1543,76 @@ impl CodeGen {
                     .ok_or_else(|| CodeGenError::MissingOperand {
                         message: format!("temporary '{}' not found in stack frame", key),
                     })
-                    .map(|v| *v)
+                    .copied()
             }
             _ => Err(CodeGenError::InvalidOperand {
-                message: "Not a stack operand".to_string(),
+                message: format!("{:?} is not a stack operand", op),
             }),
         }
     }
 
-    fn alloc_arr(&mut self, len: usize, arr: Vec<Operand>, reg: &str) -> Result<(), CodeGenError> {
-        let size = (len * 8 + 8 + 15) & !15;
-        assemble!(self.text, "sub rsp, {}", size);
-        assemble!(self.text, "mov r10, rsp");
+    fn alloc_arr(
+        &mut self,
+        len: usize,
+        elem: &IRType,
+        arr: Vec<Operand>,
+        reg: &str,
+        heap: bool,
+    ) -> Result<(), CodeGenError> {
+        let width = elem.size();
+        let size = (len * width + 8 + 15) & !15;
+        if heap {
+            self.use_runtime("malloc");
+            assemble!(self.text, "mov rdi, {}", size);
+            assemble!(self.text, "call malloc");
+            assemble!(self.text, "mov r10, rax");
+        } else {
+            assemble!(self.text, "sub rsp, {}", size);
+            assemble!(self.text, "mov r10, rsp");
+        }
+        self.regs.clear();
         assemble!(self.text, "mov rax, {}", len);
         assemble!(self.text, "mov [r10], rax");
-        for (i, op) in arr.iter().enumerate() {
-            self.load(op, "rax")?;
-            assemble!(self.text, "mov [r10 + {}], rax", 8 + i * 8);
+        if arr.len() == 1 && len > 1 {
+            self.load(&arr[0], "rax")?;
+            assemble!(self.text, "lea rdi, [r10 + 8]");
+            assemble!(self.text, "mov rcx, {}", len);
+            if width == 1 {
+                assemble!(self.text, "rep stosb");
+            } else {
+                assemble!(self.text, "rep stosq");
+            }
+        } else {
+            for (i, op) in arr.iter().enumerate() {
+                self.load(op, "rax")?;
+                if width == 1 {
+                    assemble!(self.text, "mov [r10 + {}], al", 8 + i);
+                } else {
+                    assemble!(self.text, "mov [r10 + {}], rax", 8 + i * 8);
+                }
+            }
         }
         assemble!(self.text, "mov {}, r10", reg);
         self.regs.clear();
         Ok(())
     }
 
+    fn use_runtime(&mut self, sym: &'static str) {
+        if !self.runtime_syms.contains(&sym) {
+            self.runtime_syms.push(sym);
+        }
+    }
+
     fn get_asm_op(&self, op: &Op) -> &str {
         match op {
             Op::Add => "add",
             Op::Sub => "sub",
             Op::Mul => "imul",
-            Op::LAnd | Op::Add => "and",
-            Op::LOr | Op::Or => "or",
+            Op::And => "and",
+            Op::Or => "or",
             Op::Xor => "xor",
-            Op::Not => "not",
+            Op::Shl => "sal",
+            Op::Shr => "sar",
             _ => "",
         }
     }
@@ -960,3 +1627,524 @@ impl CodeGen {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{irgen::IRGen, lexer::Lexer, parser::Parser};
+
+    /// The assembly of `func`, from its label to its `ret`.
+    fn function(src: &str, func: &str) -> Vec<String> {
+        let ast = Parser::new(Lexer::new(src)).parse().unwrap();
+        let ir = IRGen::new().compile(ast).unwrap();
+        let asm = CodeGen::new(ir).compile().unwrap();
+        asm.lines()
+            .skip_while(|line| *line != format!("{}:", func))
+            .take_while(|line| *line != "ret")
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn inst(op: Op, dst: Option<Operand>, src1: Option<Operand>) -> Instruction {
+        Instruction {
+            op,
+            dst,
+            src1,
+            src2: None,
+        }
+    }
+
+    fn int(id: usize) -> Operand {
+        Operand::Temp(id, IRType::Int)
+    }
+
+    #[test]
+    fn overlapping_temporaries_get_different_registers() {
+        let var = |name: &str| Some(Operand::Var(name.to_string()));
+        let insts = [
+            inst(Op::Load, Some(int(0)), var("a")),
+            inst(Op::Load, Some(int(1)), var("b")),
+            inst(Op::Neg, Some(int(2)), Some(int(0))),
+            inst(Op::Neg, Some(int(3)), Some(int(1))),
+            inst(Op::Store, var("c"), Some(int(3))),
+            inst(Op::Store, var("d"), Some(int(2))),
+        ];
+        let regs = assign_registers(&insts, &[]);
+        assert_eq!(regs.len(), 4);
+        let live_together = [(0, 1), (0, 2), (1, 2), (1, 3), (2, 3)];
+        for (a, b) in live_together {
+            assert_ne!(regs[&a], regs[&b], "%{} and %{} share a register", a, b);
+        }
+    }
+
+    #[test]
+    fn only_block_local_scalar_temporaries_get_registers() {
+        let float = Operand::Temp(1, IRType::Float);
+        let insts = [
+            inst(Op::Load, Some(int(0)), Some(Operand::Var("a".to_string()))),
+            inst(
+                Op::FLoad,
+                Some(float.clone()),
+                Some(Operand::Var("f".to_string())),
+            ),
+            inst(Op::Label("next".to_string()), None, None),
+            inst(Op::Store, Some(Operand::Var("b".to_string())), Some(int(0))),
+            inst(Op::FStore, Some(Operand::Var("g".to_string())), Some(float)),
+        ];
+        assert!(assign_registers(&insts, &[]).is_empty());
+    }
+
+    #[test]
+    fn temporaries_beyond_the_register_set_stay_on_the_stack() {
+        let mut insts: Vec<Instruction> = (0..7)
+            .map(|id| inst(Op::Load, Some(int(id)), Some(Operand::Var("a".to_string()))))
+            .collect();
+        for id in 0..7 {
+            insts.push(inst(
+                Op::Store,
+                Some(Operand::Var("b".to_string())),
+                Some(int(id)),
+            ));
+        }
+        assert_eq!(assign_registers(&insts, &[]).len(), TEMP_REGS.len());
+    }
+
+    #[test]
+    fn used_callee_saved_registers_are_restored() {
+        let body = function(
+            "fun f(a: int, b: int): int {
+                return (a + b) * (a - b) + 3
+            }",
+            "f",
+        );
+        let used: Vec<&str> = TEMP_REGS
+            .into_iter()
+            .filter(|reg| body.iter().any(|line| line.contains(reg)))
+            .collect();
+        assert!(!used.is_empty());
+        for reg in used {
+            let save = body
+                .iter()
+                .find_map(|line| {
+                    line.strip_prefix("mov ")?
+                        .strip_suffix(&format!(", {}", reg))
+                })
+                .filter(|slot| slot.starts_with("[rbp - "))
+                .unwrap_or_else(|| panic!("{} is not saved", reg));
+            assert!(body.contains(&format!("mov {}, {}", reg, save)));
+        }
+    }
+
+    /// The line after the first one starting with `prefix`.
+    fn after(body: &[String], prefix: &str) -> String {
+        let i = body
+            .iter()
+            .position(|line| line.starts_with(prefix))
+            .unwrap();
+        body[i + 1].clone()
+    }
+
+    #[test]
+    fn integer_comparison_branches_directly() {
+        let body = function(
+            "fun f(a: int, b: int): int {
+                if a < b { return 1 }
+                return 0
+            }",
+            "f",
+        );
+        assert!(after(&body, "cmp ").starts_with("jge "));
+        assert!(!body.iter().any(|line| line.starts_with("set")));
+    }
+
+    #[test]
+    fn comparison_against_a_literal_uses_an_immediate() {
+        let body = function(
+            "fun f(a: int): int {
+                if a == 7 { return 1 }
+                let big: bool = a > 3
+                if big { return 2 }
+                return 0
+            }",
+            "f",
+        );
+        let cmps: Vec<&String> = body
+            .iter()
+            .filter(|line| line.starts_with("cmp "))
+            .collect();
+        // The last one tests the stored boolean.
+        assert_eq!(cmps, ["cmp rax, 7", "cmp rax, 3", "cmp rax, 0"]);
+    }
+
+    #[test]
+    fn integer_comparison_used_as_a_value_is_kept() {
+        let body = function(
+            "fun f(a: int, b: int): int {
+                let c: bool = a > b
+                if c { return 1 }
+                return 0
+            }",
+            "f",
+        );
+        assert!(after(&body, "cmp ").starts_with("setg "));
+    }
+
+    #[test]
+    fn float_comparison_used_as_a_value_sets_from_unsigned_flags() {
+        let body = function(
+            "fun f(x: flt, y: flt): int {
+                let a: bool = x > y
+                let b: bool = x >= y
+                let c: bool = x < y
+                let d: bool = x <= y
+                if a && b && c && d { return 1 }
+                return 0
+            }",
+            "f",
+        );
+        let sets: Vec<String> = body
+            .iter()
+            .zip(&body[1..])
+            .filter(|(line, _)| line.starts_with("ucomisd "))
+            .map(|(_, next)| next.clone())
+            .collect();
+        assert_eq!(sets, ["seta al", "setae al", "setb al", "setbe al"]);
+    }
+
+    #[test]
+    fn float_comparison_branches_directly() {
+        let body = function(
+            "fun f(x: flt, y: flt): int {
+                if x < y { return 1 }
+                while x >= y { x = x - 1.0 }
+                return 0
+            }",
+            "f",
+        );
+        let jumps: Vec<&String> = body
+            .iter()
+            .zip(&body[1..])
+            .filter(|(line, _)| line.starts_with("ucomisd "))
+            .map(|(_, next)| next)
+            .collect();
+        assert_eq!(jumps.len(), 2);
+        assert!(jumps[0].starts_with("jae "));
+        assert!(jumps[1].starts_with("jb "));
+        assert!(!body.iter().any(|line| line.starts_with("set")));
+    }
+
+    #[test]
+    fn negation_of_a_variable_is_lowered() {
+        let body = function(
+            "fun f(a: int, b: int): int {
+                return a - - b
+            }",
+            "f",
+        );
+        assert!(body.iter().any(|line| line == "neg rax"), "{:?}", body);
+    }
+
+    #[test]
+    fn if_without_else_falls_through_to_its_end() {
+        let body = function(
+            "fun f(a: int): int {
+                let x: int = 0
+                if a > 3 { x = 1 }
+                return x
+            }",
+            "f",
+        );
+        for (i, line) in body.iter().enumerate() {
+            if let Some(target) = line.strip_prefix("jmp ") {
+                assert_ne!(body[i + 1], format!("{}:", target), "{:?}", body);
+            }
+        }
+        assert!(!body.iter().any(|line| line.starts_with("jmp .endif")));
+    }
+
+    fn count(body: &[String], line: &str) -> usize {
+        body.iter().filter(|l| *l == line).count()
+    }
+
+    #[test]
+    fn repeated_constant_is_loaded_once() {
+        let body = function(
+            "fun f(a: int): int {
+                let x: int = 5
+                let y: int = 5
+                let z: int = 5
+                return x + y + z
+            }",
+            "f",
+        );
+        assert_eq!(count(&body, "mov rax, 5"), 1);
+    }
+
+    #[test]
+    fn cached_constant_is_forgotten_when_its_register_is_written() {
+        let body = function(
+            "fun f(a: int): int {
+                let x: int = 5
+                let y: int = a + 1
+                let z: int = 5
+                return x + y + z
+            }",
+            "f",
+        );
+        assert_eq!(count(&body, "mov rax, 5"), 2);
+    }
+
+    #[test]
+    fn multiplying_by_small_constants_avoids_the_generic_imul() {
+        let product = |n: i64| {
+            let body = function(&format!("fun f(a: int): int {{ return a * {} }}", n), "f");
+            body.into_iter()
+                .find(|line| {
+                    ["sal ", "lea ", "imul "]
+                        .iter()
+                        .any(|op| line.starts_with(op))
+                })
+                .unwrap()
+        };
+        assert_eq!(product(8), "sal rax, 3");
+        assert_eq!(product(3), "lea rax, [rax + rax * 2]");
+        assert_eq!(product(5), "lea rax, [rax + rax * 4]");
+        assert_eq!(product(9), "lea rax, [rax + rax * 8]");
+        assert_eq!(product(7), "imul rax, rax, 7");
+    }
+
+    #[test]
+    fn variable_shifts_count_in_cl() {
+        let body = function(
+            "fun f(a: int, n: int): int {
+                return (a << n) + (a >> n)
+            }",
+            "f",
+        );
+        let shifts: Vec<&String> = body
+            .iter()
+            .filter(|line| line.starts_with("sal ") || line.starts_with("sar "))
+            .collect();
+        assert_eq!(shifts, ["sal rax, cl", "sar rax, cl"]);
+        assert!(after(&body, "mov rcx, ").starts_with("sal "));
+    }
+
+    #[test]
+    fn array_elements_are_scaled_by_their_width() {
+        let body = function(
+            "fun f(b: arr<_, byte>, a: arr<_>, i: int): int {
+                return b[i] + a[i]
+            }",
+            "f",
+        );
+        assert!(body.contains(&"movzx eax, byte [r10 + rcx * 1 + 8]".to_string()));
+        assert!(body.contains(&"mov rax, [r10 + rcx * 8 + 8]".to_string()));
+    }
+
+    #[test]
+    fn logical_not_yields_zero_or_one() {
+        let body = function(
+            "fun f(cond: bool): bool {
+                let b: bool = !cond
+                return b
+            }",
+            "f",
+        );
+        let test = body
+            .iter()
+            .position(|line| line == "test rax, rax")
+            .unwrap();
+        assert_eq!(body[test + 1..test + 3], ["sete al", "movzx eax, al"]);
+    }
+
+    #[test]
+    fn misplaced_operand_error_names_the_instruction() {
+        let program = IRProgram {
+            functions: vec![IRFunction {
+                name: "f".to_string(),
+                params: Vec::new(),
+                instructions: vec![inst(
+                    Op::Store,
+                    Some(Operand::Label("L".to_string())),
+                    Some(Operand::Const(IRConst::Int(1))),
+                )],
+                ret_type: IRType::Void,
+                is_pub: false,
+                is_external: false,
+            }],
+            constants: Vec::new(),
+            globals: Vec::new(),
+        };
+        let err = CodeGen::new(program).compile().unwrap_err();
+        let message = err.to_string();
+        assert!(
+            matches!(err, CodeGenError::InvalidOperand { .. }),
+            "{}",
+            message
+        );
+        assert!(
+            message.contains("Label(\"L\") is not a stack operand"),
+            "{}",
+            message
+        );
+        assert!(
+            message.contains("Store") && message.contains("of `f`"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn locals_are_zeroed_before_a_jump_can_skip_them() {
+        let body = function(
+            "fun f(): int {
+                goto done
+                let x: int = 5
+                done:
+                return x
+            }",
+            "f",
+        );
+        let zero = body
+            .iter()
+            .position(|line| line.starts_with("mov qword [rbp - ") && line.ends_with("], 0"))
+            .unwrap();
+        let jump = body
+            .iter()
+            .position(|line| line.starts_with("jmp "))
+            .unwrap();
+        assert!(zero < jump, "{:#?}", body);
+    }
+
+    #[test]
+    fn parallel_output_matches_sequential_output() {
+        let src: String = (0..12)
+            .map(|i| {
+                format!(
+                    "fun f{i}(x: int): flt {{
+                        let s: str = \"shared\"
+                        let t: str = \"only {i}\"
+                        while x > {i} {{ x -= 1 }}
+                        if x == 0 {{ return 1.5 }}
+                        return {i}.25
+                    }}\n"
+                )
+            })
+            .collect();
+        let assemble = |threads| {
+            let ast = Parser::new(Lexer::new(&src)).parse().unwrap();
+            let ir = IRGen::new().compile(ast).unwrap();
+            CodeGen::new(ir).compile_with(threads).unwrap()
+        };
+        let sequential = assemble(1);
+        for threads in [2, 5, 12, 32] {
+            assert_eq!(assemble(threads), sequential, "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn push_pop_pairs_fold_in_one_pass_over_a_large_function() {
+        let empty = IRProgram {
+            functions: Vec::new(),
+            constants: Vec::new(),
+            globals: Vec::new(),
+        };
+        // Nested pairs took a whole pass each when the folding ran to a fixpoint.
+        let depth = 100_000;
+        let src =
+            "push rax\n".repeat(depth) + &"pop rax\n".repeat(depth) + "push rcx\npop rdx\nret";
+        let start = std::time::Instant::now();
+        let out = CodeGen::new(empty).optim(src);
+        assert_eq!(out, "mov rdx, rcx\nret");
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn only_extern_calls_count_vector_registers_in_al() {
+        let body = function(
+            "extern printf(str, flt): int
+            fun g(x: int): int { return x }
+            fun f(): int {
+                printf(\"%f\", 1.5)
+                return g(1)
+            }",
+            "f",
+        );
+        let calls: Vec<usize> = (0..body.len())
+            .filter(|&i| body[i].starts_with("call "))
+            .collect();
+        assert_eq!(body[calls[0]], "call printf");
+        assert_eq!(body[calls[0] - 1], "mov al, 1");
+        assert_eq!(body[calls[1]], "call g");
+        assert!(
+            !body[calls[0] + 1..calls[1]]
+                .iter()
+                .any(|line| line.contains(" al,"))
+        );
+    }
+
+    #[test]
+    fn extern_variables_are_read_and_written_in_place() {
+        let src = "extern counter: int
+            extern scale: flt
+            fun f(): int {
+                counter += 1
+                scale = scale * 2.0
+                return counter
+            }";
+        let body = function(src, "f");
+        assert!(
+            body.contains(&"mov rax, [rel counter]".to_string()),
+            "{:#?}",
+            body
+        );
+        assert!(
+            body.contains(&"mov [rel counter], rax".to_string()),
+            "{:#?}",
+            body
+        );
+        assert!(body.iter().any(|line| line.ends_with(", [rel scale]")));
+        assert!(
+            body.contains(&"movsd [rel scale], xmm0".to_string()),
+            "{:#?}",
+            body
+        );
+        let ast = Parser::new(Lexer::new(src)).parse().unwrap();
+        let asm = CodeGen::new(IRGen::new().compile(ast).unwrap())
+            .compile()
+            .unwrap();
+        assert!(asm.lines().any(|line| line == "extern counter"));
+        assert!(asm.lines().any(|line| line == "extern scale"));
+    }
+
+    #[test]
+    fn constant_index_is_a_displacement() {
+        let body = function(
+            "fun f(a: arr<_>, b: arr<_, byte>): int {
+                a[2] = 7
+                return a[2] + b[3]
+            }",
+            "f",
+        );
+        assert!(
+            body.contains(&"mov qword [r10 + 24], rax".to_string()),
+            "{:#?}",
+            body
+        );
+        assert!(
+            body.contains(&"mov rax, [r10 + 24]".to_string()),
+            "{:#?}",
+            body
+        );
+        assert!(
+            body.contains(&"movzx eax, byte [r10 + 11]".to_string()),
+            "{:#?}",
+            body
+        );
+        assert!(
+            !body.iter().any(|line| line.contains("rcx *")),
+            "{:#?}",
+            body
+        );
+    }
+}
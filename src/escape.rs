@@ -0,0 +1,378 @@
+//! Escape analysis deciding where each array literal is allocated.
+//!
+//! An array escapes when its address can outlive the function that creates
+//! it:
+//!
+//! - returning an array makes it escape;
+//! - storing an array into an element of another array makes it escape;
+//! - passing an array to a function makes it escape only if the callee
+//!   retains that parameter, i.e. returns it, stores it into an array or
+//!   passes it on to a function that retains it. External functions are
+//!   assumed to retain every array they are given.
+//!
+//! Passing a byte array as a `str` does not make it escape: callers such as
+//! `read` and `write` only borrow the buffer for the duration of the call.
+//!
+//! Escaping arrays are allocated on the heap and never freed. Arrays that do
+//! not escape keep the placement chosen by the IR generator: the stack, or
+//! the heap when they exceed the stack budget. A `Free` emitted at scope exit
+//! is kept only if its variable is the sole owner of one non-escaping heap
+//! array and is never assigned anything else.
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::ir::{IRConst, IRFunction, IRProgram, Instruction, Op, Operand};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Slot {
+    Temp(usize),
+    Var(String),
+}
+
+fn slot(op: &Option<Operand>) -> Option<Slot> {
+    match op {
+        Some(Operand::Temp(id, _)) => Some(Slot::Temp(*id)),
+        Some(Operand::Var(name)) => Some(Slot::Var(name.clone())),
+        _ => None,
+    }
+}
+
+/// For each function, whether it retains each of its parameters.
+type Retains = HashMap<String, Vec<bool>>;
+
+pub fn place_arrays(program: &mut IRProgram) {
+    let retains = retained_params(program);
+    for func in program.functions.iter_mut() {
+        if !func.is_external {
+            place_fn_arrays(func, &program.constants, &retains);
+        }
+    }
+}
+
+/// Computes which parameters every function retains, iterating until no
+/// summary changes so that mutually recursive functions see each other.
+fn retained_params(program: &IRProgram) -> Retains {
+    let mut retains: Retains = program
+        .functions
+        .iter()
+        .map(|f| (f.name.clone(), vec![f.is_external; f.params.len()]))
+        .collect();
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for func in program.functions.iter().filter(|f| !f.is_external) {
+            // Parameter `i` is tracked as a site numbered past the last
+            // instruction, so it cannot collide with a real allocation.
+            let first = func.instructions.len();
+            let mut sites: HashMap<Slot, BTreeSet<usize>> = HashMap::new();
+            for (i, (param, _)) in func.params.iter().enumerate() {
+                if let Some(param) = slot(&Some(param.clone())) {
+                    sites.entry(param).or_default().insert(first + i);
+                }
+            }
+            propagate(&func.instructions, &mut sites);
+            let escaping = escaping(&func.instructions, &sites, &retains);
+            let retained: Vec<bool> = (0..func.params.len())
+                .map(|i| escaping.contains(&(first + i)))
+                .collect();
+            if retains[&func.name] != retained {
+                retains.insert(func.name.clone(), retained);
+                changed = true;
+            }
+        }
+    }
+    retains
+}
+
+/// Extends `sites` along moves, loads and stores until it stops growing.
+fn propagate(insts: &[Instruction], sites: &mut HashMap<Slot, BTreeSet<usize>>) {
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for inst in insts {
+            if !matches!(inst.op, Op::Move | Op::Load | Op::Store) {
+                continue;
+            }
+            let (Some(src), Some(dst)) = (slot(&inst.src1), slot(&inst.dst)) else {
+                continue;
+            };
+            let Some(from) = sites.get(&src).cloned() else {
+                continue;
+            };
+            let to = sites.entry(dst).or_default();
+            let before = to.len();
+            to.extend(from);
+            changed |= to.len() != before;
+        }
+    }
+}
+
+/// Returns the sites whose arrays outlive the function.
+fn escaping(
+    insts: &[Instruction],
+    sites: &HashMap<Slot, BTreeSet<usize>>,
+    retains: &Retains,
+) -> BTreeSet<usize> {
+    let mut escaping = BTreeSet::new();
+    let mut mark = |value: &Option<Operand>| {
+        if let Some(s) = slot(value).and_then(|s| sites.get(&s)) {
+            escaping.extend(s.iter().copied());
+        }
+    };
+    // Arguments of nested calls are interleaved with the outer call's, so
+    // each call takes its own from the top of the pending stack.
+    let mut args: Vec<&Option<Operand>> = Vec::new();
+    for inst in insts {
+        match (&inst.op, &inst.src1) {
+            (Op::Return(_), value) => mark(value),
+            (Op::ArrayAssign(_), _) => mark(&inst.src2),
+            (Op::Arg(_) | Op::FArg(_), value) => args.push(value),
+            (Op::Call, Some(Operand::Function(name))) => {
+                let params = retains.get(name).map_or(&[][..], |r| &r[..]);
+                let passed = args.split_off(args.len().saturating_sub(params.len()));
+                for (value, retained) in passed.into_iter().zip(params) {
+                    if *retained {
+                        mark(value);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    escaping
+}
+
+fn place_fn_arrays(func: &mut IRFunction, constants: &[IRConst], retains: &Retains) {
+    let insts = &mut func.instructions;
+
+    // Each allocation site is identified by the index of its instruction.
+    let mut sites: HashMap<Slot, BTreeSet<usize>> = HashMap::new();
+    for (i, inst) in insts.iter().enumerate() {
+        if let (Op::Move | Op::HeapAlloc, Some(Operand::ConstIdx(idx))) = (&inst.op, &inst.src1)
+            && let IRConst::Array(..) = constants[*idx]
+            && let Some(dst) = slot(&inst.dst)
+        {
+            sites.entry(dst).or_default().insert(i);
+        }
+    }
+    propagate(insts, &mut sites);
+    let escaping = escaping(insts, &sites, retains);
+
+    let mut owners: HashMap<usize, BTreeSet<&String>> = HashMap::new();
+    for (s, set) in &sites {
+        if let Slot::Var(name) = s {
+            for site in set {
+                owners.entry(*site).or_default().insert(name);
+            }
+        }
+    }
+
+    // The `Free` releases whatever the variable holds when its scope ends,
+    // so every value stored into it must come from its one site: a call's
+    // result, for one, is not tracked and may be an array owned elsewhere.
+    let only_from = |var: &Slot, site: usize| {
+        insts.iter().enumerate().all(|(i, inst)| {
+            slot(&inst.dst).as_ref() != Some(var)
+                || matches!(inst.op, Op::ArrayAssign(_))
+                || i == site
+                || matches!(inst.op, Op::Move | Op::Load | Op::Store)
+                    && slot(&inst.src1)
+                        .and_then(|s| sites.get(&s))
+                        .is_some_and(|from| from.len() == 1 && from.contains(&site))
+        })
+    };
+
+    let mut freeable = BTreeSet::new();
+    for (s, set) in &sites {
+        let Slot::Var(name) = s else { continue };
+        if let [site] = set.iter().copied().collect::<Vec<_>>()[..] {
+            let owned = owners.get(&site).is_some_and(|o| o.len() == 1);
+            if owned
+                && !escaping.contains(&site)
+                && insts[site].op == Op::HeapAlloc
+                && only_from(s, site)
+            {
+                freeable.insert(name.clone());
+            }
+        }
+    }
+
+    for site in &escaping {
+        insts[*site].op = Op::HeapAlloc;
+    }
+    insts.retain(|inst| match (&inst.op, &inst.src1) {
+        (Op::Free, Some(Operand::Var(name))) => freeable.contains(name),
+        _ => true,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{irgen::IRGen, lexer::Lexer, parser::Parser};
+
+    fn compile(src: &str) -> IRProgram {
+        let ast = Parser::new(Lexer::new(src)).parse().unwrap();
+        IRGen::new().compile(ast).unwrap()
+    }
+
+    fn ops(program: &IRProgram, func: &str) -> Vec<Op> {
+        let func = program.functions.iter().find(|f| f.name == func).unwrap();
+        func.instructions
+            .iter()
+            .map(|inst| inst.op.clone())
+            .collect()
+    }
+
+    fn count(program: &IRProgram, func: &str, op: Op) -> usize {
+        ops(program, func).into_iter().filter(|o| *o == op).count()
+    }
+
+    #[test]
+    fn local_array_stays_on_the_stack() {
+        let program = compile(
+            "fun f(): int {
+                let a: arr<3> = [1, 2, 3]
+                a[0] = 4
+                return a[0] + a[2]
+            }",
+        );
+        assert_eq!(count(&program, "f", Op::HeapAlloc), 0);
+    }
+
+    #[test]
+    fn returned_array_moves_to_the_heap() {
+        let program = compile(
+            "fun f(): arr<3> {
+                let a: arr<3> = [1, 2, 3]
+                return a
+            }",
+        );
+        assert_eq!(count(&program, "f", Op::HeapAlloc), 1);
+        assert_eq!(count(&program, "f", Op::Free), 0);
+    }
+
+    #[test]
+    fn array_borrowed_by_a_function_stays_on_the_stack() {
+        let program = compile(
+            "fun g(a: arr<3>): int {
+                return a[0]
+            }
+            fun f(): int {
+                let a: arr<3> = [1, 2, 3]
+                return g(a)
+            }",
+        );
+        assert_eq!(count(&program, "f", Op::HeapAlloc), 0);
+    }
+
+    #[test]
+    fn array_retained_by_a_function_moves_to_the_heap() {
+        let program = compile(
+            "fun keep(a: arr<3>): arr<3> {
+                return a
+            }
+            fun pass(a: arr<3>): arr<3> {
+                return keep(a)
+            }
+            fun f(): int {
+                let a: arr<3> = [1, 2, 3]
+                let b: arr<3> = pass(a)
+                return b[0]
+            }",
+        );
+        assert_eq!(count(&program, "f", Op::HeapAlloc), 1);
+        assert_eq!(count(&program, "f", Op::Free), 0);
+    }
+
+    #[test]
+    fn array_stored_by_a_function_moves_to_the_heap() {
+        let program = compile(
+            "fun put(into: arr<1>, a: arr<3>): int {
+                into[0] = a
+                return 0
+            }
+            fun f(into: arr<1>): int {
+                let a: arr<3> = [1, 2, 3]
+                return put(into, a)
+            }",
+        );
+        assert_eq!(count(&program, "f", Op::HeapAlloc), 1);
+    }
+
+    #[test]
+    fn array_passed_to_an_external_function_moves_to_the_heap() {
+        let program = compile(
+            "extern take(arr<3>): int
+            fun f(): int {
+                let a: arr<3> = [1, 2, 3]
+                return take(a)
+            }",
+        );
+        assert_eq!(count(&program, "f", Op::HeapAlloc), 1);
+    }
+
+    #[test]
+    fn arrays_passed_in_a_loop_are_not_leaked() {
+        let program = compile(
+            "fun sum(a: arr<300000>): int {
+                return a[0] + a[1]
+            }
+            fun g(a: arr<3>): int {
+                return a[0]
+            }
+            fun f(): int {
+                let total: int = 0
+                let i: int = 0
+                while i < 1000 {
+                    let small: arr<3> = [i, 2, 3]
+                    let big: arr<300000> = [i]
+                    total += g(small) + sum(big)
+                    i += 1
+                }
+                return total
+            }",
+        );
+        // The small array lives in the frame and the big one is released at
+        // the end of every iteration.
+        let ops = ops(&program, "f");
+        assert_eq!(count(&program, "f", Op::HeapAlloc), 1);
+        let alloc = ops.iter().position(|op| *op == Op::HeapAlloc).unwrap();
+        let free = ops.iter().position(|op| *op == Op::Free).unwrap();
+        let back = ops.iter().rposition(|op| *op == Op::Jump).unwrap();
+        assert!(alloc < free && free < back);
+    }
+
+    #[test]
+    fn scoped_heap_array_is_freed() {
+        let program = compile(
+            "fun f(): int {
+                {
+                    let big: arr<300000> = [0]
+                    big[1] = 4
+                }
+                return 0
+            }",
+        );
+        assert_eq!(count(&program, "f", Op::Free), 1);
+    }
+
+    #[test]
+    fn variable_assigned_a_call_result_is_not_freed() {
+        let program = compile(
+            "fun id(a: arr<300000>): arr<300000> {
+                return a
+            }
+            fun f(): int {
+                let s: arr<300000> = [1]
+                {
+                    let big: arr<300000> = [0]
+                    big = id(s)
+                }
+                return s[0]
+            }",
+        );
+        assert_eq!(count(&program, "f", Op::Free), 0);
+    }
+}
@@ -6,17 +6,28 @@ pub enum IRType {
     Float,
     String,
     Bool,
-    Array(Option<usize>),
+    Byte,
+    Array(Option<usize>, Box<IRType>),
     Void,
 }
 
+impl IRType {
+    /// The number of bytes one element of this type takes up in an array.
+    pub fn size(&self) -> usize {
+        match self {
+            IRType::Byte => 1,
+            _ => 8,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum IRConst {
     Int(i64),
     Float(OrderedFloat<f64>),
     Bool(bool),
     Str(String),
-    Array(usize, Vec<Operand>),
+    Array(usize, IRType, Vec<Operand>),
     Void,
 }
 
@@ -28,6 +39,7 @@ pub enum Operand {
     ConstIdx(usize),
     Label(String),
     Function(String),
+    Global(String, IRType),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -40,6 +52,7 @@ pub enum Op {
     FMul,
     Div,
     FDiv,
+    Mod,
     Eq,
     FEq,
     Ne,
@@ -57,8 +70,11 @@ pub enum Op {
     LAnd,
     LOr,
     Xor,
+    Shl,
+    Shr,
     Not,
     Range,
+    Append(IRType),
     Neg,
     FNeg,
     SizeOf,
@@ -74,14 +90,16 @@ pub enum Op {
     Return(String),
     Jump,
     JumpIfFalse,
-    ArrayAccess,
-    ArrayAssign,
+    ArrayAccess(usize),
+    ArrayAssign(usize),
+    HeapAlloc,
+    Free,
     Label(String),
     Extern(String),
     Nop,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Instruction {
     pub op: Op,
     pub dst: Option<Operand>,
@@ -103,4 +121,5 @@ pub struct IRFunction {
 pub struct IRProgram {
     pub functions: Vec<IRFunction>,
     pub constants: Vec<IRConst>,
+    pub globals: Vec<String>,
 }
@@ -1,13 +1,21 @@
-use std::{collections::HashMap, iter::zip, mem::take};
-
-use ordered_float::OrderedFloat;
+use std::{
+    collections::{HashMap, HashSet},
+    iter::zip,
+    mem::take,
+};
 
 use crate::{
-    ast::{Expr, Extern, FuncDecl, Program, Var},
+    ast::{Expr, Extern, ExternVar, FuncDecl, Program},
+    escape,
     ir::{IRConst, IRFunction, IRProgram, IRType, Instruction, Op, Operand},
+    simplify,
     token::{Literal, TokenType, VarType},
 };
 
+/// Largest array, in bytes including the length header, that is allocated
+/// on the stack. Larger arrays are allocated on the heap with `malloc`.
+pub const STACK_ARRAY_BUDGET: usize = 1 << 20;
+
 #[derive(Debug, Clone)]
 pub enum IRGenError {
     NameError { message: String },
@@ -33,15 +41,29 @@ impl std::fmt::Display for IRGenError {
 struct Symbol {
     pub name: String,
     pub ir_type: IRType,
+    pub heap: bool,
 }
 
 type Scope = HashMap<String, Symbol>;
 
+struct Loop {
+    pub label: Option<String>,
+    pub continue_label: String,
+    pub break_label: String,
+    pub depth: usize,
+}
+
 struct Context {
     pub instructions: Vec<Instruction>,
     pub tmp_cnt: usize,
     pub scope: Vec<Scope>,
     pub label_cnt: usize,
+    pub loops: Vec<Loop>,
+    pub user_labels: HashSet<String>,
+    pub gotos: Vec<String>,
+    /// Set when the next expression compiled is a statement whose value is
+    /// thrown away.
+    pub unused: bool,
 }
 
 impl Context {
@@ -51,6 +73,10 @@ impl Context {
             tmp_cnt: 0,
             scope: Vec::new(),
             label_cnt: 0,
+            loops: Vec::new(),
+            user_labels: HashSet::new(),
+            gotos: Vec::new(),
+            unused: false,
         }
     }
 
@@ -64,17 +90,124 @@ impl Context {
         format!(".{}_{:X}", name, self.label_cnt - 1)
     }
 
+    /// The assembly name of a label written in the source. It is a NASM
+    /// local label, so the same name can be used again in other functions.
+    pub fn user_label(name: &str) -> String {
+        format!(".user.{}", name)
+    }
+
+    /// Emits the label `name` written in the source, which `goto` can target.
+    pub fn define_user_label(&mut self, name: &str) -> Result<(), IRGenError> {
+        if !self.user_labels.insert(name.to_string()) {
+            return Err(IRGenError::NameError {
+                message: format!("label '{}' is defined more than once", name),
+            });
+        }
+        self.instructions.push(Instruction {
+            op: Op::Label(Context::user_label(name)),
+            dst: None,
+            src1: None,
+            src2: None,
+        });
+        Ok(())
+    }
+
     pub fn enter_scope(&mut self) {
         self.scope.push(Scope::new());
     }
 
     pub fn exit_scope(&mut self) -> Result<(), IRGenError> {
-        self.scope.pop().ok_or_else(|| IRGenError::ScopeError {
+        let scope = self.scope.pop().ok_or_else(|| IRGenError::ScopeError {
             message: "Tried to pop the root scope.".to_string(),
         })?;
+        self.free_heap_vars(&scope);
         Ok(())
     }
 
+    /// Emits a `Free` for every heap array declared in `scope`. Frees of
+    /// arrays that escape are dropped again by `escape::place_arrays`.
+    fn free_heap_vars(&mut self, scope: &Scope) {
+        let mut names: Vec<&String> = scope.values().filter(|s| s.heap).map(|s| &s.name).collect();
+        names.sort();
+        for name in names {
+            self.instructions.push(Instruction {
+                op: Op::Free,
+                dst: None,
+                src1: Some(Operand::Var(name.clone())),
+                src2: None,
+            });
+        }
+    }
+
+    /// Frees the heap arrays of the scopes from `depth` inwards, ahead of a
+    /// jump that leaves them.
+    pub fn free_scopes_from(&mut self, depth: usize) {
+        let scopes = take(&mut self.scope);
+        for scope in scopes[depth..].iter().rev() {
+            self.free_heap_vars(scope);
+        }
+        self.scope = scopes;
+    }
+
+    pub fn enter_loop(&mut self, label: Option<String>, continue_label: &str, break_label: &str) {
+        self.loops.push(Loop {
+            label,
+            continue_label: continue_label.to_string(),
+            break_label: break_label.to_string(),
+            depth: self.scope.len(),
+        });
+    }
+
+    /// Frees the scopes opened inside the innermost loop and jumps to one of
+    /// its labels.
+    pub fn jump_out_of_loop(
+        &mut self,
+        is_break: bool,
+        target: Option<String>,
+    ) -> Result<(), IRGenError> {
+        let keyword = if is_break { "break" } else { "continue" };
+        let found = match &target {
+            Some(name) => self
+                .loops
+                .iter()
+                .rev()
+                .find(|l| l.label.as_ref() == Some(name)),
+            None => self.loops.last(),
+        };
+        let (label, depth) = match found {
+            Some(l) if is_break => (l.break_label.clone(), l.depth),
+            Some(l) => (l.continue_label.clone(), l.depth),
+            None => {
+                return Err(match target {
+                    Some(name) => IRGenError::NameError {
+                        message: format!(
+                            "`{} {}` outside of a loop labeled '{}'",
+                            keyword, name, name
+                        ),
+                    },
+                    None => IRGenError::SyntaxError {
+                        message: format!("`{}` outside of a loop", keyword),
+                    },
+                });
+            }
+        };
+        self.free_scopes_from(depth);
+        self.instructions.push(Instruction {
+            op: Op::Jump,
+            dst: None,
+            src1: Some(Operand::Label(label)),
+            src2: None,
+        });
+        Ok(())
+    }
+
+    fn find_symbol_mut(&mut self, name: &str) -> Option<&mut Symbol> {
+        self.scope
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.get_mut(name))
+    }
+
     fn get_var_type(&self, name: &str) -> Result<IRType, IRGenError> {
         for scope in self.scope.iter().rev() {
             if let Some(symbol) = scope.get(name) {
@@ -86,13 +219,16 @@ impl Context {
         })
     }
 
-    pub fn from_var_type(&self, var_type: &VarType) -> IRType {
+    pub fn ir_type(&self, var_type: &VarType) -> IRType {
         match var_type {
             VarType::Int => IRType::Int,
             VarType::Float => IRType::Float,
             VarType::Bool => IRType::Bool,
             VarType::Str => IRType::String,
-            VarType::Array(len) => IRType::Array(len.to_owned()),
+            VarType::Byte => IRType::Byte,
+            VarType::Array(len, elem) => {
+                IRType::Array(len.to_owned(), Box::new(self.ir_type(elem)))
+            }
             VarType::Void => IRType::Void,
         }
     }
@@ -104,14 +240,18 @@ impl Context {
                 IRConst::Float(_) => Ok(IRType::Float),
                 IRConst::Bool(_) => Ok(IRType::Bool),
                 IRConst::Str(_) => Ok(IRType::String),
-                IRConst::Array(len, _) => Ok(IRType::Array(Some(len.to_owned()))),
+                IRConst::Array(len, elem, _) => Ok(IRType::Array(
+                    Some(len.to_owned()),
+                    Box::new(elem.to_owned()),
+                )),
                 IRConst::Void => Ok(IRType::Void),
             },
-            Operand::Var(name) => self.get_var_type(&name),
+            Operand::Var(name) => self.get_var_type(name),
             Operand::Temp(_, t) => Ok(t.to_owned()),
             Operand::Label(_) => Ok(IRType::Void),
             Operand::Function(_) => Ok(IRType::Void),
             Operand::ConstIdx(_) => Ok(IRType::Void),
+            Operand::Global(_, t) => Ok(t.to_owned()),
         }
     }
 
@@ -127,7 +267,14 @@ impl Context {
                 message: format!("variable '{}' already declared in this scope.", name),
             });
         }
-        current_scope.insert(name.clone(), Symbol { name, ir_type });
+        current_scope.insert(
+            name.clone(),
+            Symbol {
+                name,
+                ir_type,
+                heap: false,
+            },
+        );
         Ok(())
     }
 }
@@ -135,7 +282,16 @@ impl Context {
 pub struct IRGen {
     functions: Vec<IRFunction>,
     constants: Vec<IRConst>,
-    constant_pool: HashMap<IRConst, usize>,
+    /// Pool indices keyed by constant and, for a float, its bits:
+    /// `OrderedFloat` equates -0.0 with 0.0, whose signs differ.
+    constant_pool: HashMap<(IRConst, u64), usize>,
+    globals: Vec<(String, IRType)>,
+}
+
+impl Default for IRGen {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl IRGen {
@@ -144,6 +300,7 @@ impl IRGen {
             functions: Vec::new(),
             constants: Vec::new(),
             constant_pool: HashMap::new(),
+            globals: Vec::new(),
         }
     }
 
@@ -156,6 +313,14 @@ impl IRGen {
                 Expr::Extern(ext) => {
                     self.extern_decl(ext.clone())?;
                 }
+                Expr::ExternVar(ext) => {
+                    self.extern_var(ext.clone())?;
+                }
+                Expr::Return(_) => {
+                    return Err(IRGenError::SyntaxError {
+                        message: "`return` outside of a function".to_string(),
+                    });
+                }
                 _ => {}
             }
         }
@@ -172,24 +337,94 @@ impl IRGen {
             }
         }
 
-        Ok(IRProgram {
+        let mut program = IRProgram {
             functions: take(&mut self.functions),
             constants: take(&mut self.constants),
-        })
+            globals: self.globals.iter().map(|(name, _)| name.clone()).collect(),
+        };
+        simplify::fold_identities(&mut program);
+        simplify::reduce_strength(&mut program);
+        simplify::inline_constants(&mut program);
+        escape::place_arrays(&mut program);
+        Ok(program)
+    }
+
+    pub fn check_entry(&self, program: &IRProgram) -> Result<(), IRGenError> {
+        let main = program
+            .functions
+            .iter()
+            .find(|f| f.name == "main" && !f.is_external)
+            .ok_or_else(|| IRGenError::NameError {
+                message: "no `main` function defined".to_string(),
+            })?;
+        if !matches!(main.ret_type, IRType::Int | IRType::Void) {
+            return Err(IRGenError::TypeError {
+                message: format!("`main` must return int or void, found {:?}", main.ret_type),
+            });
+        }
+        let param_types: Vec<&IRType> = main.params.iter().map(|(_, t)| t).collect();
+        if !matches!(
+            param_types.as_slice(),
+            [] | [IRType::Int, IRType::Array(None, _)]
+        ) {
+            return Err(IRGenError::TypeError {
+                message: format!(
+                    "`main` must take no parameters or (argc: int, argv: arr<_>), found {:?}",
+                    param_types
+                ),
+            });
+        }
+        if !main.is_pub {
+            return Err(IRGenError::NameError {
+                message: "`main` must be declared `pub` to be visible to the linker".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    fn check_array_len(&self, len: usize, elem: &IRType) -> Result<(), IRGenError> {
+        match len.checked_mul(elem.size()).and_then(|n| n.checked_add(8)) {
+            Some(_) => Ok(()),
+            None => Err(IRGenError::TypeError {
+                message: format!("array of {} elements is too large", len),
+            }),
+        }
+    }
+
+    fn array_alloc_op(&self, len: usize, elem: &IRType) -> Op {
+        if len * elem.size() + 8 > STACK_ARRAY_BUDGET {
+            Op::HeapAlloc
+        } else {
+            Op::Move
+        }
     }
 
+    /// The pool index of `constant`, adding it if it is new. Array literals
+    /// never share an entry: each holds the temporaries of its own elements,
+    /// as each evaluation makes a new array.
     fn get_const_index(&mut self, constant: IRConst) -> usize {
-        if let Some(&index) = self.constant_pool.get(&constant) {
+        let bits = match &constant {
+            IRConst::Float(f) => f.to_bits(),
+            _ => 0,
+        };
+        let key = (constant, bits);
+        if let Some(&index) = self.constant_pool.get(&key) {
             return index;
         }
 
         let index = self.constants.len();
-        self.constants.push(constant.clone());
-        self.constant_pool.insert(constant, index);
+        self.constants.push(key.0.clone());
+        self.constant_pool.insert(key, index);
         index
     }
 
+    fn compile_stmt(&mut self, expr: Expr, ctx: &mut Context) -> Result<Operand, IRGenError> {
+        ctx.unused = true;
+        self.compile_expr(expr, ctx)
+    }
+
     fn compile_expr(&mut self, expr: Expr, ctx: &mut Context) -> Result<Operand, IRGenError> {
+        let unused = take(&mut ctx.unused);
         match expr {
             Expr::Val(val) => {
                 let (ir_const, ir_type) = match val.value {
@@ -199,16 +434,13 @@ impl IRGen {
                     Literal::Str(s) => (IRConst::Str(s), IRType::String),
                     Literal::Void => return Ok(ctx.new_tmp(IRType::Void)),
                     Literal::Array(len, arr) => {
+                        self.check_array_len(len, &IRType::Int)?;
                         let is_fill_syntax = len > 1 && arr.len() == 1;
                         if is_fill_syntax {
                             let fill_element = self.compile_expr(arr[0].clone(), ctx)?;
-                            let mut elements = Vec::new();
-                            for _ in 0..len {
-                                elements.push(fill_element.clone());
-                            }
                             (
-                                IRConst::Array(elements.len(), elements.clone()),
-                                IRType::Array(Some(elements.len())),
+                                IRConst::Array(len, IRType::Int, vec![fill_element]),
+                                IRType::Array(Some(len), Box::new(IRType::Int)),
                             )
                         } else {
                             let mut elements = Vec::new();
@@ -227,8 +459,8 @@ impl IRGen {
                             }
 
                             (
-                                IRConst::Array(elements.len(), elements.clone()),
-                                IRType::Array(Some(elements.len())),
+                                IRConst::Array(elements.len(), IRType::Int, elements.clone()),
+                                IRType::Array(Some(elements.len()), Box::new(IRType::Int)),
                             )
                         }
                     }
@@ -243,6 +475,12 @@ impl IRGen {
                         src1: Some(Operand::ConstIdx(const_idx)),
                         src2: None,
                     }),
+                    IRType::Array(Some(len), elem) => ctx.instructions.push(Instruction {
+                        op: self.array_alloc_op(len, &elem),
+                        dst: Some(res_tmp.clone()),
+                        src1: Some(Operand::ConstIdx(const_idx)),
+                        src2: None,
+                    }),
                     _ => ctx.instructions.push(Instruction {
                         op: Op::Move,
                         dst: Some(res_tmp.clone()),
@@ -258,51 +496,79 @@ impl IRGen {
                 let value_type = ctx.get_operand_type(&value)?;
 
                 let var_ir_type = match &decl.typ {
-                    VarType::Array(Some(declared_len)) => {
-                        if let IRType::Array(Some(actual_len)) = &value_type {
-                            if *declared_len > *actual_len && *actual_len == 1 {
-                                if let Operand::Temp(_, _) = value {
-                                    if let Some(last_inst) = ctx.instructions.last() {
-                                        if let Some(Operand::ConstIdx(idx)) = &last_inst.src1 {
-                                            if let IRConst::Array(_, elems) = &self.constants[*idx]
-                                            {
-                                                let fill_elem = elems[0].clone();
-                                                let new_elems = vec![fill_elem; *declared_len];
-
-                                                let new_const =
-                                                    IRConst::Array(*declared_len, new_elems);
-                                                let new_idx = self.get_const_index(new_const);
-
-                                                if let Some(last_inst) = ctx.instructions.last_mut()
-                                                {
-                                                    last_inst.src1 =
-                                                        Some(Operand::ConstIdx(new_idx));
-                                                }
-
-                                                value = Operand::Temp(
-                                                    ctx.tmp_cnt - 1,
-                                                    IRType::Array(Some(*declared_len)),
-                                                );
-                                            }
-                                        }
-                                    }
-                                }
-                            } else if *declared_len != *actual_len {
+                    VarType::Array(declared_len, elem) => {
+                        let elem = ctx.ir_type(elem);
+                        let (actual_len, actual_elem) = match &value_type {
+                            IRType::Array(Some(len), actual_elem) => (*len, actual_elem),
+                            IRType::Array(None, actual_elem) if declared_len.is_none() => {
+                                (0, actual_elem)
+                            }
+                            _ => {
                                 return Err(IRGenError::TypeError {
-                                    message: "array length mismatch".to_string(),
+                                    message: "expected array".to_string(),
                                 });
                             }
-                            IRType::Array(Some(*declared_len))
-                        } else {
+                        };
+                        let len = declared_len.unwrap_or(actual_len);
+                        let is_fill = len > actual_len && actual_len == 1;
+                        if len != actual_len && !is_fill {
                             return Err(IRGenError::TypeError {
-                                message: "expected array".to_string(),
+                                message: "array length mismatch".to_string(),
                             });
                         }
+                        // An array literal takes the declared length and element
+                        // type by rewriting the constant it was allocated from.
+                        if is_fill || **actual_elem != elem {
+                            self.check_array_len(len, &elem)?;
+                            let elems = match (&value, ctx.instructions.last()) {
+                                (
+                                    Operand::Temp(_, _),
+                                    Some(Instruction {
+                                        src1: Some(Operand::ConstIdx(idx)),
+                                        ..
+                                    }),
+                                ) => match &self.constants[*idx] {
+                                    IRConst::Array(_, _, elems) => Some(elems.clone()),
+                                    _ => None,
+                                },
+                                _ => None,
+                            };
+                            let Some(elems) = elems else {
+                                return Err(IRGenError::TypeError {
+                                    message: format!(
+                                        "expected array of {:?}, found {:?}",
+                                        elem, actual_elem
+                                    ),
+                                });
+                            };
+                            let new_idx =
+                                self.get_const_index(IRConst::Array(len, elem.clone(), elems));
+                            let alloc_op = self.array_alloc_op(len, &elem);
+                            if let Some(last_inst) = ctx.instructions.last_mut() {
+                                last_inst.op = alloc_op;
+                                last_inst.src1 = Some(Operand::ConstIdx(new_idx));
+                            }
+                            value = Operand::Temp(
+                                ctx.tmp_cnt - 1,
+                                IRType::Array(Some(len), Box::new(elem.clone())),
+                            );
+                        }
+                        IRType::Array(*declared_len, Box::new(elem))
                     }
-                    _ => ctx.from_var_type(&decl.typ),
+                    _ => ctx.ir_type(&decl.typ),
                 };
 
+                let is_heap = matches!(
+                    ctx.instructions.last(),
+                    Some(Instruction {
+                        op: Op::HeapAlloc,
+                        ..
+                    })
+                );
                 ctx.declare_var(decl.name.clone(), var_ir_type.clone())?;
+                if is_heap && let Some(symbol) = ctx.find_symbol_mut(&decl.name) {
+                    symbol.heap = true;
+                }
                 match var_ir_type {
                     IRType::Float => ctx.instructions.push(Instruction {
                         op: Op::FStore,
@@ -322,7 +588,7 @@ impl IRGen {
             Expr::VarMod(modi) => {
                 let value = self.compile_expr(*modi.value, ctx)?;
                 let typ = ctx.get_operand_type(&value)?;
-                let var_typ = ctx.get_var_type(&modi.name)?;
+                let (var, var_typ) = self.resolve_var(modi.name, ctx)?;
                 if typ != var_typ {
                     return Err(IRGenError::TypeError {
                         message: format!("unexpected type: {:?}", typ),
@@ -331,13 +597,13 @@ impl IRGen {
                 match typ {
                     IRType::Float => ctx.instructions.push(Instruction {
                         op: Op::FStore,
-                        dst: Some(Operand::Var(modi.name)),
+                        dst: Some(var),
                         src1: Some(value),
                         src2: None,
                     }),
                     _ => ctx.instructions.push(Instruction {
                         op: Op::Store,
-                        dst: Some(Operand::Var(modi.name)),
+                        dst: Some(var),
                         src1: Some(value),
                         src2: None,
                     }),
@@ -345,19 +611,19 @@ impl IRGen {
                 Ok(ctx.new_tmp(IRType::Void))
             }
             Expr::Var(var) => {
-                let var_type = ctx.get_var_type(&var.name)?;
+                let (var, var_type) = self.resolve_var(var.name, ctx)?;
                 let res_tmp = ctx.new_tmp(var_type.clone());
                 match var_type {
                     IRType::Float => ctx.instructions.push(Instruction {
                         op: Op::FLoad,
                         dst: Some(res_tmp.clone()),
-                        src1: Some(Operand::Var(var.name)),
+                        src1: Some(var),
                         src2: None,
                     }),
                     _ => ctx.instructions.push(Instruction {
                         op: Op::Load,
                         dst: Some(res_tmp.clone()),
-                        src1: Some(Operand::Var(var.name)),
+                        src1: Some(var),
                         src2: None,
                     }),
                 }
@@ -367,9 +633,29 @@ impl IRGen {
                 let left = self.compile_expr(*bin.left, ctx)?;
                 let right = self.compile_expr(*bin.right, ctx)?;
                 let typ = ctx.get_operand_type(&left)?;
+                let is_comparison = matches!(
+                    bin.operator,
+                    TokenType::COMPEQ
+                        | TokenType::COMPNE
+                        | TokenType::COMPGT
+                        | TokenType::COMPGE
+                        | TokenType::COMPLT
+                        | TokenType::COMPLE
+                        | TokenType::COMPAND
+                        | TokenType::COMPOR
+                );
+                if matches!(typ, IRType::Array(..))
+                    && !matches!(bin.operator, TokenType::COMPEQ | TokenType::COMPNE)
+                {
+                    return Err(IRGenError::TypeError {
+                        message: format!("unsupported array operation: {:?}", bin.operator),
+                    });
+                }
                 let res_tmp: Operand;
                 if bin.operator == TokenType::RANGE {
-                    res_tmp = ctx.new_tmp(IRType::Array(None));
+                    res_tmp = ctx.new_tmp(IRType::Array(None, Box::new(IRType::Int)));
+                } else if is_comparison {
+                    res_tmp = ctx.new_tmp(IRType::Int);
                 } else {
                     res_tmp = ctx.new_tmp(typ.clone());
                 }
@@ -380,6 +666,9 @@ impl IRGen {
                         | TokenType::SUB
                         | TokenType::MUL
                         | TokenType::DIV
+                        | TokenType::MOD
+                        | TokenType::SHL
+                        | TokenType::SHR
                         | TokenType::COMPEQ
                         | TokenType::COMPNE
                         | TokenType::COMPGT
@@ -413,14 +702,17 @@ impl IRGen {
                                 TokenType::SUB => Op::Sub,
                                 TokenType::MUL => Op::Mul,
                                 TokenType::DIV => Op::Div,
+                                TokenType::MOD => Op::Mod,
+                                TokenType::SHL => Op::Shl,
+                                TokenType::SHR => Op::Shr,
                                 TokenType::COMPEQ => Op::Eq,
                                 TokenType::COMPNE => Op::Ne,
                                 TokenType::COMPGT => Op::Gt,
                                 TokenType::COMPGE => Op::Ge,
                                 TokenType::COMPLT => Op::Lt,
                                 TokenType::COMPLE => Op::Le,
-                                TokenType::COMPAND => Op::And,
-                                TokenType::COMPOR => Op::Or,
+                                TokenType::COMPAND => Op::LAnd,
+                                TokenType::COMPOR => Op::LOr,
                                 _ => {
                                     return Err(IRGenError::TypeError {
                                         message: format!(
@@ -431,8 +723,8 @@ impl IRGen {
                                 }
                             },
                         },
-                        TokenType::LOGAND => Op::LAnd,
-                        TokenType::LOGOR => Op::LOr,
+                        TokenType::LOGAND => Op::And,
+                        TokenType::LOGOR => Op::Or,
                         TokenType::LOGXOR => Op::Xor,
                         TokenType::RANGE => Op::Range,
                         _ => {
@@ -450,8 +742,22 @@ impl IRGen {
             Expr::UnaryOp(unary) => {
                 let argument = self.compile_expr(*unary.argument, ctx)?;
                 let typ = ctx.get_operand_type(&argument)?;
-                let res_tmp = ctx.new_tmp(typ.clone());
+                let res_tmp = if unary.operator == TokenType::SIZEOF {
+                    ctx.new_tmp(IRType::Int)
+                } else {
+                    ctx.new_tmp(typ.clone())
+                };
                 match typ {
+                    // The length of a sized array is known without reading it.
+                    IRType::Array(Some(len), _) if unary.operator == TokenType::SIZEOF => {
+                        let idx = self.get_const_index(IRConst::Int(len as i64));
+                        ctx.instructions.push(Instruction {
+                            op: Op::Move,
+                            dst: Some(res_tmp.clone()),
+                            src1: Some(Operand::ConstIdx(idx)),
+                            src2: None,
+                        });
+                    }
                     IRType::Float => match unary.operator {
                         TokenType::NEG => ctx.instructions.push(Instruction {
                             op: Op::FNeg,
@@ -492,10 +798,11 @@ impl IRGen {
                 let body_len = stmt.body.len();
 
                 for i in 0..body_len.saturating_sub(1) {
-                    self.compile_expr(stmt.body[i].clone(), ctx)?;
+                    self.compile_stmt(stmt.body[i].clone(), ctx)?;
                 }
 
                 let result_operand = if let Some(last_expr) = stmt.body.last() {
+                    ctx.unused = unused;
                     self.compile_expr(last_expr.clone(), ctx)?
                 } else {
                     ctx.new_tmp(IRType::Void)
@@ -506,6 +813,7 @@ impl IRGen {
             Expr::Return(ret_expr) => {
                 if let Some(val) = ret_expr.value {
                     let res_op = self.compile_expr(*val, ctx)?;
+                    ctx.free_scopes_from(0);
                     match ctx.get_operand_type(&res_op)? {
                         IRType::Float => ctx.instructions.push(Instruction {
                             op: Op::Return(String::from("xmm0")),
@@ -536,19 +844,29 @@ impl IRGen {
                     src2: Some(Operand::Label(label_else.clone())),
                 });
 
+                // The result type is known only once both branches are
+                // compiled, so the moves into the result are patched below.
                 let res_tmp = ctx.new_tmp(IRType::Void);
+                let mut moves = Vec::new();
 
                 if !matches!(*i.then_branch, Expr::Stmt(_)) {
                     ctx.enter_scope();
                 }
+                ctx.unused = unused;
                 let then_op = self.compile_expr(*i.then_branch.clone(), ctx)?;
+                let then_type = ctx.get_operand_type(&then_op)?;
 
-                ctx.instructions.push(Instruction {
-                    op: Op::Move,
-                    dst: Some(res_tmp.clone()),
-                    src1: Some(then_op),
-                    src2: None,
-                });
+                // A branch without a value, such as a call to a void
+                // function, has nothing to store into the result.
+                if then_type != IRType::Void {
+                    moves.push(ctx.instructions.len());
+                    ctx.instructions.push(Instruction {
+                        op: Op::Move,
+                        dst: Some(res_tmp.clone()),
+                        src1: Some(then_op),
+                        src2: None,
+                    });
+                }
                 if !matches!(*i.then_branch, Expr::Stmt(_)) {
                     ctx.exit_scope()?;
                 }
@@ -567,18 +885,24 @@ impl IRGen {
                     src2: None,
                 });
 
+                let mut else_type = IRType::Void;
                 if let Some(else_expr) = i.else_branch {
                     if !matches!(*else_expr, Expr::Stmt(_)) {
                         ctx.enter_scope();
                     }
+                    ctx.unused = unused;
                     let else_op = self.compile_expr(*else_expr.to_owned(), ctx)?;
+                    else_type = ctx.get_operand_type(&else_op)?;
 
-                    ctx.instructions.push(Instruction {
-                        op: Op::Move,
-                        dst: Some(res_tmp.clone()),
-                        src1: Some(else_op),
-                        src2: None,
-                    });
+                    if else_type != IRType::Void {
+                        moves.push(ctx.instructions.len());
+                        ctx.instructions.push(Instruction {
+                            op: Op::Move,
+                            dst: Some(res_tmp.clone()),
+                            src1: Some(else_op),
+                            src2: None,
+                        });
+                    }
                     if !matches!(*else_expr.clone(), Expr::Stmt(_)) {
                         ctx.exit_scope()?;
                     }
@@ -591,9 +915,43 @@ impl IRGen {
                     src2: None,
                 });
 
+                let typ = Self::unify_branches(then_type.clone(), else_type.clone());
+                if typ == IRType::Void
+                    && !unused
+                    && then_type != IRType::Void
+                    && else_type != IRType::Void
+                {
+                    return Err(IRGenError::TypeError {
+                        message: format!(
+                            "if branches have different types: {:?} and {:?}",
+                            then_type, else_type
+                        ),
+                    });
+                }
+                let res_tmp = match res_tmp {
+                    Operand::Temp(id, _) => Operand::Temp(id, typ.clone()),
+                    other => other,
+                };
+                if typ == IRType::Void {
+                    for idx in moves.into_iter().rev() {
+                        ctx.instructions.remove(idx);
+                    }
+                } else {
+                    for idx in moves {
+                        let inst = &mut ctx.instructions[idx];
+                        if typ == IRType::Float {
+                            inst.op = Op::FMove;
+                        }
+                        inst.dst = Some(res_tmp.clone());
+                    }
+                }
+
                 Ok(res_tmp)
             }
             Expr::While(w) => {
+                if let Some(name) = &w.label {
+                    ctx.define_user_label(name)?;
+                }
                 let label_start = ctx.new_label("while_start");
                 let label_end = ctx.new_label("while_end");
 
@@ -613,13 +971,15 @@ impl IRGen {
                     src2: Some(Operand::Label(label_end.clone())),
                 });
 
+                ctx.enter_loop(w.label, &label_start, &label_end);
                 if !matches!(*w.body, Expr::Stmt(_)) {
                     ctx.enter_scope();
                 }
-                self.compile_expr(*w.body.clone(), ctx)?;
+                self.compile_stmt(*w.body.clone(), ctx)?;
                 if !matches!(*w.body, Expr::Stmt(_)) {
                     ctx.exit_scope()?;
                 }
+                ctx.loops.pop();
 
                 ctx.instructions.push(Instruction {
                     op: Op::Jump,
@@ -638,15 +998,22 @@ impl IRGen {
                 Ok(ctx.new_tmp(IRType::Void))
             }
             Expr::For(f) => {
+                if let Some(name) = &f.label {
+                    ctx.define_user_label(name)?;
+                }
                 let array_operand = self.compile_expr(*f.iter, ctx)?;
                 let array_type = ctx.get_operand_type(&array_operand)?;
 
+                let width = match &array_type {
+                    IRType::Array(_, elem) => elem.size(),
+                    _ => 8,
+                };
                 let array_len_operand = match array_type {
-                    IRType::Array(Some(l)) => {
+                    IRType::Array(Some(l), _) => {
                         let idx = self.get_const_index(IRConst::Int(l as i64));
                         Operand::ConstIdx(idx)
                     }
-                    IRType::Array(None) => {
+                    IRType::Array(None, _) => {
                         let len_tmp = ctx.new_tmp(IRType::Int);
                         ctx.instructions.push(Instruction {
                             op: Op::SizeOf,
@@ -680,6 +1047,7 @@ impl IRGen {
                 });
 
                 let label_cond = ctx.new_label("for_cond");
+                let label_next = ctx.new_label("for_next");
                 let label_end = ctx.new_label("for_end");
                 ctx.instructions.push(Instruction {
                     op: Op::Label(label_cond.clone()),
@@ -715,7 +1083,7 @@ impl IRGen {
                 let element_tmp = ctx.new_tmp(IRType::Int);
 
                 ctx.instructions.push(Instruction {
-                    op: Op::ArrayAccess,
+                    op: Op::ArrayAccess(width),
                     dst: Some(element_tmp.clone()),
                     src1: Some(array_operand),
                     src2: Some(curr_idx.clone()),
@@ -728,7 +1096,26 @@ impl IRGen {
                     src2: None,
                 });
 
-                self.compile_expr(*f.body, ctx)?;
+                if let Some(index) = f.index {
+                    ctx.declare_var(index.clone(), IRType::Int)?;
+                    ctx.instructions.push(Instruction {
+                        op: Op::Store,
+                        dst: Some(Operand::Var(index)),
+                        src1: Some(curr_idx.clone()),
+                        src2: None,
+                    });
+                }
+
+                ctx.enter_loop(f.label, &label_next, &label_end);
+                self.compile_stmt(*f.body, ctx)?;
+                ctx.loops.pop();
+
+                ctx.instructions.push(Instruction {
+                    op: Op::Label(label_next),
+                    dst: None,
+                    src1: None,
+                    src2: None,
+                });
 
                 let one_idx = self.get_const_index(IRConst::Int(1));
                 let next_idx = ctx.new_tmp(IRType::Int);
@@ -761,11 +1148,9 @@ impl IRGen {
                 ctx.exit_scope()?;
                 Ok(ctx.new_tmp(IRType::Void))
             }
-            Expr::FuncDecl(_) => {
-                return Err(IRGenError::SyntaxError {
-                    message: "cannot declare a function in a function".to_string(),
-                });
-            }
+            Expr::FuncDecl(_) => Err(IRGenError::SyntaxError {
+                message: "cannot declare a function in a function".to_string(),
+            }),
             Expr::FuncCall(call) => {
                 let func = self.find_func(&call.name)?;
                 if call.args.len() != func.params.len() {
@@ -777,35 +1162,63 @@ impl IRGen {
                         ),
                     });
                 }
-                let res_tmp = ctx.new_tmp(ctx.from_var_type(&call.ret_type));
-                let mut n = 0;
+                let res_tmp = ctx.new_tmp(func.ret_type.clone());
+                // Integer and float arguments are numbered separately, as each
+                // kind has its own argument registers. All arguments are
+                // evaluated before any is passed, so that evaluating one cannot
+                // overwrite the register holding another.
+                let (mut ints, mut flts) = (0, 0);
+                let mut args = Vec::new();
                 for (arg, param) in zip(call.args.iter(), func.params.iter()) {
                     let operand = self.compile_expr(arg.clone(), ctx)?;
                     let operand_type = ctx.get_operand_type(&operand)?;
-                    if operand_type != param.1 {
-                        return Err(IRGenError::TypeError {
-                            message: format!(
-                                "unexpected type {:?}, expected {:?}",
-                                operand_type, param.1
-                            ),
-                        });
-                    }
-                    match param.1 {
-                        IRType::Float => ctx.instructions.push(Instruction {
-                            op: Op::FArg(n),
-                            dst: None,
-                            src1: Some(operand),
-                            src2: None,
-                        }),
-                        _ => ctx.instructions.push(Instruction {
-                            op: Op::Arg(n),
-                            dst: None,
-                            src1: Some(operand),
-                            src2: None,
-                        }),
-                    }
-                    n += 1;
+                    let operand = match (&operand_type, &param.1) {
+                        // A byte array passed as a `str` lends out its elements.
+                        (IRType::Array(_, elem), IRType::String) if **elem == IRType::Byte => {
+                            let data = ctx.new_tmp(IRType::String);
+                            let header = self.get_const_index(IRConst::Int(8));
+                            ctx.instructions.push(Instruction {
+                                op: Op::Add,
+                                dst: Some(data.clone()),
+                                src1: Some(operand),
+                                src2: Some(Operand::ConstIdx(header)),
+                            });
+                            data
+                        }
+                        // An `arr<_>` parameter takes arrays of any length.
+                        (IRType::Array(_, elem), IRType::Array(None, param_elem))
+                            if elem == param_elem =>
+                        {
+                            operand
+                        }
+                        _ if operand_type == param.1 => operand,
+                        _ => {
+                            return Err(IRGenError::TypeError {
+                                message: format!(
+                                    "unexpected type {:?}, expected {:?}",
+                                    operand_type, param.1
+                                ),
+                            });
+                        }
+                    };
+                    let op = match param.1 {
+                        IRType::Float => {
+                            flts += 1;
+                            Op::FArg(flts - 1)
+                        }
+                        _ => {
+                            ints += 1;
+                            Op::Arg(ints - 1)
+                        }
+                    };
+                    args.push(Instruction {
+                        op,
+                        dst: None,
+                        src1: Some(operand),
+                        src2: None,
+                    });
                 }
+                ctx.instructions.extend(args);
                 ctx.instructions.push(Instruction {
                     op: Op::Call,
                     dst: Some(res_tmp.clone()),
@@ -817,11 +1230,11 @@ impl IRGen {
             Expr::ArrayAccess(aa) => {
                 let arr = Operand::Var(aa.array.clone());
                 let arr_type = ctx.get_operand_type(&arr)?;
-                if let IRType::Array(_) = arr_type {
+                if let IRType::Array(_, elem) = arr_type {
                     let offset = self.compile_expr(*aa.offset, ctx)?;
                     let res_tmp = ctx.new_tmp(IRType::Int);
                     ctx.instructions.push(Instruction {
-                        op: Op::ArrayAccess,
+                        op: Op::ArrayAccess(elem.size()),
                         dst: Some(res_tmp.clone()),
                         src1: Some(arr),
                         src2: Some(offset),
@@ -836,12 +1249,12 @@ impl IRGen {
             Expr::ArrayAssign(aa) => {
                 let arr = Operand::Var(aa.array.clone());
                 let arr_type = ctx.get_operand_type(&arr)?;
-                if let IRType::Array(_) = arr_type {
+                if let IRType::Array(_, elem) = arr_type {
                     let offset = self.compile_expr(*aa.offset, ctx)?;
                     let val = self.compile_expr(*aa.value, ctx)?;
                     let res_tmp = ctx.new_tmp(IRType::Void);
                     ctx.instructions.push(Instruction {
-                        op: Op::ArrayAssign,
+                        op: Op::ArrayAssign(elem.size()),
                         dst: Some(arr),
                         src1: Some(offset),
                         src2: Some(val),
@@ -853,27 +1266,58 @@ impl IRGen {
                     })
                 }
             }
-            Expr::Extern(_) => {
-                return Err(IRGenError::SyntaxError {
-                    message: "cannot extern a function in a function".to_string(),
-                });
-            }
+            Expr::Extern(_) => Err(IRGenError::SyntaxError {
+                message: "cannot extern a function in a function".to_string(),
+            }),
+            Expr::ExternVar(_) => Err(IRGenError::SyntaxError {
+                message: "cannot extern a variable in a function".to_string(),
+            }),
             Expr::Goto(goto) => {
                 ctx.instructions.push(Instruction {
                     op: Op::Jump,
                     dst: None,
-                    src1: Some(Operand::Label(goto.label)),
+                    src1: Some(Operand::Label(Context::user_label(&goto.label))),
                     src2: None,
                 });
+                ctx.gotos.push(goto.label);
                 Ok(ctx.new_tmp(IRType::Void))
             }
             Expr::Label(label) => {
-                ctx.instructions.push(Instruction {
-                    op: Op::Label(label.name),
-                    dst: None,
-                    src1: None,
-                    src2: None,
-                });
+                ctx.define_user_label(&label.name)?;
+                Ok(ctx.new_tmp(IRType::Void))
+            }
+            Expr::Interp(interp) => {
+                // Each piece is appended to a fresh copy of the string so far,
+                // starting from a null string.
+                let mut acc = Operand::ConstIdx(self.get_const_index(IRConst::Int(0)));
+                for part in interp.parts {
+                    let operand = self.compile_expr(part, ctx)?;
+                    let typ = ctx.get_operand_type(&operand)?;
+                    if !matches!(
+                        typ,
+                        IRType::String | IRType::Int | IRType::Bool | IRType::Float
+                    ) {
+                        return Err(IRGenError::TypeError {
+                            message: format!("cannot interpolate a value of type {:?}", typ),
+                        });
+                    }
+                    let res_tmp = ctx.new_tmp(IRType::String);
+                    ctx.instructions.push(Instruction {
+                        op: Op::Append(typ),
+                        dst: Some(res_tmp.clone()),
+                        src1: Some(acc),
+                        src2: Some(operand),
+                    });
+                    acc = res_tmp;
+                }
+                Ok(acc)
+            }
+            Expr::Break(target) => {
+                ctx.jump_out_of_loop(true, target)?;
+                Ok(ctx.new_tmp(IRType::Void))
+            }
+            Expr::Continue(target) => {
+                ctx.jump_out_of_loop(false, target)?;
                 Ok(ctx.new_tmp(IRType::Void))
             }
         }
@@ -900,15 +1344,14 @@ impl IRGen {
     }
 
     fn func_decl(&mut self, decl: FuncDecl) -> Result<(), IRGenError> {
-        let mut temp_ctx = Context::new();
+        let temp_ctx = Context::new();
         let params: Vec<(Operand, IRType)> = decl
             .params
             .iter()
-            .enumerate()
-            .map(|(i, (name, typ))| (Operand::Var(name.clone()), temp_ctx.from_var_type(typ)))
+            .map(|(name, typ)| (Operand::Var(name.clone()), temp_ctx.ir_type(typ)))
             .collect();
 
-        let ret_type = temp_ctx.from_var_type(&decl.ret_type);
+        let ret_type = temp_ctx.ir_type(&decl.ret_type);
 
         self.functions.push(IRFunction {
             name: decl.name.clone(),
@@ -928,24 +1371,33 @@ impl IRGen {
         let mut ctx = Context::new();
         ctx.enter_scope();
 
-        for (i, (param, ty)) in func.params.iter().enumerate() {
-            if let Operand::Var(name) = param {
-                if let Some(scope) = ctx.scope.last_mut() {
-                    scope.insert(
-                        name.clone(),
-                        Symbol {
-                            name: name.clone(),
-                            ir_type: ty.clone(),
-                        },
-                    );
-                }
+        for (param, ty) in func.params.iter() {
+            if let Operand::Var(name) = param
+                && let Some(scope) = ctx.scope.last_mut()
+            {
+                scope.insert(
+                    name.clone(),
+                    Symbol {
+                        name: name.clone(),
+                        ir_type: ty.clone(),
+                        heap: false,
+                    },
+                );
             }
         }
 
         let body = *decl.body;
+        // The body's value is returned unless the function returns nothing.
+        ctx.unused = func.ret_type == IRType::Void;
         let last_op = self.compile_expr(body, &mut ctx)?;
         ctx.exit_scope()?;
 
+        if let Some(label) = ctx.gotos.iter().find(|l| !ctx.user_labels.contains(*l)) {
+            return Err(IRGenError::NameError {
+                message: format!("label '{}' is not defined in function '{}'", label, name),
+            });
+        }
+
         let last_inst_op = ctx.instructions.last().map(|i| i.op.clone());
 
         let last_is_return = matches!(last_inst_op, Some(Op::Return(_)));
@@ -957,10 +1409,16 @@ impl IRGen {
                 "rax".to_string()
             };
 
+            let ret_op = if name == "main" && func.ret_type == IRType::Void {
+                Operand::ConstIdx(self.get_const_index(IRConst::Int(0)))
+            } else {
+                last_op
+            };
+
             ctx.instructions.push(Instruction {
                 op: Op::Return(reg),
                 dst: None,
-                src1: Some(last_op),
+                src1: Some(ret_op),
                 src2: None,
             });
         }
@@ -980,11 +1438,11 @@ impl IRGen {
             .map(|(i, typ)| {
                 let temp_ctx = Context::new();
                 let param_name = format!("a{}", i);
-                (Operand::Var(param_name), temp_ctx.from_var_type(&typ))
+                (Operand::Var(param_name), temp_ctx.ir_type(&typ))
             })
             .collect();
 
-        let ret_type = Context::new().from_var_type(&ext.ret_type);
+        let ret_type = Context::new().ir_type(&ext.ret_type);
 
         let signature = IRFunction {
             name: name.clone(),
@@ -998,6 +1456,53 @@ impl IRGen {
         Ok(())
     }
 
+    /// The type of an `if` used as a value. Arrays of different lengths
+    /// unify to `arr<_>`; any other mismatch, or a missing branch, leaves
+    /// the `if` without a value, which is an error only where the value is
+    /// used. The branches then store nothing.
+    fn unify_branches(then_type: IRType, else_type: IRType) -> IRType {
+        match (then_type, else_type) {
+            (IRType::Array(n, a), IRType::Array(m, b)) if a == b => {
+                IRType::Array(if n == m { n } else { None }, a)
+            }
+            (a, b) if a == b => a,
+            _ => IRType::Void,
+        }
+    }
+
+    /// Only scalar types are allowed: an array stored in a global would
+    /// escape without the escape analysis seeing it.
+    fn extern_var(&mut self, ext: ExternVar) -> Result<(), IRGenError> {
+        let typ = Context::new().ir_type(&ext.typ);
+        if !matches!(
+            typ,
+            IRType::Int | IRType::Float | IRType::Bool | IRType::String
+        ) {
+            return Err(IRGenError::TypeError {
+                message: format!("extern variable '{}' cannot have type {:?}", ext.name, typ),
+            });
+        }
+        if self.globals.iter().any(|(name, _)| *name == ext.name) {
+            return Err(IRGenError::NameError {
+                message: format!("extern variable '{}' is declared more than once", ext.name),
+            });
+        }
+        self.globals.push((ext.name, typ));
+        Ok(())
+    }
+
+    /// Resolves a variable name to a local, or to an extern variable when no
+    /// local of that name is in scope.
+    fn resolve_var(&self, name: String, ctx: &Context) -> Result<(Operand, IRType), IRGenError> {
+        match ctx.get_var_type(&name) {
+            Ok(typ) => Ok((Operand::Var(name), typ)),
+            Err(err) => match self.globals.iter().find(|(global, _)| *global == name) {
+                Some((_, typ)) => Ok((Operand::Global(name, typ.clone()), typ.clone())),
+                None => Err(err),
+            },
+        }
+    }
+
     fn find_func(&self, name: &String) -> Result<IRFunction, IRGenError> {
         for func in self.functions.iter().rev() {
             if func.name == *name {
@@ -1009,3 +1514,693 @@ impl IRGen {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::Lexer, parser::Parser};
+    use ordered_float::OrderedFloat;
+
+    fn try_compile(src: &str) -> Result<IRProgram, IRGenError> {
+        let ast = Parser::new(Lexer::new(src)).parse().unwrap();
+        IRGen::new().compile(ast)
+    }
+
+    fn compile(src: &str) -> IRProgram {
+        try_compile(src).unwrap()
+    }
+
+    fn instructions<'a>(program: &'a IRProgram, func: &str) -> &'a [Instruction] {
+        let func = program.functions.iter().find(|f| f.name == func).unwrap();
+        &func.instructions
+    }
+
+    #[test]
+    fn if_without_a_common_type_stores_nothing() {
+        let program = compile(
+            "fun g(): void {
+            }
+            fun f(c: int): int {
+                if c == 1 { 5 } else { g() }
+                if c == 2 { 1.5 } else { 2 }
+                return 0
+            }",
+        );
+        assert!(instructions(&program, "f").iter().all(|inst| {
+            !matches!(inst.op, Op::Move | Op::FMove)
+                || !matches!(inst.dst, Some(Operand::Temp(_, IRType::Void)))
+        }));
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Value {
+        Heap(usize),
+        Other,
+    }
+
+    /// Runs straight-line IR against an allocator that numbers each heap
+    /// allocation and records each free, panicking on a double free or on a
+    /// use of a freed array.
+    struct MockHeap<'a> {
+        program: &'a IRProgram,
+        allocs: usize,
+        freed: Vec<usize>,
+    }
+
+    impl<'a> MockHeap<'a> {
+        fn new(program: &'a IRProgram) -> Self {
+            Self {
+                program,
+                allocs: 0,
+                freed: Vec::new(),
+            }
+        }
+
+        fn key(op: &Option<Operand>) -> Option<String> {
+            match op {
+                Some(Operand::Temp(id, _)) => Some(format!("%{}", id)),
+                Some(Operand::Var(name)) => Some(name.clone()),
+                _ => None,
+            }
+        }
+
+        fn live(&self, value: Value) -> Value {
+            if let Value::Heap(id) = value {
+                assert!(!self.freed.contains(&id), "array {} used after free", id);
+            }
+            value
+        }
+
+        fn call(&mut self, name: &str, args: Vec<Value>) -> Value {
+            let func = self
+                .program
+                .functions
+                .iter()
+                .find(|f| f.name == name)
+                .unwrap();
+            let mut slots: HashMap<String, Value> = HashMap::new();
+            for ((param, _), arg) in func.params.iter().zip(args) {
+                slots.insert(Self::key(&Some(param.clone())).unwrap(), arg);
+            }
+            let read = |slots: &HashMap<String, Value>, op: &Option<Operand>| {
+                Self::key(op)
+                    .and_then(|k| slots.get(&k).copied())
+                    .unwrap_or(Value::Other)
+            };
+            let mut args = Vec::new();
+            for inst in &func.instructions {
+                let dst = Self::key(&inst.dst);
+                match &inst.op {
+                    Op::HeapAlloc => {
+                        slots.insert(dst.unwrap(), Value::Heap(self.allocs));
+                        self.allocs += 1;
+                    }
+                    Op::Move | Op::Load | Op::Store => {
+                        let value = read(&slots, &inst.src1);
+                        slots.insert(dst.unwrap(), value);
+                    }
+                    Op::ArrayAccess(_) => {
+                        self.live(read(&slots, &inst.src1));
+                        slots.insert(dst.unwrap(), Value::Other);
+                    }
+                    Op::ArrayAssign(_) => {
+                        self.live(read(&slots, &inst.dst));
+                    }
+                    Op::Arg(_) => args.push(self.live(read(&slots, &inst.src1))),
+                    Op::Call => {
+                        let Some(Operand::Function(callee)) = &inst.src1 else {
+                            panic!("indirect call");
+                        };
+                        let value = self.call(callee, take(&mut args));
+                        slots.insert(dst.unwrap(), value);
+                    }
+                    Op::Free => match read(&slots, &inst.src1) {
+                        Value::Heap(id) => {
+                            self.live(Value::Heap(id));
+                            self.freed.push(id);
+                        }
+                        Value::Other => panic!("freed an array not on the heap"),
+                    },
+                    Op::Return(_) => return self.live(read(&slots, &inst.src1)),
+                    op => panic!("unsupported operation {:?}", op),
+                }
+            }
+            Value::Other
+        }
+    }
+
+    #[test]
+    fn scoped_heap_array_is_freed() {
+        let program = compile(
+            "fun f(): int {
+                {
+                    let big: arr<300000> = [0]
+                    big[1] = 4
+                }
+                return 0
+            }",
+        );
+        let mut heap = MockHeap::new(&program);
+        heap.call("f", Vec::new());
+        assert_eq!(heap.allocs, 1);
+        assert_eq!(heap.freed, [0]);
+    }
+
+    #[test]
+    fn returned_array_is_not_freed() {
+        let program = compile(
+            "fun make(): arr<300000> {
+                let a: arr<300000> = [0]
+                return a
+            }
+            fun f(): int {
+                {
+                    let b: arr<300000> = make()
+                }
+                return 0
+            }",
+        );
+        let mut heap = MockHeap::new(&program);
+        heap.call("f", Vec::new());
+        assert_eq!(heap.allocs, 1);
+        assert!(heap.freed.is_empty());
+    }
+
+    #[test]
+    fn array_returned_through_a_callee_is_not_freed() {
+        let program = compile(
+            "fun id(a: arr<300000>): arr<300000> {
+                return a
+            }
+            fun f(): int {
+                let s: arr<300000> = [1]
+                {
+                    let big: arr<300000> = [0]
+                    big = id(s)
+                }
+                return s[0]
+            }",
+        );
+        let mut heap = MockHeap::new(&program);
+        heap.call("f", Vec::new());
+        assert_eq!(heap.allocs, 2);
+        assert!(heap.freed.is_empty());
+    }
+
+    #[test]
+    fn call_to_a_function_defined_below() {
+        let program = compile(
+            "fun f(): int {
+                return g(2)
+            }
+            fun g(n: int): int {
+                return n * 3
+            }",
+        );
+        assert!(instructions(&program, "f").iter().any(|inst| {
+            inst.op == Op::Call && inst.src1 == Some(Operand::Function("g".to_string()))
+        }));
+    }
+
+    #[test]
+    fn call_to_an_undefined_function_is_a_name_error() {
+        let err = try_compile("fun f(): int { return g(2) }").unwrap_err();
+        assert!(matches!(err, IRGenError::NameError { .. }));
+        assert!(err.to_string().contains("'g'"));
+    }
+
+    #[test]
+    fn forward_call_result_has_the_callee_return_type() {
+        let program = compile(
+            "fun f(): flt {
+                let x: flt = g() * 2.0
+                return x
+            }
+            fun g(): flt {
+                return 1.5
+            }",
+        );
+        let call = instructions(&program, "f")
+            .iter()
+            .find(|inst| inst.op == Op::Call)
+            .unwrap();
+        assert!(matches!(call.dst, Some(Operand::Temp(_, IRType::Float))));
+        assert!(
+            instructions(&program, "f")
+                .iter()
+                .any(|inst| inst.op == Op::FMul)
+        );
+    }
+
+    #[test]
+    fn oversized_fixed_array_is_rejected() {
+        let err = try_compile(
+            "fun f(): int {
+                let a: arr<4611686018427387904> = [0]
+                return 0
+            }",
+        )
+        .unwrap_err();
+        assert!(matches!(err, IRGenError::TypeError { .. }));
+        assert!(err.to_string().contains("too large"));
+    }
+
+    #[test]
+    fn array_over_the_stack_budget_goes_on_the_heap() {
+        let program = compile(
+            "fun f(): int {
+                let a: arr<100000000> = [0]
+                return a[0]
+            }",
+        );
+        let insts = instructions(&program, "f");
+        assert!(insts.iter().any(|inst| inst.op == Op::HeapAlloc));
+        assert!(!insts.iter().any(|inst| inst.op == Op::Move
+            && matches!(inst.src1, Some(Operand::ConstIdx(idx))
+                if matches!(program.constants[idx], IRConst::Array(..)))));
+    }
+
+    fn stores_to(program: &IRProgram, func: &str, var: &str) -> usize {
+        instructions(program, func)
+            .iter()
+            .filter(|inst| inst.dst == Some(Operand::Var(var.to_string())))
+            .count()
+    }
+
+    #[test]
+    fn for_over_an_array_with_and_without_an_index() {
+        let single = compile(
+            "fun f(): int {
+                let a: arr<3> = [1, 2, 3]
+                let s: int = 0
+                for x in a { s += x }
+                return s
+            }",
+        );
+        assert!(stores_to(&single, "f", "x") > 0);
+
+        let dual = compile(
+            "fun f(): int {
+                let a: arr<3> = [1, 2, 3]
+                let s: int = 0
+                for i, x in a { s += i * x }
+                return s
+            }",
+        );
+        assert!(stores_to(&dual, "f", "x") > 0);
+        assert!(stores_to(&dual, "f", "i") > 0);
+    }
+
+    fn has_op(program: &IRProgram, func: &str, op: Op) -> bool {
+        instructions(program, func).iter().any(|inst| inst.op == op)
+    }
+
+    #[test]
+    fn sizeof_a_sized_array_is_its_length() {
+        let program = compile(
+            "fun f(): int {
+                return sizeof [1, 2, 3]
+            }",
+        );
+        assert!(!has_op(&program, "f", Op::SizeOf));
+        assert!(instructions(&program, "f").iter().any(|inst| {
+            matches!(inst.src1, Some(Operand::ConstIdx(idx))
+                if program.constants[idx] == IRConst::Int(3))
+        }));
+    }
+
+    #[test]
+    fn sizeof_an_unsized_array_is_read_at_run_time() {
+        let program = compile(
+            "fun f(a: arr<_>): int {
+                return sizeof a
+            }",
+        );
+        assert!(has_op(&program, "f", Op::SizeOf));
+    }
+
+    fn float_entries(program: &IRProgram, value: f64) -> usize {
+        program
+            .constants
+            .iter()
+            .filter(|c| matches!(c, IRConst::Float(f) if f.to_bits() == value.to_bits()))
+            .count()
+    }
+
+    #[test]
+    fn equal_float_literals_share_a_pool_entry() {
+        let program = compile(
+            "fun f(): flt {
+                let a: flt = 1.5
+                let b: flt = 1.5
+                return a + b + 1.5
+            }",
+        );
+        assert_eq!(float_entries(&program, 1.5), 1);
+    }
+
+    #[test]
+    fn negative_zero_has_its_own_pool_entry() {
+        let mut irgen = IRGen::new();
+        let float = |f: f64| IRConst::Float(OrderedFloat(f));
+        let zero = irgen.get_const_index(float(0.0));
+        assert_ne!(irgen.get_const_index(float(-0.0)), zero);
+        assert_eq!(irgen.get_const_index(float(0.0)), zero);
+    }
+
+    #[test]
+    fn each_array_literal_has_its_own_pool_entry() {
+        let program = compile(
+            "fun f(): int {
+                let a: arr<2> = [1, 2]
+                let b: arr<2> = [1, 2]
+                return a[0] + b[1]
+            }",
+        );
+        let arrays = program
+            .constants
+            .iter()
+            .filter(|c| matches!(c, IRConst::Array(..)))
+            .count();
+        assert_eq!(arrays, 2);
+    }
+
+    fn calls(program: &IRProgram, func: &str, callee: &str) -> usize {
+        let callee = Some(Operand::Function(callee.to_string()));
+        instructions(program, func)
+            .iter()
+            .filter(|inst| inst.op == Op::Call && inst.src1 == callee)
+            .count()
+    }
+
+    #[test]
+    fn compound_assignment_calls_the_index_once() {
+        let program = compile(
+            "fun next(): int {
+                return 1
+            }
+            fun f(): int {
+                let a: arr<3> = [1, 2, 3]
+                a[next()] += 1
+                a[next() + 1] <<= a[next()]
+                return a[1]
+            }",
+        );
+        assert_eq!(calls(&program, "f", "next"), 3);
+    }
+
+    #[test]
+    fn for_over_an_unsized_array_reads_its_length_once() {
+        let program = compile(
+            "fun f(a: arr<_>): int {
+                let t: int = 0
+                for v in a { t += v }
+                return t
+            }",
+        );
+        let insts = instructions(&program, "f");
+        let sizes: Vec<usize> = (0..insts.len())
+            .filter(|i| insts[*i].op == Op::SizeOf)
+            .collect();
+        let head = insts
+            .iter()
+            .position(|inst| matches!(inst.op, Op::Label(_)))
+            .unwrap();
+        assert_eq!(sizes.len(), 1);
+        assert!(sizes[0] < head);
+    }
+
+    #[test]
+    fn unsized_array_parameter_takes_any_length() {
+        let program = compile(
+            "fun first(a: arr<_>): int {
+                return a[0]
+            }
+            fun f(): int {
+                let short: arr<2> = [1, 2]
+                let long: arr<5> = [3]
+                return first(short) + first(long)
+            }",
+        );
+        assert_eq!(calls(&program, "f", "first"), 2);
+    }
+
+    #[test]
+    fn unsized_array_parameter_checks_the_element_type() {
+        let err = try_compile(
+            "fun first(a: arr<_>): int {
+                return a[0]
+            }
+            fun f(): int {
+                let bytes: arr<4, byte> = [0]
+                return first(bytes)
+            }",
+        )
+        .unwrap_err();
+        assert!(matches!(err, IRGenError::TypeError { .. }), "{:?}", err);
+        let err = try_compile(
+            "fun f(a: arr<3>): int {
+                return a[0]
+            }
+            fun g(): int {
+                let b: arr<4> = [0]
+                return f(b)
+            }",
+        )
+        .unwrap_err();
+        assert!(matches!(err, IRGenError::TypeError { .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn functions_may_reuse_a_label_name() {
+        let program = compile(
+            "fun f(): int {
+                start:
+                goto start
+                return 0
+            }
+            fun g(): int {
+                start:
+                goto start
+                return 1
+            }",
+        );
+        for func in ["f", "g"] {
+            let labels: Vec<&Op> = instructions(&program, func)
+                .iter()
+                .map(|inst| &inst.op)
+                .filter(|op| matches!(op, Op::Label(name) if name.ends_with("start")))
+                .collect();
+            // A label starting with a dot is local to its function in NASM.
+            assert_eq!(labels, [&Op::Label(".user.start".to_string())]);
+        }
+    }
+
+    #[test]
+    fn goto_cannot_leave_its_function() {
+        let err = try_compile(
+            "fun f(): int {
+                start:
+                return 0
+            }
+            fun g(): int {
+                goto start
+                return 1
+            }",
+        )
+        .unwrap_err();
+        assert!(matches!(err, IRGenError::NameError { .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn duplicate_label_is_an_error() {
+        let err = try_compile(
+            "fun f(): int {
+                start:
+                start:
+                return 0
+            }",
+        )
+        .unwrap_err();
+        assert!(matches!(err, IRGenError::NameError { .. }), "{:?}", err);
+    }
+
+    /// The labels jumped to, in order.
+    fn jumps(program: &IRProgram, func: &str) -> Vec<String> {
+        instructions(program, func)
+            .iter()
+            .filter_map(|inst| match (&inst.op, &inst.src1, &inst.src2) {
+                (Op::Jump, Some(Operand::Label(l)), _)
+                | (Op::JumpIfFalse, _, Some(Operand::Label(l))) => Some(l.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn labeled_jumps_target_the_named_loop() {
+        let program = compile(
+            "fun f(a: bool, b: bool): int {
+                outer: while a {
+                    while b {
+                        continue outer
+                        break outer
+                    }
+                }
+                return 0
+            }",
+        );
+        let jumps = jumps(&program, "f");
+        // The outer condition exits to the outer end; the inner body comes
+        // after the inner condition.
+        let (outer_end, body) = (&jumps[0], &jumps[2..4]);
+        assert_eq!(body, [".while_start_0", outer_end.as_str()]);
+    }
+
+    #[test]
+    fn labeled_break_outside_its_loop_is_an_error() {
+        let err = try_compile(
+            "fun f(a: bool, b: bool): int {
+                while a {
+                    outer: while b {}
+                    break outer
+                }
+                return 0
+            }",
+        )
+        .unwrap_err();
+        assert!(
+            matches!(&err, IRGenError::NameError { message } if message.contains("labeled 'outer'")),
+            "{:?}",
+            err
+        );
+    }
+
+    fn check_entry(src: &str) -> Result<(), IRGenError> {
+        let ast = Parser::new(Lexer::new(src)).parse().unwrap();
+        let mut irgen = IRGen::new();
+        let program = irgen.compile(ast)?;
+        irgen.check_entry(&program)
+    }
+
+    #[test]
+    fn program_without_main_has_no_entry() {
+        let err = check_entry("pub fun helper(): int { return 1 }").unwrap_err();
+        assert!(
+            matches!(&err, IRGenError::NameError { message } if message.contains("`main`")),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn main_signature_is_checked() {
+        assert!(check_entry("pub fun main(): int { return 0 }").is_ok());
+        assert!(check_entry("pub fun main(): void { }").is_ok());
+        assert!(check_entry("pub fun main(argc: int, argv: arr<_>): int { return argc }").is_ok());
+        for src in [
+            "pub fun main(): flt { return 1.0 }",
+            "pub fun main(x: int): int { return x }",
+            "pub fun main(argc: int, argv: int): int { return argc }",
+        ] {
+            assert!(
+                matches!(check_entry(src), Err(IRGenError::TypeError { .. })),
+                "{}",
+                src
+            );
+        }
+        assert!(matches!(
+            check_entry("fun main(): int { return 0 }"),
+            Err(IRGenError::NameError { .. })
+        ));
+    }
+
+    #[test]
+    fn top_level_return_is_an_error() {
+        assert!(matches!(
+            try_compile("return 5"),
+            Err(IRGenError::SyntaxError { message }) if message.contains("`return`")
+        ));
+    }
+
+    #[test]
+    fn break_and_continue_outside_a_loop_are_errors() {
+        for src in ["fun f(): void { break }", "fun f(): void { continue }"] {
+            assert!(
+                matches!(try_compile(src), Err(IRGenError::SyntaxError { .. })),
+                "{}",
+                src
+            );
+        }
+    }
+
+    #[test]
+    fn integer_and_float_arguments_are_numbered_apart() {
+        let program = compile(
+            "fun g(a: int, x: flt, b: int, y: flt): int { return a }
+            fun f(): int { return g(1, 1.5, 2, 2.5) }",
+        );
+        let args: Vec<&Op> = instructions(&program, "f")
+            .iter()
+            .map(|inst| &inst.op)
+            .filter(|op| matches!(op, Op::Arg(_) | Op::FArg(_)))
+            .collect();
+        assert_eq!(args, [&Op::Arg(0), &Op::FArg(0), &Op::Arg(1), &Op::FArg(1)]);
+    }
+
+    #[test]
+    fn void_bodied_if_stores_no_result() {
+        let program = compile(
+            "fun g(): void {
+            }
+            fun f(c: int): int {
+                if c == 1 { g() }
+                if c == 2 { g() } else { g() }
+                return 0
+            }",
+        );
+        // Only the calls themselves name a void temporary.
+        let void = |op: &Option<Operand>| matches!(op, Some(Operand::Temp(_, IRType::Void)));
+        for inst in instructions(&program, "f") {
+            if inst.op != Op::Call {
+                assert!(
+                    !void(&inst.dst) && !void(&inst.src1) && !void(&inst.src2),
+                    "{:?}",
+                    inst
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn if_expression_takes_the_type_of_its_branches() {
+        let program = compile(
+            "fun f(a: int, b: int): int {
+                let m: int = if a > b { a } else { b }
+                let x: flt = if a > b { 1.5 } else { 2.5 }
+                return m
+            }",
+        );
+        let stored = |var: &str| {
+            instructions(&program, "f")
+                .iter()
+                .find(|inst| inst.dst == Some(Operand::Var(var.to_string())))
+                .map(|inst| (inst.op.clone(), inst.src1.clone()))
+                .unwrap()
+        };
+        assert!(matches!(
+            stored("m"),
+            (Op::Store, Some(Operand::Temp(_, IRType::Int)))
+        ));
+        assert!(matches!(
+            stored("x"),
+            (Op::FStore, Some(Operand::Temp(_, IRType::Float)))
+        ));
+        assert!(matches!(
+            try_compile(
+                "fun f(c: int): int { let m: int = if c == 1 { 1 } else { 1.5 }\nreturn m }"
+            ),
+            Err(IRGenError::TypeError { .. })
+        ));
+    }
+}
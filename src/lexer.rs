@@ -1,8 +1,8 @@
-use std::{iter::Peekable, str::Chars};
+use std::{iter::Peekable, mem::replace, str::Chars};
 
 use ordered_float::OrderedFloat;
 
-use crate::token::{Literal, Token, TokenType, VarType};
+use crate::token::{Literal, Span, Token, TokenType, VarType};
 
 #[derive(Debug, Clone)]
 pub enum LexerError {
@@ -54,11 +54,31 @@ impl std::fmt::Display for LexerError {
     }
 }
 
+/// The character written as `\c` in a string or character literal, or
+/// `None` if `c` has no escape meaning.
+pub fn unescape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '\\' => Some('\\'),
+        '"' => Some('"'),
+        '\'' => Some('\''),
+        '0' => Some('\0'),
+        _ => None,
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Lexer<'a> {
     tok: Token,
     src: Peekable<Chars<'a>>,
-    is_flt: bool,
+    pos: usize,
+    start: usize,
+    peeked: Option<(Token, Peekable<Chars<'a>>, usize)>,
+    /// Whether `#line N` comments renumber lines, which only the
+    /// preprocessor's output may use.
+    line_markers: bool,
 }
 
 impl<'a> Lexer<'a> {
@@ -69,32 +89,43 @@ impl<'a> Lexer<'a> {
                 value: None,
                 row: 1,
                 col: 1,
+                span: Span::default(),
             },
             src: src.chars().peekable(),
-            is_flt: false,
+            pos: 0,
+            start: 0,
+            peeked: None,
+            line_markers: false,
         }
     }
 
-    fn current(&mut self) -> char {
-        *self.src.peek().unwrap_or(&'\0')
+    /// A lexer for the preprocessor's output, which honors the `#line`
+    /// markers it writes around imported files. User comments never reach
+    /// that output, so they cannot pass for a marker.
+    pub fn preprocessed(src: &'a str) -> Self {
+        Lexer {
+            line_markers: true,
+            ..Self::new(src)
+        }
     }
 
-    fn current_safe(&mut self) -> Result<char, LexerError> {
-        Ok(*self.src.peek().ok_or_else(|| LexerError::UnexpectedChar {
-            expected: None,
-            found: '\0',
-            row: self.tok.row,
-            col: self.tok.col,
-        })?)
+    fn current(&mut self) -> char {
+        *self.src.peek().unwrap_or(&'\0')
     }
 
-    fn bump(&mut self) -> () {
-        self.src.next();
+    fn bump(&mut self) {
+        if let Some(c) = self.src.next() {
+            self.pos += c.len_utf8();
+        }
         self.tok.col += 1;
     }
 
-    fn skip_spaces(&mut self) -> () {
-        while self.current() == ' ' || self.current() == '\t' || self.current() == '\n' {
+    fn skip_spaces(&mut self) {
+        while self.current() == ' '
+            || self.current() == '\t'
+            || self.current() == '\r'
+            || self.current() == '\n'
+        {
             if self.current() == '\n' {
                 self.tok.row += 1;
                 self.tok.col = 0;
@@ -103,26 +134,46 @@ impl<'a> Lexer<'a> {
         }
     }
 
-    fn parse_number(&mut self) -> Result<f64, LexerError> {
-        let mut int_part = 0;
-        let mut frac_part = 0;
-        let mut frac_div = 1;
-        self.is_flt = false;
+    fn parse_number(&mut self) -> Result<Literal, LexerError> {
+        let row = self.tok.row;
+        let col = self.tok.col;
+        let mut text = String::new();
+        let mut int_val: Option<i64> = Some(0);
+
+        if self.current() == '0' {
+            self.bump();
+            let radix = match self.current() {
+                'x' | 'X' => 16,
+                'b' | 'B' => 2,
+                'o' | 'O' => 8,
+                _ => 10,
+            };
+            if radix != 10 {
+                self.bump();
+                return self.parse_radix(radix, row, col);
+            }
+            text.push('0');
+            self.skip_separator(10)?;
+        }
 
         while self.current().is_numeric() {
-            int_part = int_part * 10
-                + self
-                    .current()
-                    .to_digit(10)
-                    .ok_or_else(|| LexerError::InvalidNumber {
-                        row: self.tok.row,
-                        col: self.tok.col,
-                    })?;
+            let digit = self
+                .current()
+                .to_digit(10)
+                .ok_or(LexerError::InvalidNumber {
+                    row: self.tok.row,
+                    col: self.tok.col,
+                })?;
+            int_val = int_val
+                .and_then(|n| n.checked_mul(10))
+                .and_then(|n| n.checked_add(digit as i64));
+            text.push(self.current());
             self.bump();
+            self.skip_separator(10)?;
         }
 
         if self.current() == '.' {
-            self.is_flt = true;
+            text.push('.');
             self.bump();
             if !self.current().is_numeric() {
                 return Err(LexerError::InvalidNumber {
@@ -131,20 +182,83 @@ impl<'a> Lexer<'a> {
                 });
             }
             while self.current().is_numeric() {
-                frac_div *= 10;
-                frac_part = frac_part * 10
-                    + self
-                        .current()
-                        .to_digit(10)
-                        .ok_or_else(|| LexerError::InvalidNumber {
-                            row: self.tok.row,
-                            col: self.tok.col,
-                        })?;
+                text.push(self.current());
                 self.bump();
+                self.skip_separator(10)?;
             }
+            self.check_number_end(row, col)?;
+            let val = text
+                .parse::<f64>()
+                .map_err(|_| LexerError::InvalidNumber { row, col })?;
+            return Ok(Literal::Float(OrderedFloat(val)));
         }
 
-        Ok((int_part * frac_div + frac_part) as f64 / frac_div as f64)
+        self.check_number_end(row, col)?;
+        int_val
+            .map(Literal::Int)
+            .ok_or_else(|| LexerError::SyntaxError {
+                message: "integer literal too large".to_string(),
+                row,
+                col,
+            })
+    }
+
+    /// Parses the digits of a `0x`, `0b` or `0o` literal, after the prefix.
+    fn parse_radix(&mut self, radix: u32, row: usize, col: usize) -> Result<Literal, LexerError> {
+        let mut int_val: Option<i64> = Some(0);
+        let mut digits = 0;
+
+        while let Some(digit) = self.current().to_digit(radix) {
+            int_val = int_val
+                .and_then(|n| n.checked_mul(radix as i64))
+                .and_then(|n| n.checked_add(digit as i64));
+            digits += 1;
+            self.bump();
+            self.skip_separator(radix)?;
+        }
+
+        if digits == 0 || self.current().is_ascii_digit() {
+            return Err(LexerError::SyntaxError {
+                message: format!("invalid base {} literal", radix),
+                row,
+                col,
+            });
+        }
+        self.check_number_end(row, col)?;
+        int_val
+            .map(Literal::Int)
+            .ok_or_else(|| LexerError::SyntaxError {
+                message: "integer literal too large".to_string(),
+                row,
+                col,
+            })
+    }
+
+    /// Skips a `_` written between two digits, as in `1_000_000`.
+    fn skip_separator(&mut self, radix: u32) -> Result<(), LexerError> {
+        if self.current() == '_' {
+            self.bump();
+            if self.current().to_digit(radix).is_none() {
+                return Err(LexerError::SyntaxError {
+                    message: "`_` in a numeric literal must be followed by a digit".to_string(),
+                    row: self.tok.row,
+                    col: self.tok.col,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Rejects a number running straight into a name, such as `1abc`.
+    fn check_number_end(&mut self, row: usize, col: usize) -> Result<(), LexerError> {
+        if self.current().is_alphabetic() || self.current() == '_' {
+            return Err(LexerError::SyntaxError {
+                message: "invalid numeric literal".to_string(),
+                row,
+                col,
+            });
+        }
+        Ok(())
     }
 
     fn parse_ident(&mut self) -> String {
@@ -162,43 +276,81 @@ impl<'a> Lexer<'a> {
         ident
     }
 
-    fn is_prefix(&mut self) -> bool {
-        let prev = *self.src.peek().unwrap_or(&' ');
-        self.tok.token == TokenType::EOF
-            || self.tok.token == TokenType::LPAREN
-            || self.tok.token == TokenType::EQ
-            || prev == '='
-            || prev == '('
+    /// A `+` or `-` is a prefix operator unless it follows something that
+    /// ends an operand.
+    fn is_prefix(&self) -> bool {
+        !matches!(
+            self.tok.token,
+            TokenType::LITERAL(_) | TokenType::IDENT | TokenType::RPAREN | TokenType::RBRACKET
+        )
     }
 
     pub fn next_token(&mut self) -> Result<(), LexerError> {
+        match self.peeked.take() {
+            Some((tok, src, pos)) => {
+                self.tok = tok;
+                self.src = src;
+                self.pos = pos;
+                Ok(())
+            }
+            None => self.lex(),
+        }
+    }
+
+    /// Returns the token after the current one without consuming it. The
+    /// lexer state past that token is kept until the next `next_token`.
+    pub fn peek_token(&mut self) -> Result<Token, LexerError> {
+        if let Some((tok, _, _)) = &self.peeked {
+            return Ok(tok.clone());
+        }
+        let (tok, src, pos) = (self.tok.clone(), self.src.clone(), self.pos);
+        let lexed = self.lex();
+        let next = replace(&mut self.tok, tok);
+        let rest = replace(&mut self.src, src);
+        let rest_pos = replace(&mut self.pos, pos);
+        lexed?;
+        self.peeked = Some((next.clone(), rest, rest_pos));
+        Ok(next)
+    }
+
+    /// Lexes one token and records the bytes it covers. Tokens that `scan`
+    /// skips over, like comments, lex their successor recursively, which
+    /// moves `start` along with them.
+    fn lex(&mut self) -> Result<(), LexerError> {
         self.skip_spaces();
+        self.start = self.pos;
+        self.scan()?;
+        self.tok.span = Span {
+            start: self.start,
+            end: self.pos,
+        };
+        Ok(())
+    }
+
+    fn scan(&mut self) -> Result<(), LexerError> {
         if self.current() == '\0' {
             self.tok = Token {
                 token: TokenType::EOF,
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
-            return Ok(());
+            Ok(())
         } else if self.current().is_numeric() {
             let val = self.parse_number()?;
-            if self.is_flt {
-                self.tok = Token {
-                    token: TokenType::LITERAL(VarType::Float),
-                    value: Some(Literal::Float(OrderedFloat(val))),
-                    row: self.tok.row,
-                    col: self.tok.col,
-                };
-            } else {
-                self.tok = Token {
-                    token: TokenType::LITERAL(VarType::Int),
-                    value: Some(Literal::Int(val as i64)),
-                    row: self.tok.row,
-                    col: self.tok.col,
-                };
-            }
-            return Ok(());
+            let typ = match val {
+                Literal::Float(_) => VarType::Float,
+                _ => VarType::Int,
+            };
+            self.tok = Token {
+                token: TokenType::LITERAL(typ),
+                value: Some(val),
+                row: self.tok.row,
+                col: self.tok.col,
+                span: Span::default(),
+            };
+            Ok(())
         } else if self.current().is_alphabetic() || self.current() == '_' {
             let ident: String = self.parse_ident();
             match ident.as_str() {
@@ -208,6 +360,7 @@ impl<'a> Lexer<'a> {
                         value: Some(Literal::Bool(true)),
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     };
                 }
                 "false" => {
@@ -216,6 +369,7 @@ impl<'a> Lexer<'a> {
                         value: Some(Literal::Bool(false)),
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     };
                 }
                 "null" => {
@@ -224,6 +378,7 @@ impl<'a> Lexer<'a> {
                         value: Some(Literal::Void),
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     };
                 }
                 "let" => {
@@ -232,6 +387,7 @@ impl<'a> Lexer<'a> {
                         value: None,
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     };
                 }
                 "fun" => {
@@ -240,6 +396,7 @@ impl<'a> Lexer<'a> {
                         value: None,
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     }
                 }
                 "return" => {
@@ -248,6 +405,7 @@ impl<'a> Lexer<'a> {
                         value: None,
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     }
                 }
                 "if" => {
@@ -256,6 +414,7 @@ impl<'a> Lexer<'a> {
                         value: None,
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     }
                 }
                 "else" => {
@@ -264,6 +423,7 @@ impl<'a> Lexer<'a> {
                         value: None,
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     }
                 }
                 "while" => {
@@ -272,6 +432,7 @@ impl<'a> Lexer<'a> {
                         value: None,
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     }
                 }
                 "goto" => {
@@ -280,6 +441,25 @@ impl<'a> Lexer<'a> {
                         value: None,
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
+                    }
+                }
+                "break" => {
+                    self.tok = Token {
+                        token: TokenType::BREAK,
+                        value: None,
+                        row: self.tok.row,
+                        col: self.tok.col,
+                        span: Span::default(),
+                    }
+                }
+                "continue" => {
+                    self.tok = Token {
+                        token: TokenType::CONTINUE,
+                        value: None,
+                        row: self.tok.row,
+                        col: self.tok.col,
+                        span: Span::default(),
                     }
                 }
                 "extern" => {
@@ -288,6 +468,7 @@ impl<'a> Lexer<'a> {
                         value: None,
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     }
                 }
                 "pub" => {
@@ -296,6 +477,7 @@ impl<'a> Lexer<'a> {
                         value: None,
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     }
                 }
                 "int" => {
@@ -304,6 +486,7 @@ impl<'a> Lexer<'a> {
                         value: None,
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     }
                 }
                 "flt" => {
@@ -312,6 +495,7 @@ impl<'a> Lexer<'a> {
                         value: None,
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     }
                 }
                 "str" => {
@@ -320,6 +504,7 @@ impl<'a> Lexer<'a> {
                         value: None,
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     }
                 }
                 "bool" => {
@@ -328,6 +513,7 @@ impl<'a> Lexer<'a> {
                         value: None,
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     }
                 }
                 "void" => {
@@ -336,6 +522,7 @@ impl<'a> Lexer<'a> {
                         value: None,
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     }
                 }
                 "arr" => {
@@ -350,7 +537,15 @@ impl<'a> Lexer<'a> {
                     self.bump();
                     let len: Option<usize>;
                     if self.current().is_numeric() {
-                        len = Some(self.parse_number()? as usize);
+                        match self.parse_number()? {
+                            Literal::Int(n) => len = Some(n as usize),
+                            _ => {
+                                return Err(LexerError::InvalidNumber {
+                                    row: self.tok.row,
+                                    col: self.tok.col,
+                                });
+                            }
+                        }
                     } else if self.current() == '_' {
                         len = None;
                         self.bump();
@@ -362,6 +557,24 @@ impl<'a> Lexer<'a> {
                             col: self.tok.col,
                         });
                     }
+                    let mut elem = VarType::Int;
+                    if self.current() == ',' {
+                        self.bump();
+                        while self.current() == ' ' {
+                            self.bump();
+                        }
+                        match self.parse_ident().as_str() {
+                            "byte" => elem = VarType::Byte,
+                            _ => {
+                                return Err(LexerError::UnexpectedChar {
+                                    expected: Some("byte".to_string()),
+                                    found: self.current(),
+                                    row: self.tok.row,
+                                    col: self.tok.col,
+                                });
+                            }
+                        }
+                    }
                     if self.current() != '>' {
                         return Err(LexerError::UnexpectedChar {
                             expected: Some(">".to_string()),
@@ -372,10 +585,11 @@ impl<'a> Lexer<'a> {
                     }
                     self.bump();
                     self.tok = Token {
-                        token: TokenType::Type(VarType::Array(len)),
+                        token: TokenType::Type(VarType::Array(len, Box::new(elem))),
                         value: None,
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     }
                 }
                 "sizeof" => {
@@ -384,6 +598,7 @@ impl<'a> Lexer<'a> {
                         value: None,
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     }
                 }
                 "for" => {
@@ -392,6 +607,7 @@ impl<'a> Lexer<'a> {
                         value: None,
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     }
                 }
                 "in" => {
@@ -400,6 +616,7 @@ impl<'a> Lexer<'a> {
                         value: None,
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     }
                 }
                 _ => {
@@ -408,11 +625,14 @@ impl<'a> Lexer<'a> {
                         value: Some(Literal::Str(ident)),
                         row: self.tok.row,
                         col: self.tok.col,
+                        span: Span::default(),
                     }
                 }
             }
-            return Ok(());
+            Ok(())
         } else if self.current() == '"' {
+            let row = self.tok.row;
+            let col = self.tok.col;
             self.bump();
             let mut s = String::new();
             loop {
@@ -421,32 +641,19 @@ impl<'a> Lexer<'a> {
                         self.bump();
                         break;
                     }
-                    '\0' => {}
+                    '\0' => {
+                        return Err(LexerError::SyntaxError {
+                            message: "unterminated string literal".to_string(),
+                            row,
+                            col,
+                        });
+                    }
                     '\\' => {
                         self.bump();
-                        match self.current() {
-                            'n' => {
-                                s.push('\n');
-                                self.bump();
-                            }
-                            't' => {
-                                s.push('\t');
-                                self.bump();
-                            }
-                            'r' => {
-                                s.push('\r');
-                                self.bump();
-                            }
-                            '\\' => {
-                                s.push('\\');
-                                self.bump();
-                            }
-                            '"' => {
-                                s.push('"');
-                                self.bump();
-                            }
-                            _ => self.bump(),
+                        if let Some(c) = unescape(self.current()) {
+                            s.push(c);
                         }
+                        self.bump();
                     }
                     c => {
                         s.push(c);
@@ -459,35 +666,86 @@ impl<'a> Lexer<'a> {
                 value: Some(Literal::Str(s)),
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
-            return Ok(());
+            Ok(())
         } else if self.current() == '\'' {
+            // A character literal is the integer code of its character.
+            let row = self.tok.row;
+            let col = self.tok.col;
+            let error = |message: &str| LexerError::SyntaxError {
+                message: message.to_string(),
+                row,
+                col,
+            };
             self.bump();
-            let mut s = String::new();
-            while self.current() != '\'' {
-                if self.current() == '\0' {
-                    return Err(LexerError::UnexpectedChar {
-                        expected: Some("'".to_string()),
-                        found: self.current(),
-                        row: self.tok.row,
-                        col: self.tok.col,
-                    });
+            let c = match self.current() {
+                '\'' => return Err(error("empty character literal")),
+                '\0' => return Err(error("unterminated character literal")),
+                '\\' => {
+                    self.bump();
+                    unescape(self.current())
+                        .ok_or_else(|| error("unknown escape in character literal"))?
                 }
-                s.push(self.current());
-                self.bump();
+                c => c,
+            };
+            self.bump();
+            if self.current() != '\'' {
+                return Err(error("character literal must hold exactly one character"));
             }
             self.bump();
             self.tok = Token {
-                token: TokenType::LITERAL(VarType::Str),
+                token: TokenType::LITERAL(VarType::Int),
+                value: Some(Literal::Int(c as i64)),
+                row: self.tok.row,
+                col: self.tok.col,
+                span: Span::default(),
+            };
+            Ok(())
+        } else if self.current() == '`' {
+            // The text is kept raw, escapes included; the parser splits it
+            // into literal pieces and `{}` expressions.
+            let row = self.tok.row;
+            let col = self.tok.col;
+            self.bump();
+            let mut s = String::new();
+            loop {
+                match self.current() {
+                    '`' => {
+                        self.bump();
+                        break;
+                    }
+                    '\0' => {
+                        return Err(LexerError::SyntaxError {
+                            message: "unterminated interpolated string".to_string(),
+                            row,
+                            col,
+                        });
+                    }
+                    '\\' => {
+                        s.push('\\');
+                        self.bump();
+                        s.push(self.current());
+                        self.bump();
+                    }
+                    c => {
+                        s.push(c);
+                        self.bump();
+                    }
+                }
+            }
+            self.tok = Token {
+                token: TokenType::INTERP,
                 value: Some(Literal::Str(s)),
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
-            return Ok(());
+            Ok(())
         } else if self.current() == '+' {
             if self.is_prefix() {
                 self.bump();
-                return Ok(());
+                return self.lex();
             }
             self.bump();
             if self.current() == '=' {
@@ -496,6 +754,7 @@ impl<'a> Lexer<'a> {
                     value: None,
                     row: self.tok.row,
                     col: self.tok.col,
+                    span: Span::default(),
                 };
                 self.bump();
                 return Ok(());
@@ -505,8 +764,9 @@ impl<'a> Lexer<'a> {
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
-            return Ok(());
+            Ok(())
         } else if self.current() == '-' {
             if self.is_prefix() {
                 self.tok = Token {
@@ -514,6 +774,7 @@ impl<'a> Lexer<'a> {
                     value: None,
                     row: self.tok.row,
                     col: self.tok.col,
+                    span: Span::default(),
                 };
                 self.bump();
                 return Ok(());
@@ -525,6 +786,7 @@ impl<'a> Lexer<'a> {
                     value: None,
                     row: self.tok.row,
                     col: self.tok.col,
+                    span: Span::default(),
                 };
                 self.bump();
                 return Ok(());
@@ -534,8 +796,9 @@ impl<'a> Lexer<'a> {
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
-            return Ok(());
+            Ok(())
         } else if self.current() == '*' {
             self.bump();
             if self.current() == '=' {
@@ -544,6 +807,7 @@ impl<'a> Lexer<'a> {
                     value: None,
                     row: self.tok.row,
                     col: self.tok.col,
+                    span: Span::default(),
                 };
                 self.bump();
                 return Ok(());
@@ -553,8 +817,9 @@ impl<'a> Lexer<'a> {
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
-            return Ok(());
+            Ok(())
         } else if self.current() == '/' {
             self.bump();
             if self.current() == '=' {
@@ -563,6 +828,7 @@ impl<'a> Lexer<'a> {
                     value: None,
                     row: self.tok.row,
                     col: self.tok.col,
+                    span: Span::default(),
                 };
                 self.bump();
                 return Ok(());
@@ -572,44 +838,70 @@ impl<'a> Lexer<'a> {
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
-            return Ok(());
+            Ok(())
+        } else if self.current() == '%' {
+            self.bump();
+            if self.current() == '=' {
+                self.tok = Token {
+                    token: TokenType::MODEQ,
+                    value: None,
+                    row: self.tok.row,
+                    col: self.tok.col,
+                    span: Span::default(),
+                };
+                self.bump();
+                return Ok(());
+            }
+            self.tok = Token {
+                token: TokenType::MOD,
+                value: None,
+                row: self.tok.row,
+                col: self.tok.col,
+                span: Span::default(),
+            };
+            Ok(())
         } else if self.current() == '(' {
             self.tok = Token {
                 token: TokenType::LPAREN,
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
             self.bump();
-            return Ok(());
+            Ok(())
         } else if self.current() == ')' {
             self.tok = Token {
                 token: TokenType::RPAREN,
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
             self.bump();
-            return Ok(());
+            Ok(())
         } else if self.current() == '{' {
             self.tok = Token {
                 token: TokenType::LBRACE,
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
             self.bump();
-            return Ok(());
+            Ok(())
         } else if self.current() == '}' {
             self.tok = Token {
                 token: TokenType::RBRACE,
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
             self.bump();
-            return Ok(());
+            Ok(())
         } else if self.current() == '=' {
             self.bump();
             if self.current() == '=' {
@@ -618,6 +910,7 @@ impl<'a> Lexer<'a> {
                     value: None,
                     row: self.tok.row,
                     col: self.tok.col,
+                    span: Span::default(),
                 };
                 self.bump();
                 return Ok(());
@@ -627,8 +920,9 @@ impl<'a> Lexer<'a> {
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
-            return Ok(());
+            Ok(())
         } else if self.current() == '!' {
             self.bump();
             if self.current() == '=' {
@@ -637,6 +931,7 @@ impl<'a> Lexer<'a> {
                     value: None,
                     row: self.tok.row,
                     col: self.tok.col,
+                    span: Span::default(),
                 };
                 self.bump();
                 return Ok(());
@@ -646,8 +941,9 @@ impl<'a> Lexer<'a> {
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
-            return Ok(());
+            Ok(())
         } else if self.current() == '>' {
             self.bump();
             if self.current() == '=' {
@@ -656,17 +952,41 @@ impl<'a> Lexer<'a> {
                     value: None,
                     row: self.tok.row,
                     col: self.tok.col,
+                    span: Span::default(),
                 };
                 self.bump();
                 return Ok(());
             }
+            if self.current() == '>' {
+                self.bump();
+                if self.current() == '=' {
+                    self.tok = Token {
+                        token: TokenType::SHREQ,
+                        value: None,
+                        row: self.tok.row,
+                        col: self.tok.col,
+                        span: Span::default(),
+                    };
+                    self.bump();
+                    return Ok(());
+                }
+                self.tok = Token {
+                    token: TokenType::SHR,
+                    value: None,
+                    row: self.tok.row,
+                    col: self.tok.col,
+                    span: Span::default(),
+                };
+                return Ok(());
+            }
             self.tok = Token {
                 token: TokenType::COMPGT,
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
-            return Ok(());
+            Ok(())
         } else if self.current() == '<' {
             self.bump();
             if self.current() == '=' {
@@ -675,17 +995,41 @@ impl<'a> Lexer<'a> {
                     value: None,
                     row: self.tok.row,
                     col: self.tok.col,
+                    span: Span::default(),
                 };
                 self.bump();
                 return Ok(());
             }
+            if self.current() == '<' {
+                self.bump();
+                if self.current() == '=' {
+                    self.tok = Token {
+                        token: TokenType::SHLEQ,
+                        value: None,
+                        row: self.tok.row,
+                        col: self.tok.col,
+                        span: Span::default(),
+                    };
+                    self.bump();
+                    return Ok(());
+                }
+                self.tok = Token {
+                    token: TokenType::SHL,
+                    value: None,
+                    row: self.tok.row,
+                    col: self.tok.col,
+                    span: Span::default(),
+                };
+                return Ok(());
+            }
             self.tok = Token {
                 token: TokenType::COMPLT,
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
-            return Ok(());
+            Ok(())
         } else if self.current() == '&' {
             self.bump();
             if self.current() == '&' {
@@ -694,6 +1038,18 @@ impl<'a> Lexer<'a> {
                     value: None,
                     row: self.tok.row,
                     col: self.tok.col,
+                    span: Span::default(),
+                };
+                self.bump();
+                return Ok(());
+            }
+            if self.current() == '=' {
+                self.tok = Token {
+                    token: TokenType::ANDEQ,
+                    value: None,
+                    row: self.tok.row,
+                    col: self.tok.col,
+                    span: Span::default(),
                 };
                 self.bump();
                 return Ok(());
@@ -703,8 +1059,9 @@ impl<'a> Lexer<'a> {
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
-            return Ok(());
+            Ok(())
         } else if self.current() == '|' {
             self.bump();
             if self.current() == '|' {
@@ -713,6 +1070,18 @@ impl<'a> Lexer<'a> {
                     value: None,
                     row: self.tok.row,
                     col: self.tok.col,
+                    span: Span::default(),
+                };
+                self.bump();
+                return Ok(());
+            }
+            if self.current() == '=' {
+                self.tok = Token {
+                    token: TokenType::OREQ,
+                    value: None,
+                    row: self.tok.row,
+                    col: self.tok.col,
+                    span: Span::default(),
                 };
                 self.bump();
                 return Ok(());
@@ -722,75 +1091,114 @@ impl<'a> Lexer<'a> {
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
-            return Ok(());
+            Ok(())
         } else if self.current() == '^' {
+            self.bump();
+            if self.current() == '=' {
+                self.tok = Token {
+                    token: TokenType::XOREQ,
+                    value: None,
+                    row: self.tok.row,
+                    col: self.tok.col,
+                    span: Span::default(),
+                };
+                self.bump();
+                return Ok(());
+            }
             self.tok = Token {
                 token: TokenType::LOGXOR,
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
-            self.bump();
-            return Ok(());
+            Ok(())
         } else if self.current() == ':' {
             self.tok = Token {
                 token: TokenType::COLON,
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
             self.bump();
-            return Ok(());
+            Ok(())
+        } else if self.current() == '?' {
+            self.tok = Token {
+                token: TokenType::QUESTION,
+                value: None,
+                row: self.tok.row,
+                col: self.tok.col,
+                span: Span::default(),
+            };
+            self.bump();
+            Ok(())
         } else if self.current() == '~' {
             self.tok = Token {
                 token: TokenType::RANGE,
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
             self.bump();
-            return Ok(());
+            Ok(())
         } else if self.current() == '[' {
             self.tok = Token {
                 token: TokenType::LBRACKET,
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
             self.bump();
-            return Ok(());
+            Ok(())
         } else if self.current() == ']' {
             self.tok = Token {
                 token: TokenType::RBRACKET,
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
             self.bump();
-            return Ok(());
+            Ok(())
         } else if self.current() == ',' {
             self.tok = Token {
                 token: TokenType::COMMA,
                 value: None,
                 row: self.tok.row,
                 col: self.tok.col,
+                span: Span::default(),
             };
             self.bump();
-            return Ok(());
+            Ok(())
         } else if self.current() == '#' {
+            let mut comment = String::new();
             while self.current() != '\n' && self.current() != '\0' {
+                comment.push(self.current());
                 self.bump();
             }
-            self.next_token()?;
-            return Ok(());
+            // `#line N`, written by the preprocessor around imported code,
+            // numbers the line after it N.
+            if let Some(row) = comment
+                .strip_prefix("#line ")
+                .and_then(|n| n.trim().parse::<usize>().ok())
+                .filter(|_| self.line_markers)
+            {
+                self.tok.row = row.saturating_sub(1);
+            }
+            self.lex()?;
+            Ok(())
         } else {
-            return Err(LexerError::UnexpectedChar {
+            Err(LexerError::UnexpectedChar {
                 expected: None,
                 found: self.current(),
                 row: self.tok.row,
                 col: self.tok.col,
-            });
+            })
         }
     }
 
@@ -802,3 +1210,270 @@ impl<'a> Lexer<'a> {
         self.current()
     }
 }
+
+/// Yields the tokens after the current one, up to but not including `EOF`.
+/// The rest of the input is dropped after an error, so iteration ends there.
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<Token, LexerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_token() {
+            Ok(()) if self.tok.token == TokenType::EOF => None,
+            Ok(()) => Some(Ok(self.curr_tok())),
+            Err(e) => {
+                self.src.by_ref().for_each(drop);
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(src: &str) -> Vec<TokenType> {
+        Lexer::new(src).map(|tok| tok.unwrap().token).collect()
+    }
+
+    fn value(src: &str) -> Literal {
+        Lexer::new(src).next().unwrap().unwrap().value.unwrap()
+    }
+
+    fn error(src: &str) -> String {
+        Lexer::new(src)
+            .find_map(|tok| tok.err())
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn compound_assignments() {
+        assert_eq!(
+            tokens("%= <<= >>="),
+            [TokenType::MODEQ, TokenType::SHLEQ, TokenType::SHREQ]
+        );
+    }
+
+    #[test]
+    fn compound_assignments_leave_comparisons_and_shifts_alone() {
+        assert_eq!(
+            tokens("% << >> <= >= < >"),
+            [
+                TokenType::MOD,
+                TokenType::SHL,
+                TokenType::SHR,
+                TokenType::COMPLE,
+                TokenType::COMPGE,
+                TokenType::COMPLT,
+                TokenType::COMPGT,
+            ]
+        );
+        assert_eq!(tokens(">>>="), [TokenType::SHR, TokenType::COMPGE]);
+    }
+
+    fn rows(mut lexer: Lexer) -> Vec<usize> {
+        lexer.by_ref().map(|tok| tok.unwrap().row).collect()
+    }
+
+    #[test]
+    fn line_markers_renumber_preprocessed_lines() {
+        let src = "a\n#line 1\nb\nc\n#line 2\nd";
+        assert_eq!(rows(Lexer::preprocessed(src)), [1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn user_comments_do_not_renumber_lines() {
+        let src = "a\n#line 40\nb";
+        assert_eq!(rows(Lexer::new(src)), [1, 3]);
+    }
+
+    #[test]
+    fn integer_literal_overflowing_i64_is_an_error() {
+        assert_eq!(
+            error("x = 9999999999999999999999999"),
+            "Syntax error at 1:5: integer literal too large"
+        );
+        assert_eq!(value("9223372036854775807"), Literal::Int(i64::MAX));
+        assert!(error("9223372036854775808").contains("integer literal too large"));
+    }
+
+    #[test]
+    fn spans_cover_the_token_text() {
+        let src = "let xy: int = 0x1F + 2.5\n  \"é s\" >>= 'c'";
+        let texts: Vec<&str> = Lexer::new(src)
+            .map(|tok| {
+                let span = tok.unwrap().span;
+                &src[span.start..span.end]
+            })
+            .collect();
+        assert_eq!(
+            texts,
+            [
+                "let", "xy", ":", "int", "=", "0x1F", "+", "2.5", "\"é s\"", ">>=", "'c'"
+            ]
+        );
+    }
+
+    #[test]
+    fn radix_literals() {
+        assert_eq!(value("0x1F"), Literal::Int(31));
+        assert_eq!(value("0XfF"), Literal::Int(255));
+        assert_eq!(value("0b101"), Literal::Int(5));
+        assert_eq!(value("0o17"), Literal::Int(15));
+        assert_eq!(value("0x7fffffffffffffff"), Literal::Int(i64::MAX));
+    }
+
+    #[test]
+    fn malformed_radix_literals_are_errors() {
+        assert!(error("0x").contains("invalid base 16 literal"));
+        assert!(error("0b102").contains("invalid base 2 literal"));
+        assert!(error("0o8").contains("invalid base 8 literal"));
+        assert!(error("0x1g").contains("invalid numeric literal"));
+        assert!(error("0x8000000000000000").contains("integer literal too large"));
+    }
+
+    #[test]
+    fn underscores_separate_digits() {
+        assert_eq!(value("1_000_000"), Literal::Int(1_000_000));
+        assert_eq!(value("0xFF_FF"), Literal::Int(0xFFFF));
+        assert_eq!(value("0b1010_0101"), Literal::Int(0b1010_0101));
+        assert_eq!(value("1_0.2_5"), Literal::Float(OrderedFloat(10.25)));
+    }
+
+    #[test]
+    fn underscore_must_sit_between_digits() {
+        for src in ["1_", "1__0", "0x_1", "1_.5", "0b1_2"] {
+            assert!(error(src).contains("Syntax error"), "{} was accepted", src);
+        }
+    }
+
+    #[test]
+    fn operator_pairs_merge_only_when_adjacent() {
+        use TokenType::*;
+        let cases = [
+            ("a == b", vec![COMPEQ], "a = = b", vec![EQ, EQ]),
+            ("a != b", vec![COMPNE], "a ! = b", vec![LOGNOT, EQ]),
+            ("a <= b", vec![COMPLE], "a < = b", vec![COMPLT, EQ]),
+            ("a >= b", vec![COMPGE], "a > = b", vec![COMPGT, EQ]),
+            ("a && b", vec![COMPAND], "a & & b", vec![LOGAND, LOGAND]),
+            ("a || b", vec![COMPOR], "a | | b", vec![LOGOR, LOGOR]),
+            ("a << b", vec![SHL], "a < < b", vec![COMPLT, COMPLT]),
+            ("a >> b", vec![SHR], "a > > b", vec![COMPGT, COMPGT]),
+            ("a += b", vec![ADDEQ], "a + = b", vec![ADD, EQ]),
+            ("a -= b", vec![SUBEQ], "a - = b", vec![SUB, EQ]),
+            ("a <<= b", vec![SHLEQ], "a << = b", vec![SHL, EQ]),
+        ];
+        for (adjacent, merged, spaced, split) in cases {
+            let around = |ops: Vec<TokenType>| [vec![IDENT], ops, vec![IDENT]].concat();
+            assert_eq!(tokens(adjacent), around(merged), "{}", adjacent);
+            assert_eq!(tokens(spaced), around(split), "{}", spaced);
+        }
+    }
+
+    #[test]
+    fn doubled_signs_are_never_increments() {
+        use TokenType::*;
+        // A sign after an operator is a prefix: `-` negates, `+` is dropped.
+        assert_eq!(tokens("a - - b"), [IDENT, SUB, NEG, IDENT]);
+        assert_eq!(tokens("a -- b"), [IDENT, SUB, NEG, IDENT]);
+        assert_eq!(tokens("a + + b"), [IDENT, ADD, IDENT]);
+        assert_eq!(tokens("a ++ b"), [IDENT, ADD, IDENT]);
+        assert_eq!(tokens("--a"), [NEG, NEG, IDENT]);
+        assert_eq!(tokens("a * * b"), [IDENT, MUL, MUL, IDENT]);
+    }
+
+    #[test]
+    fn character_literals_are_their_codes() {
+        assert_eq!(value("'A'"), Literal::Int(65));
+        assert_eq!(value("'\\n'"), Literal::Int(10));
+        assert_eq!(value("'\\0'"), Literal::Int(0));
+        assert_eq!(value("'\\\\'"), Literal::Int(92));
+    }
+
+    #[test]
+    fn character_literals_hold_exactly_one_character() {
+        assert_eq!(error("''"), "Syntax error at 1:1: empty character literal");
+        assert_eq!(
+            error("'ab'"),
+            "Syntax error at 1:1: character literal must hold exactly one character"
+        );
+    }
+
+    #[test]
+    fn character_literals_record_the_end_column_like_other_tokens() {
+        let cols = |src| {
+            Lexer::new(src)
+                .map(|tok| tok.unwrap().col)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(cols("x = 'A'"), cols("x = abc"));
+    }
+
+    #[test]
+    fn unterminated_string_is_an_error() {
+        assert_eq!(
+            error("let s: str = \"abc"),
+            "Syntax error at 1:14: unterminated string literal"
+        );
+    }
+
+    #[test]
+    fn peeking_does_not_advance() {
+        let mut lexer = Lexer::new("a : 1");
+        lexer.next_token().unwrap();
+        assert_eq!(lexer.peek_token().unwrap().token, TokenType::COLON);
+        assert_eq!(lexer.peek_token().unwrap().token, TokenType::COLON);
+        assert_eq!(lexer.curr_tok().token, TokenType::IDENT);
+        lexer.next_token().unwrap();
+        assert_eq!(lexer.curr_tok().token, TokenType::COLON);
+        assert_eq!(lexer.peek_token().unwrap().value, Some(Literal::Int(1)));
+        lexer.next_token().unwrap();
+        assert_eq!(lexer.peek_token().unwrap().token, TokenType::EOF);
+        assert_eq!(lexer.curr_tok().value, Some(Literal::Int(1)));
+    }
+
+    #[test]
+    fn peeking_an_error_leaves_the_current_token() {
+        let mut lexer = Lexer::new("a 0x");
+        lexer.next_token().unwrap();
+        assert!(lexer.peek_token().is_err());
+        assert_eq!(lexer.curr_tok().token, TokenType::IDENT);
+        assert!(lexer.next_token().is_err());
+    }
+
+    #[test]
+    fn multi_line_lists_with_comments_and_crlf() {
+        use TokenType::*;
+        let src = "[\r\n  1, # one\r\n  2,\r\n  3 # three\r\n]\r\nf(\r\n  a, # first\r\n  b\r\n)";
+        assert_eq!(
+            tokens(src),
+            [
+                LBRACKET,
+                LITERAL(VarType::Int),
+                COMMA,
+                LITERAL(VarType::Int),
+                COMMA,
+                LITERAL(VarType::Int),
+                RBRACKET,
+                IDENT,
+                LPAREN,
+                IDENT,
+                COMMA,
+                IDENT,
+                RPAREN,
+            ]
+        );
+        assert_eq!(rows(Lexer::new(src)).last(), Some(&9));
+    }
+
+    #[test]
+    fn number_running_into_a_name_is_an_error() {
+        assert_eq!(
+            error("x = 1abc"),
+            "Syntax error at 1:5: invalid numeric literal"
+        );
+        assert!(error("2.5e").contains("invalid numeric literal"));
+        assert_eq!(tokens("1 abc").len(), 2);
+    }
+}
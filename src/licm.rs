@@ -0,0 +1,233 @@
+//! Loop-invariant code motion, run at `-O2`.
+//!
+//! A loop is the span from a label to the last jump back to it. An
+//! instruction in that span moves in front of the label when it computes the
+//! same value on every iteration:
+//!
+//! - it has no side effect and cannot trap, so running it when the loop body
+//!   would not have run is harmless;
+//! - it is the only definition of its temporary;
+//! - each operand is a constant, a temporary defined outside the loop or
+//!   moved out already, or a variable the loop never stores to.
+//!
+//! Array literals are never moved, since each evaluation allocates a fresh
+//! array. A loop that can be entered other than by falling into its label is
+//! left alone, as hoisted code would not run ahead of it.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::ir::{IRConst, IRProgram, Instruction, Op, Operand};
+
+pub fn hoist_invariants(program: &mut IRProgram) {
+    for func in program.functions.iter_mut() {
+        if !func.is_external {
+            while hoist_loop(&mut func.instructions, &program.constants) {}
+        }
+    }
+}
+
+/// The label an instruction jumps to, if any.
+fn target(inst: &Instruction) -> Option<&str> {
+    if matches!(inst.op, Op::Label(_)) {
+        return None;
+    }
+    [&inst.dst, &inst.src1, &inst.src2]
+        .into_iter()
+        .flatten()
+        .find_map(|op| match op {
+            Operand::Label(name) => Some(name.as_str()),
+            _ => None,
+        })
+}
+
+fn is_pure(op: &Op) -> bool {
+    matches!(
+        op,
+        Op::Add
+            | Op::Sub
+            | Op::Mul
+            | Op::And
+            | Op::Or
+            | Op::Xor
+            | Op::Shl
+            | Op::Shr
+            | Op::Eq
+            | Op::Ne
+            | Op::Gt
+            | Op::Ge
+            | Op::Lt
+            | Op::Le
+            | Op::LAnd
+            | Op::LOr
+            | Op::Not
+            | Op::Neg
+            | Op::FAdd
+            | Op::FSub
+            | Op::FMul
+            | Op::FDiv
+            | Op::FEq
+            | Op::FNe
+            | Op::FGt
+            | Op::FGe
+            | Op::FLt
+            | Op::FLe
+            | Op::FNeg
+            | Op::Move
+            | Op::FMove
+            | Op::Load
+            | Op::FLoad
+            | Op::SizeOf
+    )
+}
+
+/// Hoists the invariant instructions out of the innermost loop that has
+/// any, and reports whether there was one.
+fn hoist_loop(insts: &mut Vec<Instruction>, constants: &[IRConst]) -> bool {
+    let labels: HashMap<&str, usize> = insts
+        .iter()
+        .enumerate()
+        .filter_map(|(i, inst)| match &inst.op {
+            Op::Label(name) => Some((name.as_str(), i)),
+            _ => None,
+        })
+        .collect();
+    let mut loops: Vec<(usize, usize)> = Vec::new();
+    for (i, inst) in insts.iter().enumerate() {
+        match target(inst).and_then(|name| labels.get(name)) {
+            Some(&head) if head < i => match loops.iter_mut().find(|(h, _)| *h == head) {
+                Some(found) => found.1 = i,
+                None => loops.push((head, i)),
+            },
+            _ => {}
+        }
+    }
+    loops.sort_by_key(|(head, end)| end - head);
+
+    let mut defs: HashMap<usize, usize> = HashMap::new();
+    for inst in insts.iter() {
+        if let Some(Operand::Temp(id, _)) = &inst.dst {
+            *defs.entry(*id).or_default() += 1;
+        }
+    }
+
+    for (head, end) in loops {
+        let body = head..=end;
+        let entered = insts.iter().enumerate().any(|(i, inst)| {
+            !body.contains(&i)
+                && target(inst)
+                    .and_then(|name| labels.get(name))
+                    .is_some_and(|t| body.contains(t))
+        });
+        if entered {
+            continue;
+        }
+
+        let mut stored = HashSet::new();
+        let mut defined = HashSet::new();
+        for inst in &insts[body.clone()] {
+            match &inst.dst {
+                Some(Operand::Var(name)) => {
+                    stored.insert(name.clone());
+                }
+                Some(Operand::Temp(id, _)) => {
+                    defined.insert(*id);
+                }
+                _ => {}
+            }
+        }
+
+        let mut invariant = HashSet::new();
+        let mut hoisted = Vec::new();
+        for (i, inst) in insts.iter().enumerate().take(end + 1).skip(head + 1) {
+            let Some(Operand::Temp(id, _)) = &inst.dst else {
+                continue;
+            };
+            if !is_pure(&inst.op) || defs.get(id) != Some(&1) {
+                continue;
+            }
+            let fixed = |op: &Operand| match op {
+                Operand::Temp(t, _) => !defined.contains(t) || invariant.contains(t),
+                Operand::Var(name) => !stored.contains(name),
+                Operand::ConstIdx(idx) => !matches!(constants[*idx], IRConst::Array(..)),
+                Operand::Const(c) => !matches!(c, IRConst::Array(..)),
+                _ => false,
+            };
+            if [&inst.src1, &inst.src2].into_iter().flatten().all(fixed) {
+                invariant.insert(*id);
+                hoisted.push(i);
+            }
+        }
+        if hoisted.is_empty() {
+            continue;
+        }
+
+        let mut moved: Vec<Instruction> = hoisted.iter().rev().map(|&i| insts.remove(i)).collect();
+        moved.reverse();
+        insts.splice(head..head, moved);
+        return true;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{irgen::IRGen, lexer::Lexer, parser::Parser};
+
+    /// The instructions of `f` in `src`, after hoisting.
+    fn hoisted(src: &str) -> Vec<Instruction> {
+        let ast = Parser::new(Lexer::new(src)).parse().unwrap();
+        let mut program = IRGen::new().compile(ast).unwrap();
+        hoist_invariants(&mut program);
+        let f = program
+            .functions
+            .into_iter()
+            .find(|f| f.name == "f")
+            .unwrap();
+        f.instructions
+    }
+
+    fn loop_head(insts: &[Instruction]) -> usize {
+        insts
+            .iter()
+            .position(|inst| matches!(&inst.op, Op::Label(name) if name.contains("while_start")))
+            .unwrap()
+    }
+
+    fn position(insts: &[Instruction], op: Op) -> usize {
+        insts.iter().position(|inst| inst.op == op).unwrap()
+    }
+
+    #[test]
+    fn invariant_computation_moves_before_the_loop() {
+        let insts = hoisted(
+            "fun f(a: int, b: int): int {
+                let s: int = 0
+                let i: int = 0
+                while i < 10 {
+                    s += a * b
+                    i += 1
+                }
+                return s
+            }",
+        );
+        assert!(position(&insts, Op::Mul) < loop_head(&insts));
+        assert!(position(&insts, Op::Add) > loop_head(&insts));
+    }
+
+    #[test]
+    fn computation_on_a_variable_the_loop_stores_stays() {
+        let insts = hoisted(
+            "fun f(a: int): int {
+                let s: int = 0
+                let i: int = 0
+                while i < 10 {
+                    s += i * a
+                    i += 1
+                }
+                return s
+            }",
+        );
+        assert!(position(&insts, Op::Mul) > loop_head(&insts));
+    }
+}
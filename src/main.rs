@@ -1,99 +1,293 @@
-#![allow(warnings)]
 use crate::codegen::CodeGen;
 use crate::irgen::IRGen;
 use crate::{lexer::Lexer, parser::Parser, preprocessor::Preprocessor};
 use clap::{Arg, ArgAction, Command};
-use std::{fs, path::Path};
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
 
 pub mod ast;
 pub mod codegen;
+pub mod escape;
 pub mod ir;
 pub mod irgen;
 pub mod lexer;
+pub mod licm;
 pub mod parser;
 pub mod preprocessor;
+pub mod simplify;
 pub mod token;
 
-fn print_ast(file: &String) -> Result<(), Box<dyn std::error::Error>> {
-    let src = fs::read_to_string(file)?;
-    let path = Path::new(&file.clone())
-        .parent()
-        .ok_or("Invalid file path")?
-        .to_str()
-        .ok_or("Invalid path encoding")?
-        .to_string();
-    let mut preprocessor = Preprocessor::new(src.as_str(), path);
+struct Source {
+    code: String,
+    path: String,
+    /// Name of the file within `path`, for `__FILE__`.
+    file: String,
+    stem: String,
+}
+
+impl Source {
+    fn from_file(file: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let code = fs::read_to_string(file)?;
+        let input_path = Path::new(file);
+        let path = input_path
+            .parent()
+            .ok_or("Invalid file path")?
+            .to_str()
+            .ok_or("Invalid path encoding")?
+            .to_string();
+        let file = input_path
+            .file_name()
+            .ok_or("Invalid input filename")?
+            .to_str()
+            .ok_or("Invalid filename encoding")?
+            .to_string();
+        let stem = input_path
+            .file_stem()
+            .ok_or("Invalid input filename")?
+            .to_str()
+            .ok_or("Invalid filename encoding")?
+            .to_string();
+        Ok(Self {
+            code,
+            path,
+            file,
+            stem,
+        })
+    }
+
+    fn inline(code: &str) -> Self {
+        Self {
+            code: code.to_string(),
+            path: ".".to_string(),
+            file: "<inline>".to_string(),
+            stem: "a".to_string(),
+        }
+    }
+}
+
+fn print_ast(source: &Source) -> Result<(), Box<dyn std::error::Error>> {
+    let mut preprocessor =
+        Preprocessor::new(&source.code, source.path.clone(), source.file.clone());
     let code = preprocessor.preprocess()?;
-    let lexer = Lexer::new(code.as_str());
+    let lexer = Lexer::preprocessed(code.as_str());
     let mut parser = Parser::new(lexer);
     let ast = parser.parse()?;
     println!("{:#?}", ast);
     Ok(())
 }
 
-fn print_ir(file: &String) -> Result<(), Box<dyn std::error::Error>> {
-    let src = fs::read_to_string(file)?;
-    let path = Path::new(&file.clone())
-        .parent()
-        .ok_or("Invalid file path")?
-        .to_str()
-        .ok_or("Invalid path encoding")?
-        .to_string();
-    let mut preprocessor = Preprocessor::new(src.as_str(), path);
+fn print_ir(source: &Source, opt_level: u8) -> Result<(), Box<dyn std::error::Error>> {
+    let mut preprocessor =
+        Preprocessor::new(&source.code, source.path.clone(), source.file.clone());
     let code = preprocessor.preprocess()?;
-    let lexer = Lexer::new(code.as_str());
+    let lexer = Lexer::preprocessed(code.as_str());
     let mut parser = Parser::new(lexer);
     let ast = parser.parse()?;
     let mut irgen = IRGen::new();
-    let ir = irgen.compile(ast)?;
+    let mut ir = irgen.compile(ast)?;
+    if opt_level >= 2 {
+        licm::hoist_invariants(&mut ir);
+    }
     println!("{:#?}", ir);
     Ok(())
 }
 
-fn print_pred(file: &String) -> Result<(), Box<dyn std::error::Error>> {
-    let src = fs::read_to_string(file)?;
-    let path = Path::new(&file.clone())
-        .parent()
-        .ok_or("Invalid file path")?
-        .to_str()
-        .ok_or("Invalid path encoding")?
-        .to_string();
-    let mut preprocessor = Preprocessor::new(src.as_str(), path);
+fn print_pred(source: &Source) -> Result<(), Box<dyn std::error::Error>> {
+    let mut preprocessor =
+        Preprocessor::new(&source.code, source.path.clone(), source.file.clone());
     let code = preprocessor.preprocess()?;
     println!("{}", code);
     Ok(())
 }
 
+fn print_defines(source: &Source) -> Result<(), Box<dyn std::error::Error>> {
+    print!("{}", dump_defines(source)?);
+    Ok(())
+}
+
+/// The defines left after preprocessing `source`, one `$define` per line,
+/// sorted by name.
+fn dump_defines(source: &Source) -> Result<String, Box<dyn std::error::Error>> {
+    let mut preprocessor =
+        Preprocessor::new(&source.code, source.path.clone(), source.file.clone());
+    preprocessor.preprocess()?;
+    let mut defines: Vec<_> = preprocessor.defines().iter().collect();
+    defines.sort();
+    Ok(defines
+        .into_iter()
+        .map(|(name, value)| format!("$define {} {}\n", name, value))
+        .collect())
+}
+
+/// Wall-clock time spent in each compiler pass, reported by `--time-passes`.
+struct PassTimes {
+    enabled: bool,
+    passes: Vec<(&'static str, Duration)>,
+}
+
+impl PassTimes {
+    fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            passes: Vec::new(),
+        }
+    }
+
+    fn run<T>(&mut self, pass: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.passes.push((pass, start.elapsed()));
+        result
+    }
+
+    fn report(&self) {
+        if !self.enabled || self.passes.is_empty() {
+            return;
+        }
+        eprint!("{}", self.table());
+    }
+
+    fn table(&self) -> String {
+        let total: Duration = self.passes.iter().map(|(_, time)| *time).sum();
+        let mut table = format!("{:<12} {:>12}\n", "pass", "time (ms)");
+        for (pass, time) in self.passes.iter().chain([&("total", total)]) {
+            table += &format!("{:<12} {:>12.3}\n", pass, time.as_secs_f64() * 1000.0);
+        }
+        table
+    }
+}
+
+/// Runs the lexer over `code` on its own. The parser pulls tokens as it goes,
+/// so this is the only way to see how long lexing takes.
+fn tokenize(code: &str) -> Result<(), Box<dyn std::error::Error>> {
+    for token in Lexer::preprocessed(code) {
+        token?;
+    }
+    Ok(())
+}
+
+const CACHE_DIR: &str = ".alum-cache";
+
+/// The standard library linked into executables.
+const STD_LIB: &str = "/usr/local/lib/libalum.a";
+
+/// Compiles preprocessed `code` to assembly, checking for a usable `main`
+/// when `check_entry` is set.
+fn generate(
+    code: &str,
+    check_entry: bool,
+    opt_level: u8,
+    times: &mut PassTimes,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if times.enabled {
+        times.run("lex", || tokenize(code))?;
+    }
+    let ast = times.run("parse", || Parser::new(Lexer::preprocessed(code)).parse())?;
+    let mut irgen = IRGen::new();
+    let mut ir = times.run("irgen", || irgen.compile(ast))?;
+    if check_entry {
+        irgen.check_entry(&ir)?;
+    }
+    if opt_level >= 2 {
+        times.run("licm", || licm::hoist_invariants(&mut ir));
+    }
+    Ok(times.run("codegen", || CodeGen::new(ir).compile())?)
+}
+
+/// 64-bit FNV-1a over `parts`, each prefixed by its length. Unlike
+/// `DefaultHasher`, its output is fixed, so it can name files that outlive
+/// one build of the compiler.
+fn fnv1a(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for byte in (part.len() as u64).to_le_bytes().iter().chain(*part) {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+/// Identifies this build of the compiler by the size and modification time
+/// of its executable, as a rebuild can keep the version number.
+fn compiler_build() -> String {
+    let Some(meta) = std::env::current_exe().and_then(fs::metadata).ok() else {
+        return String::new();
+    };
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!("{}:{}", meta.len(), modified.as_nanos())
+}
+
+/// Names the assembly that compiler `build` makes from the preprocessed
+/// `code` with these options.
+fn cache_key(code: &str, check_entry: bool, opt_level: u8, build: &str) -> u64 {
+    fnv1a(&[
+        env!("CARGO_PKG_VERSION").as_bytes(),
+        build.as_bytes(),
+        &[check_entry as u8, opt_level],
+        code.as_bytes(),
+    ])
+}
+
+/// Like `generate`, but reuses the assembly in `cache` from an earlier run
+/// when it was made by this build of the compiler from the same preprocessed
+/// source. Imports are already inlined by then, so a change to any imported
+/// file misses too.
+fn generate_cached(
+    cache: &Path,
+    code: &str,
+    check_entry: bool,
+    opt_level: u8,
+    times: &mut PassTimes,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let key = cache_key(code, check_entry, opt_level, &compiler_build());
+    let entry = cache.join(format!("{:016x}.s", key));
+
+    if let Ok(assembly) = fs::read_to_string(&entry) {
+        return Ok(assembly);
+    }
+    let assembly = generate(code, check_entry, opt_level, times)?;
+    if fs::create_dir_all(cache).is_ok() {
+        let _ = fs::write(&entry, &assembly);
+    }
+    Ok(assembly)
+}
+
 fn compile(
-    input_file: &str,
+    source: &Source,
     output_file: Option<&str>,
     emit_type: &str,
     no_std: bool,
+    opt_level: u8,
+    use_cache: bool,
+    time_passes: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let src = fs::read_to_string(input_file)?;
-    let path = Path::new(&input_file)
-        .parent()
-        .ok_or("Invalid file path")?
-        .to_str()
-        .ok_or("Invalid path encoding")?
-        .to_string();
-    let mut preprocessor = Preprocessor::new(&src, path);
-    let code = preprocessor.preprocess()?;
-    let lexer = Lexer::new(&code);
-    let mut parser = Parser::new(lexer);
-    let ast = parser.parse()?;
-    let mut irgen = IRGen::new();
-    let ir = irgen.compile(ast)?;
-    let mut codegen = CodeGen::new(ir);
-    let assembly = codegen.compile()?;
-
-    let input_path = Path::new(input_file);
-    let stem = input_path
-        .file_stem()
-        .ok_or("Invalid input filename")?
-        .to_str()
-        .ok_or("Invalid filename encoding")?;
+    let mut times = PassTimes::new(time_passes);
+    let mut preprocessor =
+        Preprocessor::new(&source.code, source.path.clone(), source.file.clone());
+    let code = times.run("preprocess", || preprocessor.preprocess())?;
+    let check_entry = emit_type == "bin" && !no_std;
+    let assembly = if use_cache {
+        generate_cached(
+            Path::new(CACHE_DIR),
+            &code,
+            check_entry,
+            opt_level,
+            &mut times,
+        )?
+    } else {
+        generate(&code, check_entry, opt_level, &mut times)?
+    };
+    times.report();
 
+    let stem = source.stem.as_str();
     let output = if let Some(output_path) = output_file {
         output_path.to_string()
     } else {
@@ -114,7 +308,7 @@ fn compile(
             fs::write(&asm_file, &assembly)?;
 
             let nasm_status = std::process::Command::new("nasm")
-                .args(&["-f", "elf64", "-o", &output, &asm_file])
+                .args(["-f", "elf64", "-o", &output, &asm_file])
                 .status()?;
 
             if !nasm_status.success() {
@@ -131,7 +325,7 @@ fn compile(
             fs::write(&asm_file, &assembly)?;
 
             let nasm_status = std::process::Command::new("nasm")
-                .args(&["-f", "elf64", "-o", &obj_file, &asm_file])
+                .args(["-f", "elf64", "-o", &obj_file, &asm_file])
                 .status()?;
 
             if !nasm_status.success() {
@@ -141,7 +335,7 @@ fn compile(
 
             let mut ld_args = vec!["-o", &output, &obj_file];
             if !no_std {
-                ld_args.push("/usr/local/lib/libalum.a");
+                ld_args.push(STD_LIB);
             }
 
             let ld_status = std::process::Command::new("ld").args(&ld_args).status()?;
@@ -166,9 +360,15 @@ fn main() {
         .arg(
             Arg::new("input_files")
                 .help("Input source files")
-                .required(true)
+                .required_unless_present("eval")
                 .num_args(1..),
         )
+        .arg(
+            Arg::new("eval")
+                .short('e')
+                .help("Compile <source> instead of reading an input file")
+                .value_name("source"),
+        )
         .arg(
             Arg::new("output")
                 .short('o')
@@ -194,6 +394,14 @@ fn main() {
                 .help("Compile and assemble, but do not link")
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("opt_level")
+                .short('O')
+                .help("Optimization level; 2 also hoists loop-invariant code")
+                .value_name("level")
+                .value_parser(clap::value_parser!(u8).range(0..=2))
+                .default_value("1"),
+        )
         .arg(
             Arg::new("dump_ast")
                 .long("dump-ast")
@@ -206,12 +414,30 @@ fn main() {
                 .help("Dump IR representation")
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("dump_defines")
+                .long("dump-defines")
+                .help("Dump the defines left after preprocessing")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("nostdlib")
                 .long("nostdlib")
                 .help("Do not link with standard library")
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("cache")
+                .long("cache")
+                .help("Reuse assembly cached in .alum-cache for unchanged sources")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("time_passes")
+                .long("time-passes")
+                .help("Report the time spent in each compiler pass")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("verbose")
                 .short('v')
@@ -222,13 +448,20 @@ fn main() {
 
     let matches = cmd.get_matches();
 
-    let input_files: Vec<&String> = matches.get_many("input_files").unwrap().collect();
-
-    let input_file = input_files[0];
+    let input_file = match matches.get_one::<String>("eval") {
+        Some(_) => "<inline>",
+        None => {
+            let input_files: Vec<&String> = matches.get_many("input_files").unwrap().collect();
+            input_files[0].as_str()
+        }
+    };
     let output_file = matches.get_one::<String>("output").map(|s| s.as_str());
 
     let verbose = matches.get_flag("verbose");
     let no_std = matches.get_flag("nostdlib");
+    let use_cache = matches.get_flag("cache");
+    let time_passes = matches.get_flag("time_passes");
+    let opt_level = *matches.get_one::<u8>("opt_level").unwrap();
 
     if verbose {
         eprintln!("Alum compiler v0.5.2");
@@ -238,22 +471,607 @@ fn main() {
         }
     }
 
-    let result = if matches.get_flag("dump_ast") {
-        print_ast(input_file)
-    } else if matches.get_flag("dump_ir") {
-        print_ir(input_file)
-    } else if matches.get_flag("preprocess") {
-        print_pred(input_file)
-    } else if matches.get_flag("assemble") {
-        compile(input_file, output_file, "asm", no_std)
-    } else if matches.get_flag("compile") {
-        compile(input_file, output_file, "obj", no_std)
-    } else {
-        compile(input_file, output_file, "bin", no_std)
+    let source = match matches.get_one::<String>("eval") {
+        Some(code) => Ok(Source::inline(code)),
+        None => Source::from_file(input_file),
     };
 
+    let result = source.and_then(|source| {
+        if matches.get_flag("dump_ast") {
+            print_ast(&source)
+        } else if matches.get_flag("dump_ir") {
+            print_ir(&source, opt_level)
+        } else if matches.get_flag("dump_defines") {
+            print_defines(&source)
+        } else if matches.get_flag("preprocess") {
+            print_pred(&source)
+        } else if matches.get_flag("assemble") {
+            compile(
+                &source,
+                output_file,
+                "asm",
+                no_std,
+                opt_level,
+                use_cache,
+                time_passes,
+            )
+        } else if matches.get_flag("compile") {
+            compile(
+                &source,
+                output_file,
+                "obj",
+                no_std,
+                opt_level,
+                use_cache,
+                time_passes,
+            )
+        } else {
+            compile(
+                &source,
+                output_file,
+                "bin",
+                no_std,
+                opt_level,
+                use_cache,
+                time_passes,
+            )
+        }
+    });
+
     if let Err(e) = result {
         eprintln!("{}", e);
         std::process::exit(1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::process::{Command, Output, Stdio};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A fresh directory for one test's files.
+    fn scratch() -> PathBuf {
+        static DIRS: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "al-main-{}-{}",
+            std::process::id(),
+            DIRS.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Whether executables can be assembled and linked here. Tests that run
+    /// compiled programs pass without doing anything when they cannot.
+    fn toolchain() -> bool {
+        Command::new("nasm").arg("-v").output().is_ok() && Path::new(STD_LIB).exists()
+    }
+
+    /// Builds `source` into an executable and returns its path.
+    fn build(source: &Source) -> PathBuf {
+        let exe = scratch().join(&source.stem);
+        compile(source, exe.to_str(), "bin", false, 1, false, false).unwrap();
+        exe
+    }
+
+    fn run(source: &Source, args: &[&str]) -> Output {
+        Command::new(build(source)).args(args).output().unwrap()
+    }
+
+    /// Compiles `alum` to an object, links it with the C program `c` and runs
+    /// the result, or returns `None` when there is no C compiler.
+    fn run_with_c(alum: &str, c: &str) -> Option<Output> {
+        if !toolchain() || Command::new("cc").arg("--version").output().is_err() {
+            return None;
+        }
+        let dir = scratch();
+        let obj = dir.join("alum.o");
+        compile(
+            &Source::inline(alum),
+            obj.to_str(),
+            "obj",
+            false,
+            1,
+            false,
+            false,
+        )
+        .unwrap();
+        let driver = dir.join("driver.c");
+        fs::write(&driver, c).unwrap();
+        let exe = dir.join("driver");
+        let status = Command::new("cc")
+            .arg("-o")
+            .args([&exe, &driver, &obj])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        Some(Command::new(exe).output().unwrap())
+    }
+
+    fn stdout(output: &Output) -> String {
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+
+    #[test]
+    fn inline_source_runs() {
+        if !toolchain() {
+            return;
+        }
+        let output = run(&Source::inline("pub fun main(): int { return 7 }"), &[]);
+        assert_eq!(output.status.code(), Some(7));
+    }
+
+    #[test]
+    fn main_is_only_required_for_executables() {
+        let library = "pub fun helper(): int { return 1 }";
+        assert!(generate(library, false, 1, &mut PassTimes::new(false)).is_ok());
+        let err = generate(library, true, 1, &mut PassTimes::new(false)).unwrap_err();
+        assert!(err.to_string().contains("`main`"), "{}", err);
+    }
+
+    #[test]
+    fn main_receives_its_arguments() {
+        if !toolchain() {
+            return;
+        }
+        let output = run(
+            &Source::inline(
+                "extern println(int): int
+                pub fun main(argc: int, argv: arr<_>): int {
+                    println(argv[1])
+                    return argc
+                }",
+            ),
+            &["hello"],
+        );
+        assert_eq!(stdout(&output), "hello\n");
+        assert_eq!(output.status.code(), Some(2));
+    }
+
+    #[test]
+    fn getenv_reads_the_environment() {
+        if !toolchain() {
+            return;
+        }
+        let exe = build(&Source::inline(
+            "$import \"io\"
+            $import \"env\"
+            pub fun main(): int {
+                println(getenv(\"ALUM_TEST_VAR\"))
+                return 0
+            }",
+        ));
+        let output = Command::new(exe)
+            .env("ALUM_TEST_VAR", "found")
+            .output()
+            .unwrap();
+        assert_eq!(stdout(&output), "found\n");
+    }
+
+    #[test]
+    fn break_exits_and_continue_skips() {
+        if !toolchain() {
+            return;
+        }
+        // Sums the odd numbers below 7: 1 + 3 + 5.
+        let output = run(
+            &Source::inline(
+                "pub fun main(): int {
+                    let sum: int = 0
+                    let i: int = 0
+                    while i < 100 {
+                        i += 1
+                        if i == 7 { break }
+                        if i % 2 == 0 { continue }
+                        sum += i
+                    }
+                    return sum
+                }",
+            ),
+            &[],
+        );
+        assert_eq!(output.status.code(), Some(9));
+    }
+
+    #[test]
+    fn long_input_lines_are_truncated() {
+        if !toolchain() {
+            return;
+        }
+        let exe = build(&Source::inline(
+            "$import \"io\"
+            $import \"convert\"
+            pub fun main(): int {
+                println(input(\"\"))
+                let buf: arr<16, byte> = [0]
+                println(itoa(readline(buf, sizeof buf)))
+                return 0
+            }",
+        ));
+        let mut child = Command::new(exe)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let line = |c: &str| c.repeat(2000) + "\n";
+        let mut stdin = child.stdin.take().unwrap();
+        stdin
+            .write_all((line("a") + &line("b")).as_bytes())
+            .unwrap();
+        drop(stdin);
+        let output = child.wait_with_output().unwrap();
+        assert_eq!(stdout(&output), "a".repeat(1023) + "\n-15\n");
+    }
+
+    #[test]
+    fn sleep_ms_delays() {
+        if !toolchain() {
+            return;
+        }
+        let exe = build(&Source::inline(
+            "$import \"time\"
+            pub fun main(): int {
+                sleep_ms(50)
+                return 0
+            }",
+        ));
+        let start = std::time::Instant::now();
+        let status = Command::new(exe).status().unwrap();
+        assert!(status.success());
+        assert!(start.elapsed() >= std::time::Duration::from_millis(50));
+    }
+
+    #[test]
+    fn byte_array_is_a_buffer_for_write() {
+        if !toolchain() {
+            return;
+        }
+        let output = run(
+            &Source::inline(
+                "$import \"io\"
+                pub fun main(): int {
+                    let buf: arr<3, byte> = ['H', 'i', '\\n']
+                    write(1, buf, sizeof buf)
+                    return 0
+                }",
+            ),
+            &[],
+        );
+        assert_eq!(stdout(&output), "Hi\n");
+    }
+
+    #[test]
+    fn byte_and_int_arrays_read_back_their_elements() {
+        if !toolchain() {
+            return;
+        }
+        let output = run(
+            &Source::inline(
+                "pub fun main(): int {
+                    let b: arr<4, byte> = [1, 2, 3, 200]
+                    let a: arr<4> = [1000, 2000, 3000, 4000]
+                    let i: int = 3
+                    return b[i] - b[1] + a[i] / 1000 - a[2] / 1000
+                }",
+            ),
+            &[],
+        );
+        assert_eq!(output.status.code(), Some(199));
+    }
+
+    #[test]
+    fn logical_and_bitwise_operators_differ() {
+        if !toolchain() {
+            return;
+        }
+        // 6 + 0 + 10 + 100: `5 & 2` is 0 but `5 && 2` is true.
+        let output = run(
+            &Source::inline(
+                "pub fun main(): int {
+                    let bits: int = (5 ^ 3) + (5 & 2)
+                    let both: int = if 5 && 2 { 10 } else { 0 }
+                    let either: int = if true ^ false { 100 } else { 0 }
+                    return bits + both + either
+                }",
+            ),
+            &[],
+        );
+        assert_eq!(output.status.code(), Some(116));
+    }
+
+    #[test]
+    fn skipped_declaration_reads_as_zero() {
+        if !toolchain() {
+            return;
+        }
+        // `dirty` leaves 42 in the stack slot that `skipped` reuses for `x`.
+        let output = run(
+            &Source::inline(
+                "fun dirty(): int {
+                    let y: int = 42
+                    return y
+                }
+                fun skipped(): int {
+                    goto done
+                    let x: int = 5
+                    done:
+                    return x
+                }
+                pub fun main(): int {
+                    dirty()
+                    return skipped()
+                }",
+            ),
+            &[],
+        );
+        assert_eq!(output.status.code(), Some(0));
+    }
+
+    #[test]
+    fn declared_variable_is_assigned_in_a_branch() {
+        if !toolchain() {
+            return;
+        }
+        let output = run(
+            &Source::inline(
+                "pub fun main(argc: int, argv: arr<_>): int {
+                    let x: int
+                    let y: int
+                    if argc > 1 { x = 3 }
+                    return x * 10 + y
+                }",
+            ),
+            &["arg"],
+        );
+        assert_eq!(output.status.code(), Some(30));
+    }
+
+    #[test]
+    fn dump_lists_the_remaining_defines() {
+        let source = Source::inline("$define B 2\n$define A 1\n$define GONE 0\n$undef GONE\nA + B");
+        assert_eq!(dump_defines(&source).unwrap(), "$define A 1\n$define B 2\n");
+    }
+
+    #[test]
+    fn cache_key_covers_the_source_options_and_compiler() {
+        let code = "pub fun main(): int { return 0 }";
+        let key = cache_key(code, true, 1, "build");
+        assert_eq!(key, cache_key(code, true, 1, "build"));
+        assert_ne!(
+            key,
+            cache_key("pub fun main(): int { return 1 }", true, 1, "build")
+        );
+        assert_ne!(key, cache_key(code, false, 1, "build"));
+        assert_ne!(key, cache_key(code, true, 2, "build"));
+        assert_ne!(key, cache_key(code, true, 1, "rebuilt"));
+    }
+
+    #[test]
+    fn unchanged_source_hits_the_cache() {
+        let cache = scratch();
+        let code = "pub fun main(): int { return 0 }";
+        let mut first = PassTimes::new(false);
+        let assembly = generate_cached(&cache, code, true, 1, &mut first).unwrap();
+        assert!(!first.passes.is_empty());
+        assert_eq!(fs::read_dir(&cache).unwrap().count(), 1);
+
+        let mut second = PassTimes::new(false);
+        let cached = generate_cached(&cache, code, true, 1, &mut second).unwrap();
+        assert!(second.passes.is_empty(), "{:?}", second.passes);
+        assert_eq!(cached, assembly);
+    }
+
+    #[test]
+    fn time_passes_reports_each_stage() {
+        let mut times = PassTimes::new(true);
+        generate("pub fun main(): int { return 0 }", true, 2, &mut times).unwrap();
+        let table = times.table();
+        let passes: Vec<&str> = table
+            .lines()
+            .map(|line| line.split_whitespace().next().unwrap())
+            .collect();
+        assert_eq!(
+            passes,
+            ["pass", "lex", "parse", "irgen", "licm", "codegen", "total"]
+        );
+    }
+
+    #[test]
+    fn interpolated_string_formats_int_and_str_holes() {
+        if !toolchain() {
+            return;
+        }
+        let output = run(
+            &Source::inline(
+                "$import \"io\"
+                pub fun main(): int {
+                    let name: str = \"Al\"
+                    let age: int = 3
+                    println(`{name} is {age}, next year {age + 1}`)
+                    return 0
+                }",
+            ),
+            &[],
+        );
+        assert_eq!(stdout(&output), "Al is 3, next year 4\n");
+    }
+
+    #[test]
+    fn object_links_with_a_c_main() {
+        let Some(output) = run_with_c(
+            "pub fun twice(x: int): int { return x * 2 }",
+            "long twice(long);\nint main(void) { return twice(21); }\n",
+        ) else {
+            return;
+        };
+        assert_eq!(output.status.code(), Some(42));
+    }
+
+    #[test]
+    fn c_calls_alum_functions_with_the_system_v_abi() {
+        let Some(output) = run_with_c(
+            "pub fun add(x: int, y: int): int { return x + y }
+            pub fun half(x: flt): flt { return x / 2.0 }
+            pub fun first(s: str): str { return s }",
+            "#include <stdio.h>
+            long add(long, long);
+            double half(double);
+            char *first(char *);
+            int main(void) {
+                printf(\"%ld %.2f %s\\n\", add(40, 2), half(5.0), first(\"ok\"));
+                return 0;
+            }",
+        ) else {
+            return;
+        };
+        assert_eq!(stdout(&output), "42 2.50 ok\n");
+    }
+
+    #[test]
+    fn mixed_integer_and_float_arguments_reach_c() {
+        let Some(output) = run_with_c(
+            "extern describe(int, flt, int, flt): int
+            pub fun call_describe(): int { return describe(1, 2.5, 3, 4.5) }",
+            "#include <stdio.h>
+            long call_describe(void);
+            long describe(long a, double x, long b, double y) {
+                printf(\"%ld %.1f %ld %.1f\\n\", a, x, b, y);
+                return 0;
+            }
+            int main(void) { return call_describe(); }",
+        ) else {
+            return;
+        };
+        assert_eq!(stdout(&output), "1 2.5 3 4.5\n");
+    }
+
+    #[test]
+    fn call_in_an_argument_keeps_the_arguments_before_it() {
+        if !toolchain() {
+            return;
+        }
+        let output = run(
+            &Source::inline(
+                "fun h(x: int): int { return x * 10 }
+                fun add(a: int, b: int): int { return a + b }
+                pub fun main(): int { return add(1, h(2)) }",
+            ),
+            &[],
+        );
+        assert_eq!(output.status.code(), Some(21));
+    }
+
+    #[test]
+    fn extern_variable_is_shared_with_c() {
+        let Some(output) = run_with_c(
+            "extern counter: int
+            pub fun bump(): int {
+                counter += 1
+                return counter
+            }",
+            "long counter = 41;
+            long bump(void);
+            int main(void) { return bump() == 42 && counter == 42 ? 0 : 1; }",
+        ) else {
+            return;
+        };
+        assert_eq!(output.status.code(), Some(0));
+    }
+
+    #[test]
+    fn comparison_result_has_no_stale_high_bits() {
+        if !toolchain() {
+            return;
+        }
+        // `rax` holds -1, all bits set, right before `x < 0` is computed.
+        let output = run(
+            &Source::inline(
+                "fun negative(x: int): bool { return x < 0 }
+                pub fun main(): int {
+                    let b: bool = negative(-1)
+                    if b == true { return 1 }
+                    return 2
+                }",
+            ),
+            &[],
+        );
+        assert_eq!(output.status.code(), Some(1));
+    }
+
+    #[test]
+    fn remainder_takes_the_sign_of_the_dividend() {
+        if !toolchain() {
+            return;
+        }
+        let output = run(
+            &Source::inline(
+                "pub fun main(): int {
+                    let x: int = 17
+                    let y: int = 5
+                    return x % y * 10 + (0 - x) % y
+                }",
+            ),
+            &[],
+        );
+        assert_eq!(output.status.code(), Some(18));
+    }
+
+    #[test]
+    fn if_expression_value_is_usable() {
+        if !toolchain() {
+            return;
+        }
+        let output = run(
+            &Source::inline(
+                "fun max(a: int, b: int): int {
+                    let m: int = if a > b { a } else { b }
+                    return m
+                }
+                pub fun main(): int { return max(3, 8) * 10 + max(7, 2) }",
+            ),
+            &[],
+        );
+        assert_eq!(output.status.code(), Some(87));
+    }
+
+    #[test]
+    fn bool_crosses_the_c_boundary_as_zero_or_one() {
+        let Some(output) = run_with_c(
+            "extern odd(int): bool
+            pub fun positive(x: int): bool { return x > 0 }
+            pub fun both_odd(a: int, b: int): bool { return odd(a) && odd(b) }",
+            "#include <stdbool.h>
+            long positive(long);
+            long both_odd(long, long);
+            bool odd(long x) { return x & 1; }
+            int main(void) {
+                return positive(5) == 1 && positive(-5) == 0
+                    && both_odd(3, 5) == 1 && both_odd(3, 4) == 0 ? 0 : 1;
+            }",
+        ) else {
+            return;
+        };
+        assert_eq!(output.status.code(), Some(0));
+    }
+
+    #[test]
+    fn constant_indices_reach_the_right_elements() {
+        if !toolchain() {
+            return;
+        }
+        let output = run(
+            &Source::inline(
+                "pub fun main(): int {
+                    let a: arr<4> = [10, 20, 30, 40]
+                    a[2] = a[3] + a[0]
+                    return a[2]
+                }",
+            ),
+            &[],
+        );
+        assert_eq!(output.status.code(), Some(50));
+    }
+}
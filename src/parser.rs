@@ -1,12 +1,14 @@
-use std::collections::HashMap;
+use std::mem::take;
+
+use ordered_float::OrderedFloat;
 
 use crate::{
     ast::{
-        ArrayAccess, ArrayAssign, BinOp, Expr, Extern, For, FuncCall, FuncDecl, Goto, If, Label,
-        Program, Return, Stmt, UnaryOp, Val, Var, VarDecl, VarMod, While,
+        ArrayAccess, ArrayAssign, BinOp, Expr, Extern, ExternVar, For, FuncCall, FuncDecl, Goto,
+        If, Interp, Label, Program, Return, Stmt, UnaryOp, Val, Var, VarDecl, VarMod, While,
     },
-    lexer::{Lexer, LexerError},
-    token::{Literal, Token, TokenType, VarType},
+    lexer::{Lexer, LexerError, unescape},
+    token::{Literal, TokenType, VarType},
 };
 
 #[derive(Debug, Clone)]
@@ -78,14 +80,20 @@ impl std::fmt::Display for ParserError {
 #[derive(Debug)]
 pub struct Parser<'a> {
     lexer: Lexer<'a>,
-    functions: HashMap<String, VarType>,
+    loop_labels: Vec<String>,
+    /// Labels of the loops already closed in the current function.
+    closed_loop_labels: Vec<String>,
+    /// Counts the hidden variables introduced by desugaring.
+    hidden: usize,
 }
 
 impl<'a> Parser<'a> {
     pub fn new(lexer: Lexer<'a>) -> Self {
         Self {
             lexer,
-            functions: HashMap::new(),
+            loop_labels: Vec::new(),
+            closed_loop_labels: Vec::new(),
+            hidden: 0,
         }
     }
 
@@ -106,18 +114,14 @@ impl<'a> Parser<'a> {
                 if self.lexer.curr_tok().token == TokenType::ELSE {
                     self.lexer.next_token()?;
                     let else_body = self.stmt()?;
-                    match cond.clone() {
-                        Expr::Val(val) => match val.value {
-                            Literal::Bool(b) => {
-                                if b {
-                                    return Ok(body);
-                                } else {
-                                    return Ok(else_body);
-                                }
-                            }
-                            _ => {}
-                        },
-                        _ => {}
+                    if let Expr::Val(val) = cond.clone()
+                        && let Literal::Bool(b) = val.value
+                    {
+                        if b {
+                            return Ok(body);
+                        } else {
+                            return Ok(else_body);
+                        }
                     }
                     return Ok(Expr::If(If {
                         condition: Box::new(cond),
@@ -125,18 +129,14 @@ impl<'a> Parser<'a> {
                         else_branch: Some(Box::new(else_body)),
                     }));
                 }
-                match cond.clone() {
-                    Expr::Val(val) => match val.value {
-                        Literal::Bool(b) => {
-                            if b {
-                                return Ok(body);
-                            } else {
-                                return Ok(Expr::Stmt(Stmt { body: vec![] }));
-                            }
-                        }
-                        _ => {}
-                    },
-                    _ => {}
+                if let Expr::Val(val) = cond.clone()
+                    && let Literal::Bool(b) = val.value
+                {
+                    if b {
+                        return Ok(body);
+                    } else {
+                        return Ok(Expr::Stmt(Stmt { body: vec![] }));
+                    }
                 }
                 Ok(Expr::If(If {
                     condition: Box::new(cond),
@@ -148,35 +148,30 @@ impl<'a> Parser<'a> {
                 self.lexer.next_token()?;
                 let cond = self.expr()?;
                 let body = self.stmt()?;
-                match cond.clone() {
-                    Expr::Val(val) => match val.value {
-                        Literal::Bool(b) => {
-                            if b {
-                                let lbl = format!("loop{:p}", &body);
-                                return Ok(Expr::Stmt(Stmt {
-                                    body: vec![
-                                        Expr::Label(Label { name: lbl.clone() }),
-                                        body,
-                                        Expr::Goto(Goto { label: lbl }),
-                                    ],
-                                }));
-                            } else {
-                                return Ok(Expr::Stmt(Stmt { body: vec![] }));
-                            }
-                        }
-                        _ => {}
-                    },
-                    _ => {}
+                if let Expr::Val(Val {
+                    value: Literal::Bool(false),
+                    ..
+                }) = cond
+                {
+                    return Ok(Expr::Stmt(Stmt { body: vec![] }));
                 }
                 Ok(Expr::While(While {
+                    label: None,
                     condition: Box::new(cond),
                     body: Box::new(body),
                 }))
             }
             TokenType::FOR => {
                 self.lexer.next_token()?;
-                let init = self.get_ident()?;
+                let mut index = None;
+                let mut init = self.get_ident()?;
                 self.lexer.next_token()?;
+                if self.lexer.curr_tok().token == TokenType::COMMA {
+                    self.lexer.next_token()?;
+                    index = Some(init);
+                    init = self.get_ident()?;
+                    self.lexer.next_token()?;
+                }
                 if self.lexer.curr_tok().token != TokenType::IN {
                     return Err(ParserError::UnexpectedChar {
                         expected: Some("in".to_string()),
@@ -189,6 +184,8 @@ impl<'a> Parser<'a> {
                 let iter = self.expr()?;
                 let body = self.stmt()?;
                 Ok(Expr::For(For {
+                    label: None,
+                    index,
                     init,
                     iter: Box::new(iter),
                     body: Box::new(body),
@@ -236,9 +233,20 @@ impl<'a> Parser<'a> {
         {
             return self.ctrl();
         }
-        self.expr()
-    }
-    fn expr(&mut self) -> Result<Expr, ParserError> {
+        if self.lexer.curr_tok().token == TokenType::IDENT
+            && self.lexer.peek_token()?.token == TokenType::COLON
+        {
+            let name = self.get_ident()?;
+            self.lexer.next_token()?;
+            self.lexer.next_token()?;
+            if matches!(
+                self.lexer.curr_tok().token,
+                TokenType::WHILE | TokenType::FOR
+            ) {
+                return self.labeled_loop(name);
+            }
+            return Ok(Expr::Label(Label { name }));
+        }
         match self.lexer.curr_tok().token {
             TokenType::GOTO => {
                 self.lexer.next_token()?;
@@ -246,6 +254,78 @@ impl<'a> Parser<'a> {
                 self.lexer.next_token()?;
                 Ok(Expr::Goto(Goto { label: name }))
             }
+            TokenType::BREAK => {
+                self.lexer.next_token()?;
+                Ok(Expr::Break(self.loop_target()?))
+            }
+            TokenType::CONTINUE => {
+                self.lexer.next_token()?;
+                Ok(Expr::Continue(self.loop_target()?))
+            }
+            TokenType::RETURN => {
+                self.lexer.next_token()?;
+                let value = self.expr()?;
+                Ok(Expr::Return(Return {
+                    value: Some(Box::new(value)),
+                }))
+            }
+            _ => self.expr(),
+        }
+    }
+    /// Parses a loop written after `name:`. Inside its body, `break name` and
+    /// `continue name` target it.
+    fn labeled_loop(&mut self, name: String) -> Result<Expr, ParserError> {
+        self.loop_labels.push(name.clone());
+        let parsed = self.ctrl();
+        self.loop_labels.pop();
+        self.closed_loop_labels.push(name.clone());
+        match parsed? {
+            Expr::While(w) => Ok(Expr::While(While {
+                label: Some(name),
+                ..w
+            })),
+            Expr::For(f) => Ok(Expr::For(For {
+                label: Some(name),
+                ..f
+            })),
+            other => Ok(Expr::Stmt(Stmt {
+                body: vec![Expr::Label(Label { name }), other],
+            })),
+        }
+    }
+    /// The loop named after `break` or `continue`, if any. An identifier is
+    /// only taken as a target when it labels a loop of this function, so
+    /// `break` may still be followed by an unrelated statement. Naming a loop
+    /// that does not enclose the jump is left for the IR generator to reject.
+    fn loop_target(&mut self) -> Result<Option<String>, ParserError> {
+        if self.lexer.curr_tok().token != TokenType::IDENT {
+            return Ok(None);
+        }
+        let name = self.get_ident()?;
+        if !self.loop_labels.contains(&name) && !self.closed_loop_labels.contains(&name) {
+            return Ok(None);
+        }
+        self.lexer.next_token()?;
+        Ok(Some(name))
+    }
+    /// Jumps leave the expression being evaluated, so they are only
+    /// allowed as statements.
+    fn jump_as_value(&self) -> ParserError {
+        let tok = self.lexer.curr_tok();
+        ParserError::SyntaxError {
+            message: format!(
+                "`{}` cannot be used as a value",
+                format!("{:?}", tok.token).to_lowercase()
+            ),
+            row: tok.row,
+            col: tok.col,
+        }
+    }
+    fn expr(&mut self) -> Result<Expr, ParserError> {
+        match self.lexer.curr_tok().token {
+            TokenType::GOTO | TokenType::BREAK | TokenType::CONTINUE | TokenType::RETURN => {
+                Err(self.jump_as_value())
+            }
             TokenType::VARDECL => {
                 self.lexer.next_token()?;
                 let name = self.get_ident()?;
@@ -264,7 +344,7 @@ impl<'a> Parser<'a> {
                     TokenType::Type(VarType::Float) => VarType::Float,
                     TokenType::Type(VarType::Bool) => VarType::Bool,
                     TokenType::Type(VarType::Str) => VarType::Str,
-                    TokenType::Type(VarType::Array(n)) => VarType::Array(*n),
+                    TokenType::Type(VarType::Array(n, elem)) => VarType::Array(*n, elem.clone()),
                     _ => {
                         return Err(ParserError::UnknownType {
                             row: self.lexer.curr_tok().row,
@@ -273,36 +353,38 @@ impl<'a> Parser<'a> {
                     }
                 };
                 self.lexer.next_token()?;
-                if self.lexer.curr_tok().token != TokenType::EQ {
-                    return Err(ParserError::UnexpectedChar {
-                        expected: Some("=".to_string()),
-                        found: self.lexer.curr_ch(),
-                        row: self.lexer.curr_tok().row,
-                        col: self.lexer.curr_tok().col,
-                    });
-                }
-                self.lexer.next_token()?;
-                let value = self.expr()?;
+                let value = if self.lexer.curr_tok().token == TokenType::EQ {
+                    self.lexer.next_token()?;
+                    self.expr()?
+                } else {
+                    self.zero_value(&typ)?
+                };
                 Ok(Expr::VarDecl(VarDecl {
                     name,
                     value: Box::new(value),
                     typ,
                 }))
             }
-            TokenType::RETURN => {
-                self.lexer.next_token()?;
-                let value = self.expr()?;
-                Ok(Expr::Return(Return {
-                    value: Some(Box::new(value)),
-                }))
-            }
             TokenType::EXTERN => {
                 self.lexer.next_token()?;
                 let func = self.get_ident()?;
                 self.lexer.next_token()?;
+                if self.lexer.curr_tok().token == TokenType::COLON {
+                    self.lexer.next_token()?;
+                    let TokenType::Type(typ) = self.lexer.curr_tok().token else {
+                        return Err(ParserError::UnexpectedChar {
+                            expected: Some("TYPE".to_string()),
+                            found: self.lexer.curr_ch(),
+                            row: self.lexer.curr_tok().row,
+                            col: self.lexer.curr_tok().col,
+                        });
+                    };
+                    self.lexer.next_token()?;
+                    return Ok(Expr::ExternVar(ExternVar { name: func, typ }));
+                }
                 if self.lexer.curr_tok().token != TokenType::LPAREN {
                     return Err(ParserError::UnexpectedChar {
-                        expected: Some("(".to_string()),
+                        expected: Some("( or :".to_string()),
                         found: self.lexer.curr_ch(),
                         row: self.lexer.curr_tok().row,
                         col: self.lexer.curr_tok().col,
@@ -348,9 +430,8 @@ impl<'a> Parser<'a> {
                     });
                 }
                 self.lexer.next_token()?;
-                let ret_type: VarType;
-                match self.lexer.curr_tok().token {
-                    TokenType::Type(typ) => ret_type = typ,
+                let ret_type = match self.lexer.curr_tok().token {
+                    TokenType::Type(typ) => typ,
                     _ => {
                         return Err(ParserError::UnexpectedChar {
                             expected: Some("TYPE".to_string()),
@@ -359,9 +440,8 @@ impl<'a> Parser<'a> {
                             col: self.lexer.curr_tok().col,
                         });
                     }
-                }
+                };
                 self.lexer.next_token()?;
-                self.functions.insert(func.clone(), ret_type.clone());
                 Ok(Expr::Extern(Extern {
                     name: func,
                     params,
@@ -369,9 +449,92 @@ impl<'a> Parser<'a> {
                 }))
             }
             TokenType::IF | TokenType::WHILE | TokenType::LBRACE => self.ctrl(),
-            _ => self.logical(),
+            _ => self.ternary(),
         }
     }
+    /// Evaluates `left op right` when both sides are literals. Integer
+    /// arithmetic wraps like the generated code; a division that would trap
+    /// at run time is left unfolded.
+    fn fold(op: &TokenType, left: &Expr, right: &Expr) -> Option<Val> {
+        let (Expr::Val(l), Expr::Val(r)) = (left, right) else {
+            return None;
+        };
+        let (value, typ) = match (&l.value, &r.value) {
+            (Literal::Int(n), Literal::Int(m)) => {
+                let (n, m) = (*n, *m);
+                match op {
+                    TokenType::ADD => (Literal::Int(n.wrapping_add(m)), VarType::Int),
+                    TokenType::SUB => (Literal::Int(n.wrapping_sub(m)), VarType::Int),
+                    TokenType::MUL => (Literal::Int(n.wrapping_mul(m)), VarType::Int),
+                    TokenType::DIV => (Literal::Int(n.checked_div(m)?), VarType::Int),
+                    TokenType::MOD => (Literal::Int(n.checked_rem(m)?), VarType::Int),
+                    TokenType::SHL => (
+                        Literal::Int(n.checked_shl(u32::try_from(m).ok()?)?),
+                        VarType::Int,
+                    ),
+                    TokenType::SHR => (
+                        Literal::Int(n.checked_shr(u32::try_from(m).ok()?)?),
+                        VarType::Int,
+                    ),
+                    TokenType::LOGAND => (Literal::Int(n & m), VarType::Int),
+                    TokenType::LOGOR => (Literal::Int(n | m), VarType::Int),
+                    TokenType::LOGXOR => (Literal::Int(n ^ m), VarType::Int),
+                    TokenType::COMPEQ => (Literal::Bool(n == m), VarType::Bool),
+                    TokenType::COMPNE => (Literal::Bool(n != m), VarType::Bool),
+                    TokenType::COMPGT => (Literal::Bool(n > m), VarType::Bool),
+                    TokenType::COMPGE => (Literal::Bool(n >= m), VarType::Bool),
+                    TokenType::COMPLT => (Literal::Bool(n < m), VarType::Bool),
+                    TokenType::COMPLE => (Literal::Bool(n <= m), VarType::Bool),
+                    _ => return None,
+                }
+            }
+            (Literal::Bool(n), Literal::Bool(m)) => {
+                let (n, m) = (*n, *m);
+                let value = match op {
+                    TokenType::LOGAND => n & m,
+                    TokenType::LOGOR => n | m,
+                    TokenType::LOGXOR => n ^ m,
+                    TokenType::COMPAND => n && m,
+                    TokenType::COMPOR => n || m,
+                    _ => return None,
+                };
+                (Literal::Bool(value), VarType::Bool)
+            }
+            _ => return None,
+        };
+        Some(Val { value, typ })
+    }
+    /// Parses `cond ? a : b`, which groups to the right, into an `if`.
+    fn ternary(&mut self) -> Result<Expr, ParserError> {
+        let cond = self.logical()?;
+        if self.lexer.curr_tok().token != TokenType::QUESTION {
+            return Ok(cond);
+        }
+        self.lexer.next_token()?;
+        let then_branch = self.ternary()?;
+        if self.lexer.curr_tok().token != TokenType::COLON {
+            return Err(ParserError::UnexpectedChar {
+                expected: Some(":".to_string()),
+                found: self.lexer.curr_ch(),
+                row: self.lexer.curr_tok().row,
+                col: self.lexer.curr_tok().col,
+            });
+        }
+        self.lexer.next_token()?;
+        let else_branch = self.ternary()?;
+        if let Expr::Val(Val {
+            value: Literal::Bool(b),
+            ..
+        }) = cond
+        {
+            return Ok(if b { then_branch } else { else_branch });
+        }
+        Ok(Expr::If(If {
+            condition: Box::new(cond),
+            then_branch: Box::new(then_branch),
+            else_branch: Some(Box::new(else_branch)),
+        }))
+    }
     fn logical(&mut self) -> Result<Expr, ParserError> {
         let mut left = self.comparison()?;
         while self.lexer.curr_tok().token == TokenType::LOGAND
@@ -381,64 +544,19 @@ impl<'a> Parser<'a> {
             let op = self.lexer.curr_tok().token;
             self.lexer.next_token()?;
             let right = self.comparison()?;
-            match (left.clone(), right.clone()) {
-                (Expr::Val(l), Expr::Val(r)) => match (l.value, r.value) {
-                    (Literal::Int(n), Literal::Int(m)) => match op.clone() {
-                        TokenType::LOGAND => {
-                            left = Expr::Val(Val {
-                                value: Literal::Int(n & m),
-                                typ: VarType::Int,
-                            });
-                        }
-                        TokenType::LOGOR => {
-                            left = Expr::Val(Val {
-                                value: Literal::Int(n | m),
-                                typ: VarType::Int,
-                            });
-                        }
-                        TokenType::LOGXOR => {
-                            left = Expr::Val(Val {
-                                value: Literal::Int(n ^ m),
-                                typ: VarType::Int,
-                            });
-                        }
-                        _ => {}
-                    },
-                    (Literal::Bool(n), Literal::Bool(m)) => match op.clone() {
-                        TokenType::LOGAND => {
-                            left = Expr::Val(Val {
-                                value: Literal::Bool(n & m),
-                                typ: VarType::Bool,
-                            });
-                        }
-                        TokenType::LOGOR => {
-                            left = Expr::Val(Val {
-                                value: Literal::Bool(n | m),
-                                typ: VarType::Bool,
-                            });
-                        }
-                        TokenType::LOGXOR => {
-                            left = Expr::Val(Val {
-                                value: Literal::Bool(n ^ m),
-                                typ: VarType::Bool,
-                            });
-                        }
-                        _ => {}
-                    },
-                    (_, _) => {}
-                },
-                (_, _) => {}
+            left = match Self::fold(&op, &left, &right) {
+                Some(val) => Expr::Val(val),
+                None => Expr::BinOp(BinOp {
+                    left: Box::new(left),
+                    right: Box::new(right),
+                    operator: op,
+                }),
             }
-            left = Expr::BinOp(BinOp {
-                left: Box::new(left),
-                right: Box::new(right),
-                operator: op,
-            })
         }
         Ok(left)
     }
     fn comparison(&mut self) -> Result<Expr, ParserError> {
-        let mut left = self.additive()?;
+        let mut left = self.shift()?;
         while self.lexer.curr_tok().token == TokenType::COMPEQ
             || self.lexer.curr_tok().token == TokenType::COMPNE
             || self.lexer.curr_tok().token == TokenType::COMPLT
@@ -448,75 +566,37 @@ impl<'a> Parser<'a> {
             || self.lexer.curr_tok().token == TokenType::COMPAND
             || self.lexer.curr_tok().token == TokenType::COMPOR
             || self.lexer.curr_tok().token == TokenType::RANGE
+        {
+            let op = self.lexer.curr_tok().token;
+            self.lexer.next_token()?;
+            let right = self.shift()?;
+            left = match Self::fold(&op, &left, &right) {
+                Some(val) => Expr::Val(val),
+                None => Expr::BinOp(BinOp {
+                    left: Box::new(left),
+                    right: Box::new(right),
+                    operator: op,
+                }),
+            };
+        }
+        Ok(left)
+    }
+    fn shift(&mut self) -> Result<Expr, ParserError> {
+        let mut left = self.additive()?;
+        while self.lexer.curr_tok().token == TokenType::SHL
+            || self.lexer.curr_tok().token == TokenType::SHR
         {
             let op = self.lexer.curr_tok().token;
             self.lexer.next_token()?;
             let right = self.additive()?;
-            match (left.clone(), right.clone()) {
-                (Expr::Val(l), Expr::Val(r)) => match (l.value, r.value) {
-                    (Literal::Int(n), Literal::Int(m)) => match op.clone() {
-                        TokenType::COMPEQ => {
-                            left = Expr::Val(Val {
-                                value: Literal::Bool(n == m),
-                                typ: VarType::Bool,
-                            });
-                        }
-                        TokenType::COMPNE => {
-                            left = Expr::Val(Val {
-                                value: Literal::Bool(n != m),
-                                typ: VarType::Bool,
-                            });
-                        }
-                        TokenType::COMPGT => {
-                            left = Expr::Val(Val {
-                                value: Literal::Bool(n > m),
-                                typ: VarType::Bool,
-                            });
-                        }
-                        TokenType::COMPGE => {
-                            left = Expr::Val(Val {
-                                value: Literal::Bool(n >= m),
-                                typ: VarType::Bool,
-                            });
-                        }
-                        TokenType::COMPLT => {
-                            left = Expr::Val(Val {
-                                value: Literal::Bool(n < m),
-                                typ: VarType::Bool,
-                            });
-                        }
-                        TokenType::COMPLE => {
-                            left = Expr::Val(Val {
-                                value: Literal::Bool(n <= m),
-                                typ: VarType::Bool,
-                            });
-                        }
-                        _ => {}
-                    },
-                    (Literal::Bool(n), Literal::Bool(m)) => match op.clone() {
-                        TokenType::COMPAND => {
-                            left = Expr::Val(Val {
-                                value: Literal::Bool(n && m),
-                                typ: VarType::Bool,
-                            });
-                        }
-                        TokenType::COMPOR => {
-                            left = Expr::Val(Val {
-                                value: Literal::Bool(n || m),
-                                typ: VarType::Bool,
-                            });
-                        }
-                        _ => {}
-                    },
-                    (_, _) => {}
-                },
-                (_, _) => {}
-            }
-            left = Expr::BinOp(BinOp {
-                left: Box::new(left),
-                right: Box::new(right),
-                operator: op,
-            });
+            left = match Self::fold(&op, &left, &right) {
+                Some(val) => Expr::Val(val),
+                None => Expr::BinOp(BinOp {
+                    left: Box::new(left),
+                    right: Box::new(right),
+                    operator: op,
+                }),
+            };
         }
         Ok(left)
     }
@@ -528,32 +608,14 @@ impl<'a> Parser<'a> {
             let op = self.lexer.curr_tok().token;
             self.lexer.next_token()?;
             let right = self.term()?;
-            match (left.clone(), right.clone()) {
-                (Expr::Val(l), Expr::Val(r)) => match (l.value, r.value) {
-                    (Literal::Int(n), Literal::Int(m)) => match op.clone() {
-                        TokenType::ADD => {
-                            left = Expr::Val(Val {
-                                value: Literal::Int(n + m),
-                                typ: VarType::Int,
-                            });
-                        }
-                        TokenType::SUB => {
-                            left = Expr::Val(Val {
-                                value: Literal::Int(n - m),
-                                typ: VarType::Int,
-                            });
-                        }
-                        _ => {}
-                    },
-                    (_, _) => {}
-                },
-                (_, _) => {}
-            }
-            left = Expr::BinOp(BinOp {
-                left: Box::new(left),
-                right: Box::new(right),
-                operator: op,
-            });
+            left = match Self::fold(&op, &left, &right) {
+                Some(val) => Expr::Val(val),
+                None => Expr::BinOp(BinOp {
+                    left: Box::new(left),
+                    right: Box::new(right),
+                    operator: op,
+                }),
+            };
         }
         Ok(left)
     }
@@ -561,48 +623,95 @@ impl<'a> Parser<'a> {
         let mut left = self.factor()?;
         while self.lexer.curr_tok().token == TokenType::MUL
             || self.lexer.curr_tok().token == TokenType::DIV
+            || self.lexer.curr_tok().token == TokenType::MOD
         {
             let op = self.lexer.curr_tok().token;
             self.lexer.next_token()?;
             let right = self.factor()?;
-            match (left.clone(), right.clone()) {
-                (Expr::Val(l), Expr::Val(r)) => match (l.value, r.value) {
-                    (Literal::Int(n), Literal::Int(m)) => match op.clone() {
-                        TokenType::MUL => {
-                            left = Expr::Val(Val {
-                                value: Literal::Int(n * m),
-                                typ: VarType::Int,
-                            });
-                        }
-                        TokenType::DIV => {
-                            left = Expr::Val(Val {
-                                value: Literal::Int(n / m),
-                                typ: VarType::Int,
-                            });
-                        }
-                        _ => {}
-                    },
-                    (_, _) => {}
+            left = match Self::fold(&op, &left, &right) {
+                Some(val) => Expr::Val(val),
+                None => Expr::BinOp(BinOp {
+                    left: Box::new(left),
+                    right: Box::new(right),
+                    operator: op,
+                }),
+            };
+        }
+        Ok(left)
+    }
+    /// Splits the raw text of an interpolated string into string literals
+    /// and the expressions written between `{` and `}`. A string without
+    /// any expression stays a plain literal.
+    fn interp(raw: &str, row: usize, col: usize) -> Result<Expr, ParserError> {
+        let error = |message: &str| ParserError::SyntaxError {
+            message: message.to_string(),
+            row,
+            col,
+        };
+        let literal = |s: String| {
+            Expr::Val(Val {
+                value: Literal::Str(s),
+                typ: VarType::Str,
+            })
+        };
+
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = raw.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some(c @ ('`' | '{' | '}')) => text.push(c),
+                    Some(c) => text.extend(unescape(c)),
+                    None => {}
                 },
-                (_, _) => {}
+                '{' => {
+                    let mut hole = String::new();
+                    let mut depth = 1;
+                    loop {
+                        let c = chars
+                            .next()
+                            .ok_or_else(|| error("unclosed '{' in interpolated string"))?;
+                        match c {
+                            '{' => depth += 1,
+                            '}' if depth == 1 => break,
+                            '}' => depth -= 1,
+                            _ => {}
+                        }
+                        hole.push(c);
+                    }
+                    if !text.is_empty() {
+                        parts.push(literal(take(&mut text)));
+                    }
+                    let mut parser = Parser::new(Lexer::new(&hole));
+                    parser.lexer.next_token()?;
+                    if parser.lexer.curr_tok().token == TokenType::EOF {
+                        return Err(error("empty '{}' in interpolated string"));
+                    }
+                    parts.push(parser.expr()?);
+                    if parser.lexer.curr_tok().token != TokenType::EOF {
+                        return Err(error("expected '}' after interpolated expression"));
+                    }
+                }
+                '}' => return Err(error("unmatched '}' in interpolated string")),
+                c => text.push(c),
             }
-            left = Expr::BinOp(BinOp {
-                left: Box::new(left),
-                right: Box::new(right),
-                operator: op,
-            });
         }
-        Ok(left)
+
+        if parts.is_empty() {
+            return Ok(literal(text));
+        }
+        if !text.is_empty() {
+            parts.push(literal(text));
+        }
+        Ok(Expr::Interp(Interp { parts }))
     }
     fn factor(&mut self) -> Result<Expr, ParserError> {
         match self.lexer.curr_tok().token {
             TokenType::LITERAL(typ) => {
                 if let Some(val) = self.lexer.curr_tok().value.clone() {
                     self.lexer.next_token()?;
-                    Ok(Expr::Val(Val {
-                        value: val,
-                        typ: typ,
-                    }))
+                    Ok(Expr::Val(Val { value: val, typ }))
                 } else {
                     Err(ParserError::SyntaxError {
                         message: "expected literal value".to_string(),
@@ -611,6 +720,21 @@ impl<'a> Parser<'a> {
                     })
                 }
             }
+            TokenType::GOTO | TokenType::BREAK | TokenType::CONTINUE | TokenType::RETURN => {
+                Err(self.jump_as_value())
+            }
+            TokenType::INTERP => {
+                let tok = self.lexer.curr_tok();
+                self.lexer.next_token()?;
+                match tok.value {
+                    Some(Literal::Str(raw)) => Self::interp(&raw, tok.row, tok.col),
+                    _ => Err(ParserError::SyntaxError {
+                        message: "expected interpolated string".to_string(),
+                        row: tok.row,
+                        col: tok.col,
+                    }),
+                }
+            }
             TokenType::LPAREN => {
                 self.lexer.next_token()?;
                 let expr = self.expr()?;
@@ -647,23 +771,19 @@ impl<'a> Parser<'a> {
                 self.lexer.next_token()?;
                 Ok(Expr::Val(Val {
                     value: Literal::Array(array.len(), array.clone()),
-                    typ: VarType::Array(Some(array.len())),
+                    typ: VarType::Array(Some(array.len()), Box::new(VarType::Int)),
                 }))
             }
             TokenType::NEG => {
                 self.lexer.next_token()?;
-                let argument = self.expr()?;
-                match argument.clone() {
-                    Expr::Val(val) => match val.value {
-                        Literal::Int(n) => {
-                            return Ok(Expr::Val(Val {
-                                value: Literal::Int(-n),
-                                typ: VarType::Int,
-                            }));
-                        }
-                        _ => {}
-                    },
-                    _ => {}
+                let argument = self.factor()?;
+                if let Expr::Val(val) = argument.clone()
+                    && let Literal::Int(n) = val.value
+                {
+                    return Ok(Expr::Val(Val {
+                        value: Literal::Int(-n),
+                        typ: VarType::Int,
+                    }));
                 }
                 Ok(Expr::UnaryOp(UnaryOp {
                     argument: Box::new(argument),
@@ -672,18 +792,14 @@ impl<'a> Parser<'a> {
             }
             TokenType::LOGNOT => {
                 self.lexer.next_token()?;
-                let argument = self.expr()?;
-                match argument.clone() {
-                    Expr::Val(val) => match val.value {
-                        Literal::Bool(n) => {
-                            return Ok(Expr::Val(Val {
-                                value: Literal::Bool(!n),
-                                typ: VarType::Bool,
-                            }));
-                        }
-                        _ => {}
-                    },
-                    _ => {}
+                let argument = self.factor()?;
+                if let Expr::Val(val) = argument.clone()
+                    && let Literal::Bool(n) = val.value
+                {
+                    return Ok(Expr::Val(Val {
+                        value: Literal::Bool(!n),
+                        typ: VarType::Bool,
+                    }));
                 }
                 Ok(Expr::UnaryOp(UnaryOp {
                     argument: Box::new(argument),
@@ -702,14 +818,9 @@ impl<'a> Parser<'a> {
                 let name = self.get_ident()?;
                 self.lexer.next_token()?;
                 match self.lexer.curr_tok().token {
-                    TokenType::COLON => {
-                        self.lexer.next_token()?;
-                        Ok(Expr::Label(Label { name: name }))
-                    }
                     TokenType::LPAREN => {
                         self.lexer.next_token()?;
                         let mut args: Vec<Expr> = Vec::new();
-                        let ret_type = self.find_func_ret_type(&name)?;
                         while self.lexer.curr_tok().token != TokenType::RPAREN {
                             args.push(self.expr()?);
                             if self.lexer.curr_tok().token == TokenType::COMMA {
@@ -726,11 +837,7 @@ impl<'a> Parser<'a> {
                             }
                         }
                         self.lexer.next_token()?;
-                        Ok(Expr::FuncCall(FuncCall {
-                            name,
-                            args,
-                            ret_type,
-                        }))
+                        Ok(Expr::FuncCall(FuncCall { name, args }))
                     }
                     TokenType::EQ => {
                         self.lexer.next_token()?;
@@ -740,19 +847,17 @@ impl<'a> Parser<'a> {
                             value: Box::new(val),
                         }))
                     }
-                    TokenType::ADDEQ => {
-                        self.lexer.next_token()?;
-                        let val = self.expr()?;
-                        Ok(Expr::VarMod(VarMod {
-                            name: name.clone(),
-                            value: Box::new(Expr::BinOp(BinOp {
-                                left: Box::new(Expr::Var(Var { name })),
-                                right: Box::new(val),
-                                operator: TokenType::ADD,
-                            })),
-                        }))
-                    }
-                    TokenType::SUBEQ => {
+                    TokenType::ADDEQ
+                    | TokenType::SUBEQ
+                    | TokenType::MULEQ
+                    | TokenType::DIVEQ
+                    | TokenType::ANDEQ
+                    | TokenType::OREQ
+                    | TokenType::XOREQ
+                    | TokenType::MODEQ
+                    | TokenType::SHLEQ
+                    | TokenType::SHREQ => {
+                        let op = Self::compound_op(&self.lexer.curr_tok().token);
                         self.lexer.next_token()?;
                         let val = self.expr()?;
                         Ok(Expr::VarMod(VarMod {
@@ -760,31 +865,7 @@ impl<'a> Parser<'a> {
                             value: Box::new(Expr::BinOp(BinOp {
                                 left: Box::new(Expr::Var(Var { name })),
                                 right: Box::new(val),
-                                operator: TokenType::SUB,
-                            })),
-                        }))
-                    }
-                    TokenType::MULEQ => {
-                        self.lexer.next_token()?;
-                        let val = self.expr()?;
-                        Ok(Expr::VarMod(VarMod {
-                            name: name.clone(),
-                            value: Box::new(Expr::BinOp(BinOp {
-                                left: Box::new(Expr::Var(Var { name })),
-                                right: Box::new(val),
-                                operator: TokenType::MUL,
-                            })),
-                        }))
-                    }
-                    TokenType::DIVEQ => {
-                        self.lexer.next_token()?;
-                        let val = self.expr()?;
-                        Ok(Expr::VarMod(VarMod {
-                            name: name.clone(),
-                            value: Box::new(Expr::BinOp(BinOp {
-                                left: Box::new(Expr::Var(Var { name })),
-                                right: Box::new(val),
-                                operator: TokenType::DIV,
+                                operator: op,
                             })),
                         }))
                     }
@@ -800,19 +881,68 @@ impl<'a> Parser<'a> {
                             });
                         }
                         self.lexer.next_token()?;
-                        if self.lexer.curr_tok().token == TokenType::EQ {
-                            self.lexer.next_token()?;
-                            let value = self.expr()?;
-                            Ok(Expr::ArrayAssign(ArrayAssign {
-                                array: name,
-                                offset: Box::new(offset),
-                                value: Box::new(value),
-                            }))
-                        } else {
-                            Ok(Expr::ArrayAccess(ArrayAccess {
+                        match self.lexer.curr_tok().token {
+                            TokenType::EQ => {
+                                self.lexer.next_token()?;
+                                let value = self.expr()?;
+                                Ok(Expr::ArrayAssign(ArrayAssign {
+                                    array: name,
+                                    offset: Box::new(offset),
+                                    value: Box::new(value),
+                                }))
+                            }
+                            TokenType::ADDEQ
+                            | TokenType::SUBEQ
+                            | TokenType::MULEQ
+                            | TokenType::DIVEQ
+                            | TokenType::ANDEQ
+                            | TokenType::OREQ
+                            | TokenType::XOREQ
+                            | TokenType::MODEQ
+                            | TokenType::SHLEQ
+                            | TokenType::SHREQ => {
+                                let op = Self::compound_op(&self.lexer.curr_tok().token);
+                                self.lexer.next_token()?;
+                                let value = self.expr()?;
+                                // An index that is not a plain variable or
+                                // literal is evaluated once into a hidden
+                                // variable, which no identifier can name.
+                                let (offset, index) = match offset {
+                                    Expr::Var(_) | Expr::Val(_) => (offset, None),
+                                    _ => {
+                                        self.hidden += 1;
+                                        let name = format!("index@{}", self.hidden);
+                                        let decl = Expr::VarDecl(VarDecl {
+                                            name: name.clone(),
+                                            value: Box::new(offset),
+                                            typ: VarType::Int,
+                                        });
+                                        (Expr::Var(Var { name }), Some(decl))
+                                    }
+                                };
+                                let assign = Expr::ArrayAssign(ArrayAssign {
+                                    array: name.clone(),
+                                    offset: Box::new(offset.clone()),
+                                    value: Box::new(Expr::BinOp(BinOp {
+                                        left: Box::new(Expr::ArrayAccess(ArrayAccess {
+                                            array: name,
+                                            offset: Box::new(offset),
+                                        })),
+                                        right: Box::new(value),
+                                        operator: op,
+                                    })),
+                                });
+                                Ok(match index {
+                                    Some(decl) => Expr::Stmt(Stmt {
+                                        body: vec![decl, assign],
+                                    }),
+                                    None => assign,
+                                })
+                            }
+                            _ => Ok(Expr::ArrayAccess(ArrayAccess {
                                 array: name,
                                 offset: Box::new(offset),
-                            }))
+                            })),
                         }
                     }
                     _ => Ok(Expr::Var(Var { name })),
@@ -826,6 +956,35 @@ impl<'a> Parser<'a> {
         }
     }
 
+    /// The initializer of a `let` that has none.
+    fn zero_value(&self, typ: &VarType) -> Result<Expr, ParserError> {
+        let zero = |value| {
+            Expr::Val(Val {
+                value,
+                typ: VarType::Int,
+            })
+        };
+        let value = match typ {
+            VarType::Float => Literal::Float(OrderedFloat(0.0)),
+            VarType::Bool => Literal::Bool(false),
+            VarType::Str => Literal::Str(String::new()),
+            VarType::Array(Some(0), _) => Literal::Array(0, Vec::new()),
+            VarType::Array(Some(len), _) => Literal::Array(*len, vec![zero(Literal::Int(0))]),
+            VarType::Array(None, _) => {
+                return Err(ParserError::TypeError {
+                    message: "`arr<_>` needs an initializer to infer its length".to_string(),
+                    row: self.lexer.curr_tok().row,
+                    col: self.lexer.curr_tok().col,
+                });
+            }
+            _ => Literal::Int(0),
+        };
+        Ok(Expr::Val(Val {
+            value,
+            typ: typ.clone(),
+        }))
+    }
+
     fn get_ident(&mut self) -> Result<String, ParserError> {
         match self.lexer.curr_tok().value.as_ref() {
             Some(Literal::Str(s)) => Ok(s.clone()),
@@ -843,6 +1002,7 @@ impl<'a> Parser<'a> {
     }
 
     fn func_decl(&mut self, is_pub: bool) -> Result<Expr, ParserError> {
+        self.closed_loop_labels.clear();
         self.lexer.next_token()?;
         let name = self.get_ident()?;
         let mut params: Vec<(String, VarType)> = Vec::new();
@@ -945,9 +1105,8 @@ impl<'a> Parser<'a> {
                 col: self.lexer.curr_tok().col,
             });
         }
-        self.functions.insert(name.clone(), ret_type.clone());
         self.lexer.next_token()?;
-        let body = self.expr()?;
+        let body = self.stmt()?;
         Ok(Expr::FuncDecl(FuncDecl {
             name,
             params,
@@ -957,14 +1116,351 @@ impl<'a> Parser<'a> {
         }))
     }
 
-    fn find_func_ret_type(&self, name: &String) -> Result<VarType, ParserError> {
-        self.functions
-            .get(name)
-            .ok_or_else(|| ParserError::SyntaxError {
-                message: format!("undefined function: '{}'", name),
-                row: 0,
-                col: 0,
+    fn compound_op(tok: &TokenType) -> TokenType {
+        match tok {
+            TokenType::ADDEQ => TokenType::ADD,
+            TokenType::SUBEQ => TokenType::SUB,
+            TokenType::MULEQ => TokenType::MUL,
+            TokenType::DIVEQ => TokenType::DIV,
+            TokenType::ANDEQ => TokenType::LOGAND,
+            TokenType::OREQ => TokenType::LOGOR,
+            TokenType::XOREQ => TokenType::LOGXOR,
+            TokenType::MODEQ => TokenType::MOD,
+            TokenType::SHLEQ => TokenType::SHL,
+            TokenType::SHREQ => TokenType::SHR,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(src: &str) -> Vec<Expr> {
+        Parser::new(Lexer::new(src)).parse().unwrap().body
+    }
+
+    #[test]
+    fn compound_assignments_desugar_on_variables() {
+        assert_eq!(parse("x %= 3"), parse("x = x % 3"));
+        assert_eq!(parse("x <<= 2"), parse("x = x << 2"));
+        assert_eq!(parse("x >>= y"), parse("x = x >> y"));
+    }
+
+    #[test]
+    fn compound_assignments_desugar_on_array_elements() {
+        assert_eq!(parse("a[i] %= 3"), parse("a[i] = a[i] % 3"));
+        assert_eq!(parse("a[0] <<= 1"), parse("a[0] = a[0] << 1"));
+    }
+
+    #[test]
+    fn compound_assignment_evaluates_a_computed_index_once() {
+        let [Expr::Stmt(Stmt { body })] = &parse("a[f()] += 1")[..] else {
+            panic!("expected a block");
+        };
+        let [Expr::VarDecl(decl), Expr::ArrayAssign(assign)] = &body[..] else {
+            panic!("expected a declaration and an assignment, got {:?}", body);
+        };
+        assert!(matches!(*decl.value, Expr::FuncCall(ref call) if call.name == "f"));
+        let index = var(&decl.name);
+        assert_eq!(assign.offset, index);
+        let Expr::BinOp(ref sum) = *assign.value else {
+            panic!("expected a sum, got {:?}", assign.value);
+        };
+        assert_eq!(
+            *sum.left,
+            Expr::ArrayAccess(ArrayAccess {
+                array: "a".to_string(),
+                offset: index,
+            })
+        );
+    }
+
+    fn for_loop(src: &str) -> For {
+        match parse(src).remove(0) {
+            Expr::For(f) => f,
+            other => panic!("expected a for loop, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn for_binds_the_element() {
+        let f = for_loop("for x in a { x }");
+        assert_eq!(f.index, None);
+        assert_eq!(f.init, "x");
+    }
+
+    #[test]
+    fn for_binds_the_index_and_the_element() {
+        let f = for_loop("for i, x in a { x }");
+        assert_eq!(f.index.as_deref(), Some("i"));
+        assert_eq!(f.init, "x");
+    }
+
+    fn var(name: &str) -> Box<Expr> {
+        Box::new(Expr::Var(Var {
+            name: name.to_string(),
+        }))
+    }
+
+    #[test]
+    fn identifier_and_colon_at_statement_start_is_a_label() {
+        let body = parse("start:\nx = 1");
+        assert_eq!(
+            body[0],
+            Expr::Label(Label {
+                name: "start".to_string()
+            })
+        );
+        assert!(matches!(body[1], Expr::VarMod(_)));
+    }
+
+    #[test]
+    fn label_is_found_by_peeking_past_the_identifier() {
+        let label = |name: &str| {
+            Expr::Label(Label {
+                name: name.to_string(),
+            })
+        };
+        let [Expr::Stmt(Stmt { body })] = &parse("{ f()\nend:\nx }")[..] else {
+            panic!("expected a block");
+        };
+        assert!(matches!(body[0], Expr::FuncCall(_)));
+        assert_eq!(body[1], label("end"));
+        assert_eq!(body[2], *var("x"));
+        // A name not followed by a colon is left for the expression parser.
+        assert!(matches!(parse("a[0]")[..], [Expr::ArrayAccess(_)]));
+        assert!(matches!(parse("f(a)")[..], [Expr::FuncCall(_)]));
+    }
+
+    #[test]
+    fn colon_after_an_identifier_in_a_conditional_is_not_a_label() {
+        let conditional = Expr::If(If {
+            condition: var("c"),
+            then_branch: var("x"),
+            else_branch: Some(var("y")),
+        });
+        assert_eq!(
+            parse("z = c ? x : y"),
+            [Expr::VarMod(VarMod {
+                name: "z".to_string(),
+                value: Box::new(conditional.clone()),
+            })]
+        );
+        assert_eq!(
+            parse("f(c ? x : y)"),
+            [Expr::FuncCall(FuncCall {
+                name: "f".to_string(),
+                args: vec![conditional],
+            })]
+        );
+    }
+
+    fn conditional(condition: Box<Expr>, then: Box<Expr>, otherwise: Box<Expr>) -> Expr {
+        Expr::If(If {
+            condition,
+            then_branch: then,
+            else_branch: Some(otherwise),
+        })
+    }
+
+    #[test]
+    fn conditional_groups_to_the_right() {
+        let inner = conditional(var("c"), var("d"), var("e"));
+        assert_eq!(
+            parse("a ? b : c ? d : e"),
+            [conditional(var("a"), var("b"), Box::new(inner))]
+        );
+        let inner = conditional(var("b"), var("c"), var("d"));
+        assert_eq!(
+            parse("a ? b ? c : d : e"),
+            [conditional(var("a"), Box::new(inner), var("e"))]
+        );
+    }
+
+    #[test]
+    fn conditional_on_a_literal_folds() {
+        assert_eq!(parse("true ? x : y"), [*var("x")]);
+        assert_eq!(parse("false ? x : y"), [*var("y")]);
+    }
+
+    #[test]
+    fn conditional_without_a_colon_is_an_error() {
+        assert!(Parser::new(Lexer::new("a ? b")).parse().is_err());
+    }
+
+    fn not(argument: Box<Expr>) -> Box<Expr> {
+        Box::new(Expr::UnaryOp(UnaryOp {
+            argument,
+            operator: TokenType::LOGNOT,
+        }))
+    }
+
+    fn binop(left: Box<Expr>, operator: TokenType, right: Box<Expr>) -> Expr {
+        Expr::BinOp(BinOp {
+            left,
+            right,
+            operator,
+        })
+    }
+
+    #[test]
+    fn not_binds_tighter_than_binary_operators() {
+        assert_eq!(
+            parse("!a && b"),
+            [binop(not(var("a")), TokenType::COMPAND, var("b"))]
+        );
+        assert_eq!(
+            parse("!a == b"),
+            [binop(not(var("a")), TokenType::COMPEQ, var("b"))]
+        );
+    }
+
+    #[test]
+    fn not_nests() {
+        assert_eq!(parse("!!a"), [*not(not(var("a")))]);
+        assert_eq!(parse("!!true"), parse("true"));
+    }
+
+    fn int(n: i64) -> Expr {
+        Expr::Val(Val {
+            value: Literal::Int(n),
+            typ: VarType::Int,
+        })
+    }
+
+    #[test]
+    fn constant_shifts_fold() {
+        assert_eq!(parse("1 << 4"), [int(16)]);
+        assert_eq!(parse("-64 >> 2"), [int(-16)]);
+        assert_eq!(parse("1 << 2 + 1"), [int(8)]);
+    }
+
+    #[test]
+    fn break_and_continue_name_an_enclosing_loop() {
+        let [Expr::While(outer)] =
+            &parse("outer: while a { while b { break outer\ncontinue outer } }")[..]
+        else {
+            panic!("expected a loop");
+        };
+        assert_eq!(outer.label.as_deref(), Some("outer"));
+        let Expr::Stmt(Stmt { body }) = &*outer.body else {
+            panic!("expected a block");
+        };
+        let Expr::While(inner) = &body[0] else {
+            panic!("expected an inner loop");
+        };
+        assert_eq!(
+            *inner.body,
+            Expr::Stmt(Stmt {
+                body: vec![
+                    Expr::Break(Some("outer".to_string())),
+                    Expr::Continue(Some("outer".to_string())),
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn break_before_an_unrelated_statement_keeps_its_meaning() {
+        let [Expr::While(w)] = &parse("while a { break x += 1 }")[..] else {
+            panic!("expected a loop");
+        };
+        let Expr::Stmt(Stmt { body }) = &*w.body else {
+            panic!("expected a block");
+        };
+        assert_eq!(body[0], Expr::Break(None));
+        assert_eq!(body[1..], parse("x += 1"));
+    }
+
+    #[test]
+    fn lists_may_span_lines_with_comments() {
+        assert_eq!(
+            parse("let a: arr<3> = [\n  1, # one\n  2,\n  3 # three\n]"),
+            parse("let a: arr<3> = [1, 2, 3]")
+        );
+        assert_eq!(parse("f(\r\n  a, # first\r\n  b\r\n)"), parse("f(a, b)"));
+    }
+
+    #[test]
+    fn declaration_without_initializer_starts_at_zero() {
+        assert_eq!(parse("let x: int"), parse("let x: int = 0"));
+        assert_eq!(parse("let x: flt"), parse("let x: flt = 0.0"));
+        assert_eq!(parse("let x: bool"), parse("let x: bool = false"));
+        assert_eq!(parse("let x: str"), parse("let x: str = \"\""));
+        assert_eq!(
+            parse("let x: int\nif c { x = 1 } else { x = 2 }")[1..],
+            parse("if c { x = 1 } else { x = 2 }")[..]
+        );
+        assert!(matches!(
+            Parser::new(Lexer::new("let a: arr<_>")).parse(),
+            Err(ParserError::TypeError { .. })
+        ));
+    }
+
+    #[test]
+    fn deeply_nested_literal_arithmetic_folds_to_one_value() {
+        let depth = 30;
+        let nested = "(1 + ".repeat(depth) + "1" + &")".repeat(depth);
+        assert_eq!(parse(&nested), [int(depth as i64 + 1)]);
+        let chain = vec!["2 * 3"; 5000].join(" - ");
+        assert_eq!(parse(&chain), [int(-6 * 4998)]);
+        assert_eq!(parse("1 + 2"), [int(3)]);
+        assert_eq!(parse("9223372036854775807 + 1"), [int(i64::MIN)]);
+        assert!(matches!(&parse("1 / 0")[..], [Expr::BinOp(_)]));
+        assert!(matches!(&parse("x + 2 * 3")[..], [Expr::BinOp(sum)] if *sum.right == int(6)));
+    }
+
+    #[test]
+    fn interpolated_string_splits_into_text_and_expressions() {
+        let text = |s: &str| {
+            Expr::Val(Val {
+                value: Literal::Str(s.to_string()),
+                typ: VarType::Str,
             })
-            .map(|t| t.to_owned())
+        };
+        assert_eq!(
+            parse("`x = {x}, \\{y\\} = {f(y)}!`"),
+            [Expr::Interp(Interp {
+                parts: vec![
+                    text("x = "),
+                    *var("x"),
+                    text(", {y} = "),
+                    parse("f(y)").remove(0),
+                    text("!"),
+                ],
+            })]
+        );
+        assert_eq!(parse("`plain`"), [text("plain")]);
+    }
+
+    #[test]
+    fn remainder_folds_like_the_generated_code() {
+        assert_eq!(parse("17 % 5"), [int(2)]);
+        assert_eq!(parse("-7 % 3"), [int(-1)]);
+        assert_eq!(parse("2 + 7 % 4 * 2"), [int(8)]);
+        assert!(matches!(&parse("1 % 0")[..], [Expr::BinOp(_)]));
+    }
+
+    #[test]
+    fn jumps_cannot_be_used_as_values() {
+        for (src, keyword) in [
+            ("let x: int = return 5", "return"),
+            ("x = 1 + return 5", "return"),
+            ("f(break)", "break"),
+            ("while c { y = continue }", "continue"),
+            ("let x: int = goto end", "goto"),
+        ] {
+            let err = Parser::new(Lexer::new(src)).parse().unwrap_err();
+            assert!(
+                matches!(&err, ParserError::SyntaxError { message, .. }
+                    if *message == format!("`{}` cannot be used as a value", keyword)),
+                "{}: {:?}",
+                src,
+                err
+            );
+        }
+        assert_eq!(parse("return 5").len(), 1);
     }
 }
@@ -1,4 +1,12 @@
-use std::{collections::HashMap, fs, iter::Peekable, str::Chars};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    iter::Peekable,
+    mem::take,
+    path::PathBuf,
+    rc::Rc,
+    str::Chars,
+};
 
 #[derive(Debug, Clone)]
 pub enum PreprocessorError {
@@ -7,6 +15,11 @@ pub enum PreprocessorError {
         row: usize,
         col: usize,
     },
+    CircularImport {
+        file: String,
+        row: usize,
+        col: usize,
+    },
     IoError {
         message: String,
         row: usize,
@@ -22,6 +35,12 @@ pub enum PreprocessorError {
         row: usize,
         col: usize,
     },
+    MacroError {
+        name: String,
+        message: String,
+        row: usize,
+        col: usize,
+    },
 }
 
 impl std::error::Error for PreprocessorError {}
@@ -36,6 +55,13 @@ impl std::fmt::Display for PreprocessorError {
                     row, col, file
                 )
             }
+            PreprocessorError::CircularImport { file, row, col } => {
+                write!(
+                    f,
+                    "Import error at {}:{}: '{}' is already being imported",
+                    row, col, file
+                )
+            }
             PreprocessorError::IoError { message, row, col } => {
                 write!(f, "IO error at {}:{}: {}", row, col, message)
             }
@@ -49,33 +75,71 @@ impl std::fmt::Display for PreprocessorError {
             PreprocessorError::ConditionError { message, row, col } => {
                 write!(f, "Condition error at {}:{}: {}", row, col, message)
             }
+            PreprocessorError::MacroError {
+                name,
+                message,
+                row,
+                col,
+            } => {
+                write!(
+                    f,
+                    "Macro error in '{}' at {}:{}: {}",
+                    name, row, col, message
+                )
+            }
         }
     }
 }
 
+/// An open `$ifdef` or `$ifndef`.
+struct Branch {
+    /// Whether the text in the current branch is kept, which takes every
+    /// enclosing branch being kept as well.
+    active: bool,
+    has_else: bool,
+    row: usize,
+    col: usize,
+}
+
 pub struct Preprocessor<'a> {
     src: Peekable<Chars<'a>>,
     path: String,
+    /// Name of the file being read, relative to the directory it was found
+    /// in, which `__FILE__` expands to.
+    file: String,
     row: usize,
     col: usize,
     defines: HashMap<String, String>,
-    condition_stack: Vec<bool>,
+    /// Contents of every imported file, keyed by canonical path, so a file
+    /// imported many times is read from disk once.
+    sources: HashMap<PathBuf, Rc<str>>,
+    /// Canonical paths of the files whose imports are being expanded, to
+    /// catch a file that ends up importing itself.
+    importing: HashSet<PathBuf>,
+    condition_stack: Vec<Branch>,
     skipping: bool,
 }
 
 impl<'a> Preprocessor<'a> {
-    pub fn new(src: &'a str, path: String) -> Self {
+    pub fn new(src: &'a str, path: String, file: String) -> Self {
         Self {
             src: src.chars().peekable(),
             path,
+            file,
             row: 1,
             col: 0,
             defines: HashMap::new(),
+            sources: HashMap::new(),
+            importing: HashSet::new(),
             condition_stack: Vec::new(),
             skipping: false,
         }
     }
 
+    pub fn defines(&self) -> &HashMap<String, String> {
+        &self.defines
+    }
+
     fn current(&mut self) -> char {
         *self.src.peek().unwrap_or(&'\0')
     }
@@ -105,9 +169,8 @@ impl<'a> Preprocessor<'a> {
 
     fn parse_ident(&mut self) -> String {
         let mut ident = String::new();
-        if self.current().is_ascii_alphabetic() || self.current() == '_' {
-            ident.push(self.current());
-            self.bump();
+        if !(self.current().is_ascii_alphabetic() || self.current() == '_') {
+            return ident;
         }
         while self.current().is_alphanumeric() || self.current() == '_' {
             ident.push(self.current());
@@ -134,17 +197,30 @@ impl<'a> Preprocessor<'a> {
         None
     }
 
+    /// Replaces each `$NAME` in `value` whose whole name is defined. Define
+    /// values are expanded when they are defined, so one pass suffices.
     fn expand_macros(&self, value: &str) -> String {
-        let mut result = value.to_string();
-        let mut changed = true;
-
-        while changed {
-            changed = false;
-            for (name, val) in &self.defines {
-                let pattern = format!("${}", name);
-                if result.contains(&pattern) {
-                    result = result.replace(&pattern, val);
-                    changed = true;
+        let mut result = String::new();
+        let mut chars = value.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            if c != '$' {
+                result.push(c);
+                continue;
+            }
+            let mut name = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_') {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+            match self.defines.get(&name) {
+                Some(val) => result.push_str(val),
+                None => {
+                    result.push('$');
+                    result.push_str(&name);
                 }
             }
         }
@@ -152,6 +228,41 @@ impl<'a> Preprocessor<'a> {
         result
     }
 
+    /// The expansion of `__LINE__` on line `row`, or of `__FILE__`.
+    fn builtin(&self, ident: &str, row: usize) -> Option<String> {
+        match ident {
+            "__LINE__" => Some(row.to_string()),
+            "__FILE__" => {
+                let escaped = self.file.replace('\\', "\\\\").replace('"', "\\\"");
+                Some(format!("\"{}\"", escaped))
+            }
+            _ => None,
+        }
+    }
+
+    /// Expands `__LINE__` and `__FILE__` in a define value used on line
+    /// `row`. They stay unexpanded in the value until then, so that they
+    /// refer to where the macro is used.
+    fn expand_builtins(&self, value: &str, row: usize) -> String {
+        let mut result = String::new();
+        let mut ident = String::new();
+        for c in value.chars().chain(['\0']) {
+            if c.is_alphanumeric() || c == '_' {
+                ident.push(c);
+                continue;
+            }
+            match self.builtin(&ident, row) {
+                Some(expanded) => result.push_str(&expanded),
+                None => result.push_str(&ident),
+            }
+            ident.clear();
+            if c != '\0' {
+                result.push(c);
+            }
+        }
+        result
+    }
+
     fn check_condition(&mut self, negated: bool) -> bool {
         self.skip_spaces();
         let ident = self.parse_ident();
@@ -160,9 +271,51 @@ impl<'a> Preprocessor<'a> {
         if negated { !defined } else { defined }
     }
 
+    /// Handles a conditional directive, `cmd` being its name without the
+    /// `$`, and whether the text after it is skipped.
+    fn condition(&mut self, cmd: &str) -> Result<(), PreprocessorError> {
+        let (row, col) = (self.row, self.col - cmd.len() - 1);
+        let error = |message: &str| PreprocessorError::ConditionError {
+            message: message.to_string(),
+            row,
+            col,
+        };
+        let depth = self.condition_stack.len();
+        match cmd {
+            "ifdef" | "ifndef" => {
+                let enclosing = self.condition_stack.last().is_none_or(|b| b.active);
+                let condition_met = self.check_condition(cmd == "ifndef");
+                self.condition_stack.push(Branch {
+                    active: enclosing && condition_met,
+                    has_else: false,
+                    row,
+                    col,
+                });
+            }
+            "else" => {
+                let enclosing = depth < 2 || self.condition_stack[depth - 2].active;
+                let branch = self
+                    .condition_stack
+                    .last_mut()
+                    .ok_or_else(|| error("Unexpected $else"))?;
+                if branch.has_else {
+                    return Err(error("Duplicate $else"));
+                }
+                branch.has_else = true;
+                branch.active = enclosing && !branch.active;
+            }
+            _ => {
+                self.condition_stack
+                    .pop()
+                    .ok_or_else(|| error("Unexpected $endif"))?;
+            }
+        }
+        self.skipping = self.condition_stack.last().is_some_and(|b| !b.active);
+        Ok(())
+    }
+
     pub fn preprocess(&mut self) -> Result<String, PreprocessorError> {
         let mut output = String::new();
-        let mut in_comment = false;
 
         while self.current() != '\0' {
             if self.current() == '#' {
@@ -178,29 +331,16 @@ impl<'a> Preprocessor<'a> {
                     let cmd = self.parse_ident();
 
                     match cmd.as_str() {
-                        "ifdef" | "ifndef" => {
-                            self.skip_spaces();
-                            self.parse_ident();
-
-                            self.condition_stack.push(false);
-                        }
-                        "endif" => {
-                            if let Some(_) = self.condition_stack.pop() {
-                                self.skipping = !self.condition_stack.is_empty()
-                                    && self.condition_stack.last().map(|&s| !s).unwrap_or(false);
-                            } else {
-                                return Err(PreprocessorError::ConditionError {
-                                    message: "Unexpected $endif".to_string(),
-                                    row: self.row,
-                                    col: self.col,
-                                });
-                            }
-                        }
+                        "ifdef" | "ifndef" | "else" | "endif" => self.condition(&cmd)?,
                         _ => {
                             self.skip_until_newline();
                         }
                     }
                 } else {
+                    // Skipped lines stay as blank ones to keep the row count.
+                    if self.current() == '\n' {
+                        output.push('\n');
+                    }
                     self.bump();
                 }
                 continue;
@@ -213,11 +353,42 @@ impl<'a> Preprocessor<'a> {
                 match cmd.as_str() {
                     "define" => {
                         self.skip_spaces();
+                        let (row, col) = (self.row, self.col);
                         let name = self.parse_ident();
+                        if name.is_empty() {
+                            return Err(PreprocessorError::MacroError {
+                                name,
+                                message: "expected a macro name".to_string(),
+                                row,
+                                col,
+                            });
+                        }
+                        if self.current() == '(' {
+                            return Err(PreprocessorError::MacroError {
+                                name,
+                                message: "parameterized macros are not supported".to_string(),
+                                row,
+                                col,
+                            });
+                        }
                         self.skip_spaces();
 
+                        // A `#` comment ends the value, as it would any line.
                         let mut value = String::new();
-                        while self.current() != '\n' && self.current() != '\0' {
+                        while !matches!(self.current(), '\n' | '\0' | '#') {
+                            if self.current() == '\\' {
+                                self.bump();
+                                // A trailing backslash joins the next line.
+                                match self.current() {
+                                    '\n' => {
+                                        self.bump();
+                                        output.push('\n');
+                                    }
+                                    '\0' => {}
+                                    _ => value.push('\\'),
+                                }
+                                continue;
+                            }
                             value.push(self.current());
                             self.bump();
                         }
@@ -227,28 +398,22 @@ impl<'a> Preprocessor<'a> {
                         let expanded_value = self.expand_macros(&value);
                         self.defines.insert(name, expanded_value);
                     }
-                    "ifdef" => {
-                        let condition_met = self.check_condition(false);
-                        self.condition_stack.push(condition_met);
-                        self.skipping = !condition_met;
-                    }
-                    "ifndef" => {
-                        let condition_met = self.check_condition(true);
-                        self.condition_stack.push(condition_met);
-                        self.skipping = !condition_met;
-                    }
-                    "endif" => {
-                        if let Some(_) = self.condition_stack.pop() {
-                            self.skipping = !self.condition_stack.is_empty()
-                                && self.condition_stack.last().map(|&s| !s).unwrap_or(false);
-                        } else {
-                            return Err(PreprocessorError::ConditionError {
-                                message: "Unexpected $endif".to_string(),
-                                row: self.row,
-                                col: self.col,
+                    "undef" => {
+                        self.skip_spaces();
+                        let (row, col) = (self.row, self.col);
+                        let name = self.parse_ident();
+                        if name.is_empty() {
+                            return Err(PreprocessorError::MacroError {
+                                name,
+                                message: "expected a macro name".to_string(),
+                                row,
+                                col,
                             });
                         }
+                        // Like C, undefining an unknown name does nothing.
+                        self.defines.remove(&name);
                     }
+                    "ifdef" | "ifndef" | "else" | "endif" => self.condition(&cmd)?,
                     "import" => {
                         let file_name =
                             self.parse_file_path().ok_or(PreprocessorError::IoError {
@@ -257,42 +422,69 @@ impl<'a> Preprocessor<'a> {
                                 col: self.col,
                             })?;
 
+                        let dir = if self.path.is_empty() {
+                            "."
+                        } else {
+                            &self.path
+                        };
                         let paths_to_try = [
-                            format!(
-                                "{}/{}",
-                                if self.path.is_empty() {
-                                    "."
-                                } else {
-                                    &self.path
-                                },
-                                file_name
-                            ),
-                            format!(
-                                "{}/{}.al",
-                                if self.path.is_empty() {
-                                    "."
-                                } else {
-                                    &self.path
-                                },
-                                file_name
-                            ),
-                            format!("/usr/local/alum/{}", file_name),
-                            format!("/usr/local/alum/{}.al", file_name),
+                            (dir, file_name.clone()),
+                            (dir, format!("{}.al", file_name)),
+                            ("/usr/local/alum", file_name.clone()),
+                            ("/usr/local/alum", format!("{}.al", file_name)),
                         ];
 
                         let mut raw_content = None;
-                        for p in &paths_to_try {
-                            if let Ok(c) = fs::read_to_string(p) {
-                                raw_content = Some(c);
+                        for (dir, name) in paths_to_try {
+                            let Ok(path) = fs::canonicalize(format!("{}/{}", dir, name)) else {
+                                continue;
+                            };
+                            if let Some(c) = self.sources.get(&path) {
+                                raw_content = Some((path, name, c.clone()));
+                                break;
+                            }
+                            if let Ok(c) = fs::read_to_string(&path) {
+                                let c: Rc<str> = c.into();
+                                self.sources.insert(path.clone(), c.clone());
+                                raw_content = Some((path, name, c));
                                 break;
                             }
                         }
 
-                        if let Some(content) = raw_content {
-                            let mut child_pp = Preprocessor::new(&content, self.path.clone());
+                        if let Some((path, name, content)) = raw_content {
+                            if !self.importing.insert(path.clone()) {
+                                // A guarded file being imported has defined
+                                // its guard already, so it would add nothing.
+                                if include_guard(&content)
+                                    .is_some_and(|guard| self.defines.contains_key(guard))
+                                {
+                                    continue;
+                                }
+                                return Err(PreprocessorError::CircularImport {
+                                    file: file_name,
+                                    row: self.row,
+                                    col: self.col,
+                                });
+                            }
+                            let mut child_pp = Preprocessor::new(&content, self.path.clone(), name);
                             child_pp.defines = self.defines.clone();
-                            let processed_sub = child_pp.preprocess()?;
-                            output.push_str(&processed_sub);
+                            child_pp.sources = take(&mut self.sources);
+                            child_pp.importing = take(&mut self.importing);
+                            let processed_sub = child_pp.preprocess();
+                            self.sources = take(&mut child_pp.sources);
+                            self.importing = take(&mut child_pp.importing);
+                            self.importing.remove(&path);
+                            // The imported lines are numbered from 1, and the
+                            // rest of this file picks up at the import's row.
+                            if !output.is_empty() && !output.ends_with('\n') {
+                                output.push('\n');
+                            }
+                            output.push_str("#line 1\n");
+                            output.push_str(&processed_sub?);
+                            if !output.ends_with('\n') {
+                                output.push('\n');
+                            }
+                            output.push_str(&format!("#line {}\n", self.row));
                             self.defines = child_pp.defines;
                         } else {
                             return Err(PreprocessorError::ImportError {
@@ -304,7 +496,7 @@ impl<'a> Preprocessor<'a> {
                     }
                     _ => {
                         if let Some(val) = self.defines.get(&cmd) {
-                            output.push_str(val);
+                            output.push_str(&self.expand_builtins(val, self.row));
                         } else {
                             output.push('$');
                             output.push_str(&cmd);
@@ -313,14 +505,22 @@ impl<'a> Preprocessor<'a> {
                 }
             } else {
                 if self.current().is_ascii_alphabetic() || self.current() == '_' {
-                    let start_col = self.col;
+                    let row = self.row;
                     let ident = self.parse_ident();
 
                     if let Some(val) = self.defines.get(&ident) {
-                        output.push_str(val);
+                        output.push_str(&self.expand_builtins(val, row));
+                    } else if let Some(expanded) = self.builtin(&ident, row) {
+                        output.push_str(&expanded);
                     } else {
                         output.push_str(&ident);
                     }
+                } else if self.current().is_ascii_digit() {
+                    // Keep a number together so `2N` does not expand `N`.
+                    while self.current().is_alphanumeric() || self.current() == '_' {
+                        output.push(self.current());
+                        self.bump();
+                    }
                 } else {
                     output.push(self.current());
                     self.bump();
@@ -328,14 +528,254 @@ impl<'a> Preprocessor<'a> {
             }
         }
 
-        if !self.condition_stack.is_empty() {
+        if let Some(branch) = self.condition_stack.last() {
             return Err(PreprocessorError::ConditionError {
                 message: "Unclosed $ifdef or $ifndef".to_string(),
-                row: self.row,
-                col: self.col,
+                row: branch.row,
+                col: branch.col,
             });
         }
 
         Ok(output)
     }
 }
+
+/// The macro guarding a file whose first directive is `$ifndef NAME` and
+/// whose matching `$endif` ends it, with no `$else` between them. Importing
+/// such a file while `NAME` is defined adds nothing.
+fn include_guard(src: &str) -> Option<&str> {
+    let mut lines = src
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'));
+    let guard = lines.next()?.strip_prefix("$ifndef")?.trim();
+    let mut depth = 1;
+    for line in lines.by_ref() {
+        if line.starts_with("$ifdef") || line.starts_with("$ifndef") {
+            depth += 1;
+        } else if line.starts_with("$else") && depth == 1 {
+            return None;
+        } else if line.starts_with("$endif") {
+            depth -= 1;
+            if depth == 0 {
+                break;
+            }
+        }
+    }
+    (depth == 0 && lines.next().is_none() && !guard.is_empty()).then_some(guard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preprocess(src: &str) -> Result<String, PreprocessorError> {
+        Preprocessor::new(src, ".".to_string(), "main.al".to_string()).preprocess()
+    }
+
+    #[test]
+    fn line_expands_to_the_current_row() {
+        assert_eq!(preprocess("a\n\nb(__LINE__)").unwrap(), "a\n\nb(3)");
+    }
+
+    #[test]
+    fn line_counts_skipped_and_joined_lines() {
+        let src = "$ifdef X\nx\n$endif\n$define Y 1 + \\\n 2\n__LINE__";
+        assert!(preprocess(src).unwrap().ends_with("\n6"));
+    }
+
+    #[test]
+    fn define_value_ends_at_a_comment() {
+        let src = "$define N 5 #line 1\nN + 1";
+        assert_eq!(preprocess(src).unwrap(), "\n5 + 1");
+    }
+
+    #[test]
+    fn file_expands_to_a_string_literal() {
+        assert_eq!(preprocess("f(__FILE__)").unwrap(), "f(\"main.al\")");
+        let mut quoted = Preprocessor::new("__FILE__", ".".to_string(), "a\"b.al".to_string());
+        assert_eq!(quoted.preprocess().unwrap(), "\"a\\\"b.al\"");
+    }
+
+    #[test]
+    fn builtins_in_a_define_expand_where_it_is_used() {
+        let src = "$define HERE __FILE__:__LINE__\n\nHERE\n$HERE x__LINE__";
+        assert_eq!(
+            preprocess(src).unwrap(),
+            "\n\n\"main.al\":3\n\"main.al\":4 x__LINE__"
+        );
+    }
+
+    /// Preprocesses `src` as `main.al` in a fresh directory holding `files`.
+    /// A fresh directory holding `files`.
+    fn project(files: &[(&str, &str)]) -> PathBuf {
+        static DIRS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = DIRS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("alum-pp-{}-{}", std::process::id(), n));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, content) in files {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        dir
+    }
+
+    fn preprocess_with(src: &str, files: &[(&str, &str)]) -> Result<String, PreprocessorError> {
+        let dir = project(files);
+        let path = dir.to_str().unwrap().to_string();
+        let out = Preprocessor::new(src, path, "main.al".to_string()).preprocess();
+        fs::remove_dir_all(&dir).unwrap();
+        out
+    }
+
+    #[test]
+    fn file_and_line_inside_an_import_refer_to_the_import() {
+        let out = preprocess_with(
+            "$import \"lib\"\ng(__FILE__, __LINE__)",
+            &[("lib.al", "\nf(__FILE__, __LINE__)\n")],
+        )
+        .unwrap();
+        assert!(out.contains("f(\"lib.al\", 2)"));
+        assert!(out.contains("g(\"main.al\", 2)"));
+    }
+
+    #[test]
+    fn defines_expand_only_whole_identifiers() {
+        let src = "$define N 10\nN Node xN N_1 N";
+        assert_eq!(preprocess(src).unwrap(), "\n10 Node xN N_1 10");
+    }
+
+    #[test]
+    fn backslash_joins_define_lines() {
+        let src = "$define SECONDS 60 * \\\n  60\nSECONDS";
+        assert_eq!(preprocess(src).unwrap(), "\n\n60 *   60");
+    }
+
+    #[test]
+    fn backslash_at_end_of_file_ends_the_define() {
+        let mut pp = Preprocessor::new("$define A 1 \\", ".".to_string(), "main.al".to_string());
+        pp.preprocess().unwrap();
+        assert_eq!(pp.defines()["A"], "1");
+    }
+
+    #[test]
+    fn macro_errors_name_the_macro() {
+        let err = preprocess("x\n$define MAX(a, b) a\nMAX(1)").unwrap_err();
+        assert!(matches!(
+            &err,
+            PreprocessorError::MacroError { name, row: 2, col: 8, .. } if name == "MAX"
+        ));
+        assert!(err.to_string().contains("'MAX'"));
+    }
+
+    #[test]
+    fn circular_import_is_an_error() {
+        let err = preprocess_with(
+            "$import \"a\"",
+            &[("a.al", "$import \"b\"\n"), ("b.al", "\n$import \"a\"\n")],
+        )
+        .unwrap_err();
+        assert!(matches!(
+            &err,
+            PreprocessorError::CircularImport { file, row: 2, .. } if file == "a"
+        ));
+    }
+
+    #[test]
+    fn guarded_file_imported_twice_is_included_once() {
+        let lib = "$ifndef LIB\n$define LIB\nlib()\n$endif\n";
+        let out = preprocess_with("$import \"lib\"\n$import \"lib\"", &[("lib.al", lib)]).unwrap();
+        assert_eq!(out.matches("lib()").count(), 1);
+    }
+
+    #[test]
+    fn guarded_files_importing_each_other_are_included_once() {
+        let a = "$ifndef A\n$define A\n$import \"b\"\na()\n$endif\n";
+        let b = "# b\n$ifndef B\n$define B\n$import \"a\"\nb()\n$endif\n";
+        let out =
+            preprocess_with("$import \"a\"\n$import \"b\"", &[("a.al", a), ("b.al", b)]).unwrap();
+        assert_eq!(out.matches("a()").count(), 1);
+        assert_eq!(out.matches("b()").count(), 1);
+    }
+
+    #[test]
+    fn guard_must_enclose_the_whole_file() {
+        let a = "$ifndef A\n$define A\n$endif\n$import \"b\"\n";
+        let b = "$import \"a\"\n";
+        let err = preprocess_with("$import \"a\"", &[("a.al", a), ("b.al", b)]).unwrap_err();
+        assert!(matches!(err, PreprocessorError::CircularImport { .. }));
+        assert_eq!(include_guard("$ifndef A\n$else\n$endif"), None);
+        assert_eq!(
+            include_guard("$ifndef A\n$ifdef B\n$endif\n$endif # A"),
+            Some("A")
+        );
+    }
+
+    #[test]
+    fn else_keeps_the_other_branch() {
+        let src = "$define A\n$ifdef A\na\n$else\nb\n$endif\n$ifndef A\nc\n$else\nd\n$endif";
+        let out = preprocess(src).unwrap();
+        assert_eq!(out.split_whitespace().collect::<Vec<_>>(), ["a", "d"]);
+    }
+
+    #[test]
+    fn else_inside_a_skipped_branch_stays_skipped() {
+        let src = "$ifdef A\n$ifdef B\nb\n$else\nnb\n$endif\n$else\nna\n$endif";
+        let out = preprocess(src).unwrap();
+        assert_eq!(out.trim(), "na");
+    }
+
+    #[test]
+    fn misplaced_else_is_an_error() {
+        let message = |src| match preprocess(src).unwrap_err() {
+            PreprocessorError::ConditionError { message, .. } => message,
+            err => panic!("unexpected error {}", err),
+        };
+        assert_eq!(message("$else"), "Unexpected $else");
+        assert_eq!(message("$ifdef A\n$else\n$else\n$endif"), "Duplicate $else");
+        assert_eq!(message("$endif"), "Unexpected $endif");
+        assert_eq!(message("\n$ifdef A\n$else\n"), "Unclosed $ifdef or $ifndef");
+    }
+
+    #[test]
+    fn undef_removes_a_define() {
+        let src = "$define N 1\nN\n$undef N\nN\n$ifdef N\nyes\n$else\nno\n$endif\n$undef M";
+        let out = preprocess(src).unwrap();
+        assert_eq!(out.split_whitespace().collect::<Vec<_>>(), ["1", "N", "no"]);
+    }
+
+    #[test]
+    fn undef_without_a_name_is_an_error() {
+        let err = preprocess("$undef 1").unwrap_err();
+        assert!(matches!(
+            err,
+            PreprocessorError::MacroError { row: 1, col: 7, .. }
+        ));
+    }
+
+    #[test]
+    fn file_imported_from_two_places_is_read_once() {
+        let dir = project(&[("lib.al", "lib()\n"), ("a.al", "$import \"lib\"\na()\n")]);
+        let lib = fs::canonicalize(dir.join("lib.al")).unwrap();
+        let mut pp = Preprocessor::new(
+            "$import \"a\"\n$import \"lib\"",
+            dir.to_str().unwrap().to_string(),
+            "main.al".to_string(),
+        );
+        let out = pp.preprocess().unwrap();
+        assert_eq!(out.matches("lib()").count(), 2);
+        assert_eq!(pp.sources.len(), 2);
+
+        // Once cached, a file is not read again, so a change on disk goes unseen.
+        fs::write(dir.join("lib.al"), "changed()\n").unwrap();
+        let mut pp = Preprocessor::new(
+            "$import \"a\"\n$import \"lib\"",
+            dir.to_str().unwrap().to_string(),
+            "main.al".to_string(),
+        );
+        pp.sources.insert(lib, "cached()\n".into());
+        let out = pp.preprocess().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(out.matches("cached()").count(), 2);
+        assert!(!out.contains("changed()"));
+    }
+}
@@ -0,0 +1,378 @@
+//! Simplifications of integer operations with a constant operand.
+//!
+//! The IR generator loads every constant into a temporary before using it,
+//! so an operand is known to be constant when it is a temporary whose only
+//! definition is a `Move` from the constant pool.
+//!
+//! `fold_identities` rewrites instructions into a `Move` of the surviving
+//! operand:
+//!
+//! - `x + 0`, `0 + x`, `x - 0`, `x * 1`, `1 * x` and `x / 1` become `x`;
+//! - `x | 0`, `x ^ 0` and their mirrored forms become `x`, as do `x << 0`
+//!   and `x >> 0`;
+//! - `x * 0`, `0 * x`, `x & 0` and `0 & x` become `0`.
+//!
+//! `reduce_strength` replaces multiplication and signed division by a
+//! power of two `2^k` with shifts:
+//!
+//! - `x * 2^k` and `2^k * x` become `x << k`;
+//! - `x / 2^k` becomes `(x + ((x >> 63) & (2^k - 1))) >> k`. The arithmetic
+//!   shift alone rounds towards negative infinity, so a negative `x` is first
+//!   biased by `2^k - 1` to round towards zero like `idiv`.
+//!
+//! `inline_constants` then makes a constant that fits in a 32-bit immediate
+//! the direct `src2` of the arithmetic or comparison using it, so the code
+//! generator can encode it in the instruction, and drops loads of constants
+//! left unused. A constant array index is inlined the same way, to become
+//! part of the element's address.
+//!
+//! Only integer operations are rewritten. The float identities do not hold
+//! for NaN and negative zero.
+
+use std::collections::HashMap;
+
+use crate::ir::{IRConst, IRProgram, IRType, Instruction, Op, Operand};
+
+pub fn fold_identities(program: &mut IRProgram) {
+    for func in program.functions.iter_mut() {
+        if !func.is_external {
+            fold_fn_identities(&mut func.instructions, &program.constants);
+        }
+    }
+}
+
+/// The value and constant pool index of every temporary that holds an
+/// integer constant.
+fn const_temps(insts: &[Instruction], constants: &[IRConst]) -> HashMap<usize, (i64, usize)> {
+    let mut defs: HashMap<usize, usize> = HashMap::new();
+    for inst in insts.iter() {
+        if let Some(Operand::Temp(id, _)) = &inst.dst {
+            *defs.entry(*id).or_default() += 1;
+        }
+    }
+
+    let mut values = HashMap::new();
+    for inst in insts.iter() {
+        if let (Op::Move, Some(Operand::Temp(id, _)), Some(Operand::ConstIdx(idx))) =
+            (&inst.op, &inst.dst, &inst.src1)
+            && let (Some(1), IRConst::Int(n)) = (defs.get(id), &constants[*idx])
+        {
+            values.insert(*id, (*n, *idx));
+        }
+    }
+    values
+}
+
+fn fold_fn_identities(insts: &mut [Instruction], constants: &[IRConst]) {
+    let values = const_temps(insts, constants);
+    let value = |op: &Option<Operand>| match op {
+        Some(Operand::Temp(id, _)) => values.get(id).map(|(n, _)| *n),
+        _ => None,
+    };
+
+    for inst in insts.iter_mut() {
+        let (l, r) = (value(&inst.src1), value(&inst.src2));
+        let kept = match (&inst.op, l, r) {
+            (Op::Add | Op::Sub | Op::Or | Op::Xor | Op::Shl | Op::Shr, _, Some(0)) => {
+                inst.src1.take()
+            }
+            (Op::Add | Op::Or | Op::Xor, Some(0), _) => inst.src2.take(),
+            (Op::Mul | Op::Div, _, Some(1)) => inst.src1.take(),
+            (Op::Mul, Some(1), _) => inst.src2.take(),
+            (Op::Mul | Op::And, _, Some(0)) => inst.src2.take(),
+            (Op::Mul | Op::And, Some(0), _) => inst.src1.take(),
+            _ => continue,
+        };
+        inst.op = Op::Move;
+        inst.src1 = kept;
+        inst.src2 = None;
+    }
+}
+
+pub fn reduce_strength(program: &mut IRProgram) {
+    for func in program.functions.iter_mut() {
+        if !func.is_external {
+            reduce_fn_strength(&mut func.instructions, &program.constants);
+        }
+    }
+}
+
+/// The exponent `k` of a constant `2^k` with `k` at least 1 and below
+/// `limit`.
+fn log2(n: Option<i64>, limit: u32) -> Option<u32> {
+    n.filter(|n| *n > 1 && (*n as u64).is_power_of_two())
+        .map(|n| n.trailing_zeros())
+        .filter(|k| *k < limit)
+}
+
+fn reduce_fn_strength(insts: &mut Vec<Instruction>, constants: &[IRConst]) {
+    let values = const_temps(insts, constants);
+    let value = |op: &Option<Operand>| match op {
+        Some(Operand::Temp(id, _)) => values.get(id).map(|(n, _)| *n),
+        _ => None,
+    };
+    let shift = |k: u32| Some(Operand::Const(IRConst::Int(k as i64)));
+
+    let mut next_temp = insts
+        .iter()
+        .flat_map(|inst| [&inst.dst, &inst.src1, &inst.src2])
+        .filter_map(|op| match op {
+            Some(Operand::Temp(id, _)) => Some(*id + 1),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+
+    let mut reduced = Vec::with_capacity(insts.len());
+    for mut inst in insts.drain(..) {
+        let (l, r) = (value(&inst.src1), value(&inst.src2));
+        match inst.op {
+            Op::Mul if log2(r, 63).is_some() => {
+                inst.op = Op::Shl;
+                inst.src2 = shift(log2(r, 63).unwrap());
+            }
+            Op::Mul if log2(l, 63).is_some() => {
+                inst.op = Op::Shl;
+                inst.src1 = inst.src2.take();
+                inst.src2 = shift(log2(l, 63).unwrap());
+            }
+            // The bias `2^k - 1` must fit in a 32-bit immediate.
+            Op::Div if log2(r, 32).is_some() => {
+                let k = log2(r, 32).unwrap();
+                let mut temp = || {
+                    next_temp += 1;
+                    Some(Operand::Temp(next_temp - 1, IRType::Int))
+                };
+                let (sign, bias, biased) = (temp(), temp(), temp());
+                let x = inst.src1.take();
+                let op = |op, dst: &Option<Operand>, src1: &Option<Operand>, src2| Instruction {
+                    op,
+                    dst: dst.clone(),
+                    src1: src1.clone(),
+                    src2,
+                };
+                reduced.push(op(Op::Shr, &sign, &x, shift(63)));
+                let mask = Some(Operand::Const(IRConst::Int((1 << k) - 1)));
+                reduced.push(op(Op::And, &bias, &sign, mask));
+                reduced.push(op(Op::Add, &biased, &x, bias));
+                inst.op = Op::Shr;
+                inst.src1 = biased;
+                inst.src2 = shift(k);
+            }
+            _ => {}
+        }
+        reduced.push(inst);
+    }
+    *insts = reduced;
+}
+
+pub fn inline_constants(program: &mut IRProgram) {
+    for func in program.functions.iter_mut() {
+        if !func.is_external {
+            inline_fn_constants(&mut func.instructions, &program.constants);
+        }
+    }
+}
+
+fn inline_fn_constants(insts: &mut Vec<Instruction>, constants: &[IRConst]) {
+    let values = const_temps(insts, constants);
+    let imm = |op: &Option<Operand>| match op {
+        Some(Operand::Temp(id, _)) => values
+            .get(id)
+            .filter(|(n, _)| i32::try_from(*n).is_ok())
+            .map(|(_, idx)| Operand::ConstIdx(*idx)),
+        _ => None,
+    };
+
+    for inst in insts.iter_mut() {
+        let index = match inst.op {
+            Op::ArrayAccess(_) => Some(&mut inst.src2),
+            Op::ArrayAssign(_) => Some(&mut inst.src1),
+            _ => None,
+        };
+        if let Some(index) = index {
+            if let Some(c) = imm(index) {
+                *index = Some(c);
+            }
+            continue;
+        }
+        // The operation computing the same value with its operands swapped.
+        let swapped = match inst.op {
+            Op::Add | Op::Mul | Op::And | Op::Or | Op::Xor | Op::Eq | Op::Ne => {
+                Some(inst.op.clone())
+            }
+            Op::Gt => Some(Op::Lt),
+            Op::Ge => Some(Op::Le),
+            Op::Lt => Some(Op::Gt),
+            Op::Le => Some(Op::Ge),
+            Op::Sub | Op::Shl | Op::Shr => None,
+            _ => continue,
+        };
+        if let Some(c) = imm(&inst.src2) {
+            inst.src2 = Some(c);
+        } else if let (Some(c), Some(op)) = (imm(&inst.src1), swapped) {
+            inst.op = op;
+            inst.src1 = inst.src2.take();
+            inst.src2 = Some(c);
+        }
+    }
+
+    // Array literals refer to the temporaries holding their elements.
+    let mut used: HashMap<usize, usize> = HashMap::new();
+    for inst in insts.iter() {
+        for op in [&inst.dst, &inst.src1, &inst.src2].into_iter().flatten() {
+            let elems = match op {
+                Operand::ConstIdx(idx) => match &constants[*idx] {
+                    IRConst::Array(_, _, elems) => &elems[..],
+                    _ => &[],
+                },
+                op => std::slice::from_ref(op),
+            };
+            for elem in elems {
+                if let Operand::Temp(id, _) = elem {
+                    *used.entry(*id).or_default() += 1;
+                }
+            }
+        }
+    }
+    insts.retain(|inst| match (&inst.op, &inst.dst) {
+        (Op::Move, Some(Operand::Temp(id, _))) => {
+            !values.contains_key(id) || used.get(id).is_some_and(|n| *n > 1)
+        }
+        _ => true,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::IRType;
+    use ordered_float::OrderedFloat;
+
+    fn temp(id: usize) -> Operand {
+        Operand::Temp(id, IRType::Int)
+    }
+
+    fn inst(op: Op, dst: Operand, src1: Operand, src2: Option<Operand>) -> Instruction {
+        Instruction {
+            op,
+            dst: Some(dst),
+            src1: Some(src1),
+            src2,
+        }
+    }
+
+    /// Folds `%2 = op %0, %1`, or `op %1, %0` when `left` is set, where `%1`
+    /// holds `constant` and `%0` is unknown, and returns what it became.
+    fn fold(op: Op, constant: IRConst, left: bool) -> Instruction {
+        let (l, r) = if left {
+            (temp(1), temp(0))
+        } else {
+            (temp(0), temp(1))
+        };
+        let mut insts = vec![
+            inst(Op::Move, temp(1), Operand::ConstIdx(0), None),
+            inst(op, temp(2), l, Some(r)),
+        ];
+        fold_fn_identities(&mut insts, &[constant]);
+        insts.pop().unwrap()
+    }
+
+    fn moved(src: Operand) -> Instruction {
+        inst(Op::Move, temp(2), src, None)
+    }
+
+    #[test]
+    fn identities_keep_the_other_operand() {
+        for op in [Op::Add, Op::Sub, Op::Or, Op::Xor, Op::Shl, Op::Shr] {
+            assert_eq!(fold(op, IRConst::Int(0), false), moved(temp(0)));
+        }
+        for op in [Op::Add, Op::Or, Op::Xor] {
+            assert_eq!(fold(op, IRConst::Int(0), true), moved(temp(0)));
+        }
+        for op in [Op::Mul, Op::Div] {
+            assert_eq!(fold(op, IRConst::Int(1), false), moved(temp(0)));
+        }
+        assert_eq!(fold(Op::Mul, IRConst::Int(1), true), moved(temp(0)));
+    }
+
+    #[test]
+    fn multiplying_or_masking_by_zero_gives_zero() {
+        for op in [Op::Mul, Op::And] {
+            assert_eq!(fold(op.clone(), IRConst::Int(0), false), moved(temp(1)));
+            assert_eq!(fold(op, IRConst::Int(0), true), moved(temp(1)));
+        }
+    }
+
+    #[test]
+    fn operations_that_are_not_identities_are_kept() {
+        let kept = |op: Op| inst(op, temp(2), temp(0), Some(temp(1)));
+        let kept_left = |op: Op| inst(op, temp(2), temp(1), Some(temp(0)));
+        assert_eq!(fold(Op::Sub, IRConst::Int(0), true), kept_left(Op::Sub));
+        assert_eq!(fold(Op::Div, IRConst::Int(1), true), kept_left(Op::Div));
+        assert_eq!(fold(Op::Add, IRConst::Int(1), false), kept(Op::Add));
+        assert_eq!(fold(Op::Mul, IRConst::Int(2), false), kept(Op::Mul));
+    }
+
+    #[test]
+    fn float_identities_are_left_alone() {
+        let zero = IRConst::Float(OrderedFloat(0.0));
+        let one = IRConst::Float(OrderedFloat(1.0));
+        let kept = |op: Op| inst(op, temp(2), temp(0), Some(temp(1)));
+        assert_eq!(fold(Op::FAdd, zero.clone(), false), kept(Op::FAdd));
+        assert_eq!(fold(Op::FMul, zero, false), kept(Op::FMul));
+        assert_eq!(fold(Op::FMul, one, false), kept(Op::FMul));
+    }
+
+    /// Runs `reduce_fn_strength` over the same instructions `fold` builds.
+    fn reduce(op: Op, constant: i64, left: bool) -> Vec<Instruction> {
+        let (l, r) = if left {
+            (temp(1), temp(0))
+        } else {
+            (temp(0), temp(1))
+        };
+        let mut insts = vec![
+            inst(Op::Move, temp(1), Operand::ConstIdx(0), None),
+            inst(op, temp(2), l, Some(r)),
+        ];
+        reduce_fn_strength(&mut insts, &[IRConst::Int(constant)]);
+        insts.split_off(1)
+    }
+
+    fn int(n: i64) -> Option<Operand> {
+        Some(Operand::Const(IRConst::Int(n)))
+    }
+
+    #[test]
+    fn multiplying_by_a_power_of_two_shifts() {
+        let shifted = |k| vec![inst(Op::Shl, temp(2), temp(0), int(k))];
+        assert_eq!(reduce(Op::Mul, 8, false), shifted(3));
+        assert_eq!(reduce(Op::Mul, 2, true), shifted(1));
+        assert_eq!(reduce(Op::Mul, 1 << 40, false), shifted(40));
+    }
+
+    #[test]
+    fn dividing_by_a_power_of_two_shifts_the_biased_dividend() {
+        assert_eq!(
+            reduce(Op::Div, 16, false),
+            [
+                inst(Op::Shr, temp(3), temp(0), int(63)),
+                inst(Op::And, temp(4), temp(3), int(15)),
+                inst(Op::Add, temp(5), temp(0), Some(temp(4))),
+                inst(Op::Shr, temp(2), temp(5), int(4)),
+            ]
+        );
+    }
+
+    #[test]
+    fn other_multipliers_and_divisors_are_kept() {
+        let kept = |op: Op| vec![inst(op, temp(2), temp(0), Some(temp(1)))];
+        let kept_left = |op: Op| vec![inst(op, temp(2), temp(1), Some(temp(0)))];
+        assert_eq!(reduce(Op::Mul, 6, false), kept(Op::Mul));
+        assert_eq!(reduce(Op::Mul, -4, false), kept(Op::Mul));
+        assert_eq!(reduce(Op::Div, 3, false), kept(Op::Div));
+        assert_eq!(reduce(Op::Div, 4, true), kept_left(Op::Div));
+        // Its bias would not fit in an immediate.
+        assert_eq!(reduce(Op::Div, 1 << 32, false), kept(Op::Div));
+    }
+}
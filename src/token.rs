@@ -8,12 +8,21 @@ pub enum TokenType {
     SUB,
     MUL,
     DIV,
+    MOD,
+    SHL,
+    SHR,
     NEG,
     EQ,
     ADDEQ,
     SUBEQ,
     MULEQ,
     DIVEQ,
+    ANDEQ,
+    OREQ,
+    XOREQ,
+    MODEQ,
+    SHLEQ,
+    SHREQ,
     COMPEQ,
     COMPNE,
     COMPGT,
@@ -27,6 +36,7 @@ pub enum TokenType {
     LOGOR,
     LOGXOR,
     LITERAL(VarType),
+    INTERP,
     LPAREN,
     RPAREN,
     LBRACE,
@@ -34,6 +44,7 @@ pub enum TokenType {
     LBRACKET,
     RBRACKET,
     COLON,
+    QUESTION,
     VARDECL,
     VAR,
     OUT,
@@ -44,6 +55,8 @@ pub enum TokenType {
     IN,
     LABEL,
     GOTO,
+    BREAK,
+    CONTINUE,
     FUNCDECL,
     CALL,
     RETURN,
@@ -73,14 +86,23 @@ pub enum VarType {
     Float,
     Bool,
     Str,
-    Array(Option<usize>),
+    Byte,
+    Array(Option<usize>, Box<VarType>),
     Void,
 }
 
+/// Byte offsets of a token in the lexed source; `end` is exclusive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
 #[derive(Debug, Clone)]
 pub struct Token {
     pub token: TokenType,
     pub value: Option<Literal>,
     pub row: usize,
     pub col: usize,
+    pub span: Span,
 }